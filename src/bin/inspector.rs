@@ -0,0 +1,95 @@
+//! Packet inspector: runs a client session, or the proxy with `--proxy`,
+//! and shows every packet live on stderr. Session logs still go to stdout,
+//! run it as `inspector > session.log`.
+//!
+//! usage: inspector [--proxy] [--listen <addr>] [--login-server <addr>]
+//!                  [--username <name>] [--password <password>]
+
+use std::{
+    process,
+    sync::{Arc, Mutex},
+};
+
+use ragnarok_socket::{
+    capture::recorder,
+    inspector::{inspect, ui, Inspector},
+    logging,
+    protocol::login,
+    proxy::{Proxy, ProxyConfig},
+    r#const::{LOGIN_PASSWORD, LOGIN_SERVER_ADDR, LOGIN_USERNAME},
+};
+use tokio::sync::broadcast::error::RecvError;
+
+fn usage() -> ! {
+    eprintln!("usage: inspector [--proxy] [--listen <addr>] [--login-server <addr>]");
+    eprintln!("                 [--username <name>] [--password <password>]");
+    process::exit(2);
+}
+
+#[tokio::main]
+async fn main() {
+    let mut proxy = false;
+    let mut listen = "127.0.0.1:6900".to_string();
+    let mut login_server = LOGIN_SERVER_ADDR.to_string();
+    let mut username = LOGIN_USERNAME.to_string();
+    let mut password = LOGIN_PASSWORD.to_string();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--proxy" => proxy = true,
+            "--listen" => listen = value(),
+            "--login-server" => login_server = value(),
+            "--username" => username = value(),
+            "--password" => password = value(),
+            _ => usage(),
+        }
+    }
+
+    // logs go to stdout, the inspector draws on stderr
+    logging::init();
+
+    // subscribed before the session starts, so no packet is missed
+    let mut packets = recorder::subscribe();
+    let inspector = Arc::new(Mutex::new(Inspector::new()));
+    let feed = inspector.clone();
+    tokio::spawn(async move {
+        loop {
+            match packets.recv().await {
+                Ok(packet) => {
+                    let packet = inspect(packet).await;
+                    feed.lock().unwrap().push(packet);
+                }
+                Err(RecvError::Lagged(count)) => feed.lock().unwrap().dropped += count,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+
+    if proxy {
+        let proxy = Proxy::new(ProxyConfig {
+            listen: listen.parse().unwrap_or_else(|_| usage()),
+            login_server: login_server.parse().unwrap_or_else(|_| usage()),
+            public_ip: None,
+            // decoded by the inspector
+            decode: false,
+        });
+        if let Err(e) = proxy.start().await {
+            eprintln!("Failed to start proxy: {}", e);
+            process::exit(1);
+        }
+    } else {
+        tokio::spawn(async move {
+            login::initialize(&login_server, &username, &password).await;
+        });
+    }
+
+    let result = tokio::task::spawn_blocking(move || ui::run(inspector))
+        .await
+        .unwrap();
+    if let Err(e) = result {
+        eprintln!("Inspector failed: {}", e);
+        process::exit(1);
+    }
+}
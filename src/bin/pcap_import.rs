@@ -0,0 +1,89 @@
+//! Imports a pcap/pcapng capture of RO traffic and checks every server
+//! packet against our decoders.
+//!
+//! usage: pcap_import <file> [--login-port N] [--char-port N] [--map-port N] [--out <file.rocap>]
+
+use std::{fs, process};
+
+use ragnarok_socket::capture::{
+    format::{write_header, write_record},
+    import::{import, StagePorts},
+    replay::replay,
+    Stage,
+};
+
+fn usage() -> ! {
+    println!(
+        "usage: pcap_import <file> [--login-port N] [--char-port N] [--map-port N] [--out <file.rocap>]"
+    );
+    process::exit(2);
+}
+
+#[tokio::main]
+async fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut input = None;
+    let mut output = None;
+    let mut ports = StagePorts::default();
+
+    while let Some(arg) = args.next() {
+        let stage = match arg.as_str() {
+            "--login-port" => Some(Stage::Login),
+            "--char-port" => Some(Stage::Char),
+            "--map-port" => Some(Stage::Map),
+            "--out" => {
+                output = Some(args.next().unwrap_or_else(|| usage()));
+                continue;
+            }
+            _ => None,
+        };
+
+        match stage {
+            Some(stage) => {
+                let port = args
+                    .next()
+                    .and_then(|port| port.parse().ok())
+                    .unwrap_or_else(|| usage());
+                ports.set(stage, port);
+            }
+            None if input.is_none() && !arg.starts_with("--") => input = Some(arg),
+            None => usage(),
+        }
+    }
+
+    let Some(input) = input else { usage() };
+    let buffer = fs::read(&input).unwrap_or_else(|e| {
+        println!("Failed to read {}: {}", input, e);
+        process::exit(1);
+    });
+    let capture = import(&buffer, &ports).unwrap_or_else(|e| {
+        println!("Failed to import {}: {}", input, e);
+        process::exit(1);
+    });
+
+    let report = replay(&capture).await;
+    for packet in report.failures() {
+        println!("{}", packet);
+    }
+    println!(
+        "{} packets, {} failed",
+        report.packets.len(),
+        report.failures().count()
+    );
+
+    if let Some(output) = output {
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, capture.start).unwrap();
+        for packet in &capture.packets {
+            write_record(&mut buffer, packet).unwrap();
+        }
+        if let Err(e) = fs::write(&output, buffer) {
+            println!("Failed to write {}: {}", output, e);
+            process::exit(1);
+        }
+    }
+
+    if !report.is_clean() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,78 @@
+//! Man-in-the-middle proxy: point the client to `--listen` and every
+//! packet of the session gets logged and decoded.
+//!
+//! usage: proxy [--listen <addr>] [--login-server <addr>] [--public-ip <ip>] [--no-decode]
+//!              [--capture <file>] [--capture-format text|binary]
+
+use std::process;
+
+use ragnarok_socket::{
+    capture::recorder::{self, CaptureFormat},
+    logging,
+    proxy::{Proxy, ProxyConfig},
+    r#const::LOGIN_SERVER_ADDR,
+};
+
+fn usage() -> ! {
+    println!(
+        "usage: proxy [--listen <addr>] [--login-server <addr>] [--public-ip <ip>] [--no-decode]"
+    );
+    println!("             [--capture <file>] [--capture-format text|binary]");
+    process::exit(2);
+}
+
+#[tokio::main]
+async fn main() {
+    let mut config = ProxyConfig {
+        listen: "127.0.0.1:6900".parse().unwrap(),
+        login_server: LOGIN_SERVER_ADDR.parse().unwrap(),
+        public_ip: None,
+        decode: true,
+    };
+    logging::init();
+    let mut capture_file = None;
+    let mut capture_format = CaptureFormat::Text;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--listen" => config.listen = value().parse().unwrap_or_else(|_| usage()),
+            "--login-server" => config.login_server = value().parse().unwrap_or_else(|_| usage()),
+            "--public-ip" => config.public_ip = Some(value().parse().unwrap_or_else(|_| usage())),
+            "--no-decode" => config.decode = false,
+            "--capture" => capture_file = Some(value()),
+            "--capture-format" => {
+                capture_format = match value().as_str() {
+                    "text" => CaptureFormat::Text,
+                    "binary" => CaptureFormat::Binary,
+                    _ => usage(),
+                }
+            }
+            _ => usage(),
+        }
+    }
+
+    if let Some(capture_file) = capture_file {
+        if let Err(e) = recorder::start(&capture_file, capture_format) {
+            tracing::error!(error = %e, "failed to start packet capture");
+            process::exit(1);
+        }
+    }
+
+    let login_server = config.login_server;
+    let proxy = Proxy::new(config);
+    match proxy.start().await {
+        Ok(addr) => tracing::info!(listen = %addr, %login_server, "proxy started"),
+        Err(e) => {
+            tracing::error!(error = %e, "failed to start proxy");
+            process::exit(1);
+        }
+    }
+
+    // runs until interrupted, then writes out the rest of the capture
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!(error = %e, "failed to wait for ctrl-c");
+    }
+    recorder::finish();
+}
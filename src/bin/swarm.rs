@@ -0,0 +1,89 @@
+//! Load test: logs in every account of a credentials file, ramping up at
+//! `--rate` sessions per second, keeps them on the map for `--duration`
+//! seconds and prints the latency and failure report.
+//!
+//! usage: swarm <credentials> [--login-server <addr>] [--rate N] [--timeout secs]
+//!              [--duration secs] [--behaviour idle|walk|chat|mixed] [--interval secs]
+//!              [--radius cells] [--metrics <addr>]
+//!
+//! With `--metrics`, per-session counters are served in the Prometheus text
+//! format on `addr`, e.g. `127.0.0.1:9100`.
+
+use std::{fs, process, time::Duration};
+
+use ragnarok_socket::{
+    logging, metrics,
+    swarm::{self, parse_credentials, SwarmConfig},
+};
+
+fn usage() -> ! {
+    println!("usage: swarm <credentials> [--login-server <addr>] [--rate N] [--timeout secs]");
+    println!("             [--duration secs] [--behaviour idle|walk|chat|mixed] [--interval secs]");
+    println!("             [--radius cells] [--metrics <addr>]");
+    process::exit(2);
+}
+
+fn seconds(value: String) -> Duration {
+    value
+        .parse()
+        .ok()
+        .filter(|secs: &f64| *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .unwrap_or_else(|| usage())
+}
+
+#[tokio::main]
+async fn main() {
+    logging::init();
+
+    let mut config = SwarmConfig::default();
+    let mut input = None;
+    let mut metrics_addr = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--login-server" => config.login_server = value(),
+            "--rate" => {
+                config.rate = value()
+                    .parse()
+                    .ok()
+                    .filter(|rate: &f64| *rate > 0.0)
+                    .unwrap_or_else(|| usage())
+            }
+            "--timeout" => config.timeout = seconds(value()),
+            "--duration" => config.duration = seconds(value()),
+            "--behaviour" => config.behaviour = value().parse().unwrap_or_else(|_| usage()),
+            "--interval" => config.action_interval = seconds(value()),
+            "--radius" => config.walk_radius = value().parse().unwrap_or_else(|_| usage()),
+            "--metrics" => metrics_addr = Some(value().parse().unwrap_or_else(|_| usage())),
+            _ if input.is_none() && !arg.starts_with("--") => input = Some(arg),
+            _ => usage(),
+        }
+    }
+
+    let Some(input) = input else { usage() };
+    let text = fs::read_to_string(&input).unwrap_or_else(|e| {
+        println!("Failed to read {}: {}", input, e);
+        process::exit(1);
+    });
+    let accounts = parse_credentials(&text).unwrap_or_else(|e| {
+        println!("{}: {}", input, e);
+        process::exit(1);
+    });
+
+    if let Some(addr) = metrics_addr {
+        if let Err(e) = metrics::serve_prometheus(addr).await {
+            println!("Failed to start metrics endpoint on {}: {}", addr, e);
+            process::exit(1);
+        }
+    }
+
+    let report = swarm::run(config, accounts).await;
+    print!("{}", report);
+
+    if report.failures().next().is_some() {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use crate::io::{error::ParseError, reader::BinaryReader};
+
+use super::{Direction, Stage};
+
+pub static CAPTURE_MAGIC: &[u8; 8] = b"ROPKTCAP";
+pub static CAPTURE_VERSION: u16 = 1;
+
+/// Hex bytes per line in the text form.
+static TEXT_BYTES_PER_LINE: usize = 16;
+
+pub struct CapturedPacket {
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+    /// Session, or connection for the proxy and imports, the packet belongs
+    /// to. 0 when unknown.
+    pub session: u32,
+    pub direction: Direction,
+    pub stage: Stage,
+    pub packet_id: u16,
+    /// Whole packet, header included.
+    pub data: Vec<u8>,
+}
+
+pub struct Capture {
+    /// Milliseconds since the unix epoch, when recording started.
+    pub start: u64,
+    pub packets: Vec<CapturedPacket>,
+}
+
+impl CapturedPacket {
+    pub fn new(timestamp: u64, direction: Direction, stage: Stage, data: &[u8]) -> CapturedPacket {
+        let packet_id = match data {
+            [low, high, ..] => u16::from_le_bytes([*low, *high]),
+            _ => 0,
+        };
+
+        CapturedPacket {
+            timestamp,
+            session: 0,
+            direction,
+            stage,
+            packet_id,
+            data: data.to_vec(),
+        }
+    }
+
+    pub fn with_session(mut self, session: u32) -> CapturedPacket {
+        self.session = session;
+        self
+    }
+}
+
+impl Capture {
+    /// One capture per session, so each can be replayed on its own.
+    pub fn split_sessions(self) -> BTreeMap<u32, Capture> {
+        let mut captures = BTreeMap::new();
+        for packet in self.packets {
+            captures
+                .entry(packet.session)
+                .or_insert_with(|| Capture {
+                    start: self.start,
+                    packets: Vec::new(),
+                })
+                .packets
+                .push(packet);
+        }
+        captures
+    }
+}
+
+// binary form:
+//   header: magic[8], version u16, start u64
+//   record: timestamp u64, session u32, direction u8, stage u8, packet id u16, len u32, data[len]
+pub fn write_header<W: Write>(writer: &mut W, start: u64) -> io::Result<()> {
+    writer.write_all(CAPTURE_MAGIC)?;
+    writer.write_all(&CAPTURE_VERSION.to_le_bytes())?;
+    writer.write_all(&start.to_le_bytes())
+}
+
+pub fn write_record<W: Write>(writer: &mut W, packet: &CapturedPacket) -> io::Result<()> {
+    writer.write_all(&packet.timestamp.to_le_bytes())?;
+    writer.write_all(&packet.session.to_le_bytes())?;
+    writer.write_all(&[packet.direction as u8, packet.stage as u8])?;
+    writer.write_all(&packet.packet_id.to_le_bytes())?;
+    writer.write_all(&(packet.data.len() as u32).to_le_bytes())?;
+    writer.write_all(&packet.data)
+}
+
+pub fn read_capture(buffer: &[u8]) -> Result<Capture, ParseError> {
+    let mut data = BinaryReader::new(buffer);
+    if data.read_bytes(CAPTURE_MAGIC.len())? != CAPTURE_MAGIC {
+        return Err(ParseError::InvalidMagic);
+    }
+    let version = data.read_u16()?;
+    if version != CAPTURE_VERSION {
+        return Err(ParseError::UnsupportedVersion(version as u32));
+    }
+    let start = data.read_u64()?;
+
+    let mut packets = Vec::new();
+    while !data.is_eof() {
+        let timestamp = data.read_u64()?;
+        let session = data.read_u32()?;
+        let direction = Direction::try_from(data.read_u8()?)
+            .map_err(|_| ParseError::InvalidField("direction"))?;
+        let stage =
+            Stage::try_from(data.read_u8()?).map_err(|_| ParseError::InvalidField("stage"))?;
+        let packet_id = data.read_u16()?;
+        let len = data.read_u32()?;
+        let packet_data = data.read_bytes(len as usize)?.to_vec();
+
+        packets.push(CapturedPacket {
+            timestamp,
+            session,
+            direction,
+            stage,
+            packet_id,
+            data: packet_data,
+        });
+    }
+
+    Ok(Capture { start, packets })
+}
+
+/// `HH:MM:SS.mmm`, UTC.
+pub fn format_time(timestamp: u64) -> String {
+    let millis = timestamp % 1000;
+    let seconds = timestamp / 1000;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60,
+        millis
+    )
+}
+
+// text form, one line per packet followed by its hex dump:
+//   12:30:05.120 +1.250s [3] map   <- 0x02EB (13 bytes)
+//       EB 02 ...
+pub fn write_text_record<W: Write>(
+    writer: &mut W,
+    packet: &CapturedPacket,
+    start: u64,
+) -> io::Result<()> {
+    let arrow = match packet.direction {
+        Direction::Sent => "->",
+        Direction::Received => "<-",
+    };
+    let elapsed = packet.timestamp.saturating_sub(start);
+
+    writeln!(
+        writer,
+        "{} +{}.{:03}s [{}] {:<5} {} 0x{:04X} ({} bytes)",
+        format_time(packet.timestamp),
+        elapsed / 1000,
+        elapsed % 1000,
+        packet.session,
+        packet.stage.name(),
+        arrow,
+        packet.packet_id,
+        packet.data.len()
+    )?;
+
+    for line in hex_lines(&packet.data) {
+        writeln!(writer, "    {}", line)?;
+    }
+
+    Ok(())
+}
+
+/// `data` as lines of space separated hex bytes.
+pub fn hex_lines(data: &[u8]) -> Vec<String> {
+    data.chunks(TEXT_BYTES_PER_LINE)
+        .map(|line| {
+            let hex: Vec<String> = line.iter().map(|byte| format!("{:02X}", byte)).collect();
+            hex.join(" ")
+        })
+        .collect()
+}
+
+pub fn write_text<W: Write>(writer: &mut W, capture: &Capture) -> io::Result<()> {
+    writeln!(writer, "# capture started at {} (unix ms)", capture.start)?;
+    for packet in &capture.packets {
+        write_text_record(writer, packet, capture.start)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binary_round_trip_and_text_dump() {
+        let start = 45_005_000;
+        let packets = [
+            CapturedPacket::new(
+                start + 10,
+                Direction::Sent,
+                Stage::Login,
+                &[0x64, 0x00, 1, 2],
+            ),
+            CapturedPacket::new(
+                start + 1250,
+                Direction::Received,
+                Stage::Map,
+                &[0xDE, 0x0A, 0x32, 0, 0, 0],
+            )
+            .with_session(3),
+        ];
+
+        let mut buffer = Vec::new();
+        write_header(&mut buffer, start).unwrap();
+        for packet in &packets {
+            write_record(&mut buffer, packet).unwrap();
+        }
+
+        let capture = read_capture(&buffer).unwrap();
+        assert_eq!(capture.start, start);
+        assert_eq!(capture.packets.len(), 2);
+        assert_eq!(capture.packets[1].packet_id, 0x0ADE);
+        assert_eq!(capture.packets[1].stage, Stage::Map);
+        assert_eq!(capture.packets[1].session, 3);
+        assert_eq!(capture.packets[1].data, packets[1].data);
+
+        let mut text = Vec::new();
+        write_text(&mut text, &capture).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(
+            text.contains("12:30:05.010 +0.010s [0] login -> 0x0064 (4 bytes)\n    64 00 01 02\n")
+        );
+        assert!(text.contains(
+            "12:30:06.250 +1.250s [3] map   <- 0x0ADE (6 bytes)\n    DE 0A 32 00 00 00\n"
+        ));
+
+        assert!(read_capture(&buffer[..buffer.len() - 1]).is_err());
+        let mut corrupted = buffer.clone();
+        corrupted[31] = 0xFF;
+        assert!(matches!(
+            read_capture(&corrupted),
+            Err(ParseError::InvalidField("stage"))
+        ));
+
+        let sessions = capture.split_sessions();
+        assert_eq!(sessions.keys().copied().collect::<Vec<u32>>(), vec![0, 3]);
+        assert_eq!(sessions[&3].packets[0].packet_id, 0x0ADE);
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+};
+
+use crate::io::error::ParseError;
+
+use super::{
+    format::{Capture, CapturedPacket},
+    pcap::{parse_tcp, read_frames, TcpSegment, TCP_FLAG_SYN},
+    splitter::{Chunk, PacketSplitter},
+    Direction, Stage,
+};
+
+/// Server ports of each stage, the rAthena defaults unless changed.
+#[derive(Clone)]
+pub struct StagePorts {
+    ports: HashMap<u16, Stage>,
+}
+
+impl Default for StagePorts {
+    fn default() -> Self {
+        StagePorts {
+            ports: HashMap::from([
+                (6900, Stage::Login),
+                (6121, Stage::Char),
+                (5121, Stage::Map),
+            ]),
+        }
+    }
+}
+
+impl StagePorts {
+    /// Replaces the ports of `stage` with `port`.
+    pub fn set(&mut self, stage: Stage, port: u16) {
+        self.ports.retain(|_, port_stage| *port_stage != stage);
+        self.ports.insert(port, stage);
+    }
+
+    /// Adds another port for `stage`.
+    pub fn add(&mut self, stage: Stage, port: u16) {
+        self.ports.insert(port, stage);
+    }
+
+    pub fn stage(&self, port: u16) -> Option<Stage> {
+        self.ports.get(&port).copied()
+    }
+}
+
+type StreamKey = ((IpAddr, u16), (IpAddr, u16));
+
+/// One direction of a TCP connection, put back in order.
+struct TcpStream {
+    /// Sequence number of the first payload byte.
+    initial_seq: Option<u32>,
+    /// Whether the SYN was captured, so `data` starts with the first byte sent.
+    from_start: bool,
+    data: Vec<u8>,
+    /// Offset in `data` where each segment starts, with its timestamp.
+    timestamps: Vec<(usize, u64)>,
+    /// Segments received ahead of a missing one, by offset.
+    pending: BTreeMap<usize, (u64, Vec<u8>)>,
+}
+
+impl TcpStream {
+    fn new() -> TcpStream {
+        TcpStream {
+            initial_seq: None,
+            from_start: false,
+            data: Vec::new(),
+            timestamps: Vec::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    fn push(&mut self, segment: TcpSegment) {
+        if segment.flags & TCP_FLAG_SYN != 0 {
+            self.initial_seq = Some(segment.seq.wrapping_add(1));
+            self.from_start = true;
+            return;
+        }
+        if segment.payload.is_empty() {
+            return;
+        }
+
+        let initial_seq = *self.initial_seq.get_or_insert(segment.seq);
+        let offset = segment.seq.wrapping_sub(initial_seq);
+        // before the first byte we know of, a retransmission
+        if offset > u32::MAX / 2 {
+            return;
+        }
+
+        self.pending
+            .insert(offset as usize, (segment.timestamp, segment.payload));
+
+        while let Some(entry) = self.pending.first_entry() {
+            let offset = *entry.key();
+            if offset > self.data.len() {
+                break;
+            }
+
+            let (timestamp, payload) = entry.remove();
+            // retransmitted segments overlap what we already have
+            let skip = self.data.len() - offset;
+            if skip < payload.len() {
+                self.timestamps.push((self.data.len(), timestamp));
+                self.data.extend_from_slice(&payload[skip..]);
+            }
+        }
+    }
+
+    fn timestamp_at(&self, offset: usize) -> u64 {
+        match self
+            .timestamps
+            .binary_search_by_key(&offset, |(start, _)| *start)
+        {
+            Ok(index) => self.timestamps[index].1,
+            Err(0) => 0,
+            Err(index) => self.timestamps[index - 1].1,
+        }
+    }
+}
+
+/// Splits a server stream into packets with the length table of its stage.
+/// An unknown packet id ends the split: the rest of the stream is kept as a
+/// single packet, so the replay reports it.
+fn split_packets(stage: Stage, session: u32, stream: &TcpStream) -> Vec<CapturedPacket> {
+    // the char server sends the account id before the first packet
+    let prefix_len = if stage == Stage::Char && stream.from_start {
+        4
+    } else {
+        0
+    };
+    let mut splitter = PacketSplitter::new(stage.packets_len()).with_raw_prefix(prefix_len);
+
+    let mut chunks = splitter.push(&stream.data);
+    // truncated last packet
+    if !splitter.remaining().is_empty() {
+        chunks.push(Chunk::Packet(splitter.remaining().to_vec()));
+    }
+
+    let mut packets = Vec::new();
+    let mut position = 0;
+    for chunk in chunks {
+        let data = chunk.data();
+        if position >= prefix_len {
+            packets.push(
+                CapturedPacket::new(
+                    stream.timestamp_at(position),
+                    Direction::Received,
+                    stage,
+                    data,
+                )
+                .with_session(session),
+            );
+        }
+        position += data.len();
+    }
+
+    packets
+}
+
+/// Turns a pcap or pcapng file into a capture of the server packets, each
+/// TCP connection under its own session id.
+///
+/// Client packets are not split, we only have length tables for what the
+/// servers send.
+pub fn import(buffer: &[u8], ports: &StagePorts) -> Result<Capture, ParseError> {
+    let frames = read_frames(buffer)?;
+    let start = frames.first().map(|frame| frame.timestamp).unwrap_or(0);
+
+    // streams in the order they were first seen
+    let mut streams: Vec<(Stage, TcpStream)> = Vec::new();
+    let mut stream_index: HashMap<StreamKey, usize> = HashMap::new();
+
+    for frame in &frames {
+        let Some(segment) = parse_tcp(frame) else {
+            continue;
+        };
+        let Some(stage) = ports.stage(segment.src.1) else {
+            continue;
+        };
+
+        let key = (segment.src, segment.dst);
+        let index = *stream_index.entry(key).or_insert_with(|| {
+            streams.push((stage, TcpStream::new()));
+            streams.len() - 1
+        });
+        streams[index].1.push(segment);
+    }
+
+    // each stream is recorded as its own connection, counting from 1
+    let mut packets: Vec<CapturedPacket> = streams
+        .iter()
+        .enumerate()
+        .flat_map(|(index, (stage, stream))| split_packets(*stage, index as u32 + 1, stream))
+        .collect();
+    packets.sort_by_key(|packet| packet.timestamp);
+
+    Ok(Capture { start, packets })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::{
+        pcap::tests::{build_pcap, build_tcp_frame},
+        replay::{replay, PacketOutcome},
+    };
+
+    static SERVER: [u8; 4] = [10, 0, 0, 1];
+    static CLIENT: [u8; 4] = [10, 0, 0, 2];
+
+    #[tokio::test]
+    async fn reassembles_streams_into_decodable_packets() {
+        // map server: weight limit (6 bytes) and map block list (6 bytes),
+        // split across segments that arrive out of order and retransmitted
+        let map_data = [
+            0xDE, 0x0A, 0x32, 0x00, 0x00, 0x00, 0x83, 0x02, 0x01, 0x00, 0x00, 0x00,
+        ];
+        let map = |seq: u32, flags: u8, payload: &[u8]| {
+            build_tcp_frame((SERVER, 5121), (CLIENT, 50001), seq, flags, payload)
+        };
+
+        // char server: account id, then a pin code state
+        let mut char_data = vec![1, 0, 0, 0, 0xB9, 0x08];
+        char_data.extend_from_slice(&[0u8; 10]);
+
+        let frames = vec![
+            (1000, map(499, TCP_FLAG_SYN, &[])),
+            (1001, map(500, 0x18, &map_data[..4])),
+            (1003, map(508, 0x18, &map_data[8..])),
+            (1002, map(504, 0x18, &map_data[4..8])),
+            (1004, map(504, 0x18, &map_data[4..8])),
+            (
+                1005,
+                build_tcp_frame((SERVER, 6121), (CLIENT, 50000), 7, TCP_FLAG_SYN, &[]),
+            ),
+            (
+                1006,
+                build_tcp_frame((SERVER, 6121), (CLIENT, 50000), 8, 0x18, &char_data),
+            ),
+            // client packets are ignored
+            (
+                1007,
+                build_tcp_frame((CLIENT, 50001), (SERVER, 5121), 1, 0x18, &[0x7D, 0x00]),
+            ),
+        ];
+
+        let capture = import(&build_pcap(&frames), &StagePorts::default()).unwrap();
+        let packets: Vec<(u32, Stage, u16, u64)> = capture
+            .packets
+            .iter()
+            .map(|packet| {
+                (
+                    packet.session,
+                    packet.stage,
+                    packet.packet_id,
+                    packet.timestamp,
+                )
+            })
+            .collect();
+        assert_eq!(
+            packets,
+            vec![
+                (1, Stage::Map, 0x0ADE, 1001),
+                (1, Stage::Map, 0x0283, 1002),
+                (2, Stage::Char, 0x08B9, 1006),
+            ]
+        );
+
+        let report = replay(&capture).await;
+        assert!(report.is_clean());
+    }
+
+    #[tokio::test]
+    async fn unknown_ids_keep_the_rest_of_the_stream() {
+        let mut ports = StagePorts::default();
+        ports.set(Stage::Map, 4501);
+
+        let payload = [0xDE, 0x0A, 0x32, 0x00, 0x00, 0x00, 0xFE, 0xFF, 0x01, 0x02];
+        let frames = vec![(
+            0,
+            build_tcp_frame((SERVER, 4501), (CLIENT, 50001), 1, 0x18, &payload),
+        )];
+
+        let capture = import(&build_pcap(&frames), &ports).unwrap();
+        assert_eq!(capture.packets.len(), 2);
+        assert_eq!(capture.packets[1].data, vec![0xFE, 0xFF, 0x01, 0x02]);
+
+        let report = replay(&capture).await;
+        assert_eq!(report.packets[1].outcome, PacketOutcome::UnknownId);
+    }
+}
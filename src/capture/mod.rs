@@ -0,0 +1,83 @@
+//! Packet captures: every packet sent or received, with its stage and
+//! direction, so sessions can be attached to bug reports and replayed.
+
+use std::collections::HashMap;
+
+use crate::protocol::{
+    character_list::{self, CharListClient, CharListServer},
+    game::{self, GameClient, GameServer},
+    login::{self, LoginClient, LoginServer},
+};
+
+pub mod format;
+pub mod import;
+pub mod pcap;
+pub mod recorder;
+pub mod replay;
+pub mod splitter;
+
+#[derive(num_enum::TryFromPrimitive, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(u8)]
+pub enum Stage {
+    Login = 0,
+    Char = 1,
+    Map = 2,
+}
+
+#[derive(num_enum::TryFromPrimitive, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(u8)]
+pub enum Direction {
+    Sent = 0,
+    Received = 1,
+}
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Login => "login",
+            Stage::Char => "char",
+            Stage::Map => "map",
+        }
+    }
+
+    /// Length table of the packets the server sends on this stage.
+    pub fn packets_len(&self) -> HashMap<u16, u16> {
+        match self {
+            Stage::Login => login::login_packets_len(),
+            Stage::Char => character_list::char_list_packets_len(),
+            Stage::Map => game::game_packets_len(),
+        }
+    }
+
+    /// Length table of the packets the client sends on this stage.
+    pub fn client_packets_len(&self) -> HashMap<u16, u16> {
+        match self {
+            Stage::Login => login::login_client_packets_len(),
+            Stage::Char => character_list::char_list_client_packets_len(),
+            Stage::Map => game::game_client_packets_len(),
+        }
+    }
+
+    /// Name of the packet in our protocol enums, `None` for ids we don't know.
+    pub fn packet_name(&self, direction: Direction, packet_id: u16) -> Option<String> {
+        let name = match (self, direction) {
+            (Stage::Login, Direction::Sent) => {
+                format!("{:?}", LoginClient::try_from(packet_id).ok()?)
+            }
+            (Stage::Login, Direction::Received) => {
+                format!("{:?}", LoginServer::try_from(packet_id).ok()?)
+            }
+            (Stage::Char, Direction::Sent) => {
+                format!("{:?}", CharListClient::try_from(packet_id).ok()?)
+            }
+            (Stage::Char, Direction::Received) => {
+                format!("{:?}", CharListServer::try_from(packet_id).ok()?)
+            }
+            (Stage::Map, Direction::Sent) => format!("{:?}", GameClient::try_from(packet_id).ok()?),
+            (Stage::Map, Direction::Received) => {
+                format!("{:?}", GameServer::try_from(packet_id).ok()?)
+            }
+        };
+        Some(name)
+    }
+}
@@ -0,0 +1,429 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::io::{error::ParseError, reader::BinaryReader};
+
+pub static PCAP_MAGIC_MICROS: u32 = 0xA1B2_C3D4;
+pub static PCAP_MAGIC_NANOS: u32 = 0xA1B2_3C4D;
+pub static PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+pub static PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+/// pcapng block types we read, everything else is skipped.
+static PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+static PCAPNG_SIMPLE_PACKET: u32 = 0x0000_0003;
+static PCAPNG_ENHANCED_PACKET: u32 = 0x0000_0006;
+static PCAPNG_OPTION_END: u16 = 0;
+static PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+/// Link types (`LINKTYPE_*` in libpcap), const to be matched on.
+pub const LINKTYPE_NULL: u32 = 0;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+pub const LINKTYPE_IPV4: u32 = 228;
+pub const LINKTYPE_IPV6: u32 = 229;
+pub const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+static ETHERTYPE_IPV4: u16 = 0x0800;
+static ETHERTYPE_IPV6: u16 = 0x86DD;
+static ETHERTYPE_VLAN: u16 = 0x8100;
+static IP_PROTOCOL_TCP: u8 = 6;
+
+pub static TCP_FLAG_FIN: u8 = 0x01;
+pub static TCP_FLAG_SYN: u8 = 0x02;
+pub static TCP_FLAG_RST: u8 = 0x04;
+
+/// A captured link layer frame.
+pub struct Frame {
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub link_type: u32,
+    pub data: Vec<u8>,
+}
+
+pub struct TcpSegment {
+    pub timestamp: u64,
+    pub src: (IpAddr, u16),
+    pub dst: (IpAddr, u16),
+    pub seq: u32,
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Little-endian reader that swaps bytes for big-endian captures.
+struct EndianReader<'a> {
+    data: BinaryReader<'a>,
+    big_endian: bool,
+}
+
+impl<'a> EndianReader<'a> {
+    fn read_u16(&mut self) -> Result<u16, ParseError> {
+        let value = self.data.read_u16()?;
+        Ok(if self.big_endian {
+            value.swap_bytes()
+        } else {
+            value
+        })
+    }
+
+    fn read_u32(&mut self) -> Result<u32, ParseError> {
+        let value = self.data.read_u32()?;
+        Ok(if self.big_endian {
+            value.swap_bytes()
+        } else {
+            value
+        })
+    }
+}
+
+fn to_millis(value: u64, units_per_second: u64) -> u64 {
+    (value as u128 * 1000 / units_per_second.max(1) as u128) as u64
+}
+
+/// Reads every frame of a pcap or pcapng file.
+pub fn read_frames(buffer: &[u8]) -> Result<Vec<Frame>, ParseError> {
+    let mut data = BinaryReader::new(buffer);
+    let magic = data.read_u32()?;
+
+    if magic == PCAPNG_SECTION_HEADER {
+        read_pcapng(buffer)
+    } else if [PCAP_MAGIC_MICROS, PCAP_MAGIC_NANOS].contains(&magic)
+        || [PCAP_MAGIC_MICROS, PCAP_MAGIC_NANOS].contains(&magic.swap_bytes())
+    {
+        read_pcap(buffer)
+    } else {
+        Err(ParseError::InvalidMagic)
+    }
+}
+
+fn read_pcap(buffer: &[u8]) -> Result<Vec<Frame>, ParseError> {
+    let mut data = BinaryReader::new(buffer);
+    let magic = data.read_u32()?;
+    let big_endian = ![PCAP_MAGIC_MICROS, PCAP_MAGIC_NANOS].contains(&magic);
+    let magic = if big_endian {
+        magic.swap_bytes()
+    } else {
+        magic
+    };
+    let units_per_second = if magic == PCAP_MAGIC_NANOS {
+        1_000_000_000
+    } else {
+        1_000_000
+    };
+
+    let mut data = EndianReader { data, big_endian };
+    data.data.skip_bytes(16); // version, timezone, sigfigs, snaplen
+    let link_type = data.read_u32()?;
+
+    let mut frames = Vec::new();
+    while !data.data.is_eof() {
+        let seconds = data.read_u32()? as u64;
+        let fraction = data.read_u32()? as u64;
+        let captured_len = data.read_u32()?;
+        data.data.skip_bytes(4); // original length
+
+        frames.push(Frame {
+            timestamp: seconds * 1000 + to_millis(fraction, units_per_second),
+            link_type,
+            data: data.data.read_bytes(captured_len as usize)?.to_vec(),
+        });
+    }
+
+    Ok(frames)
+}
+
+fn read_pcapng(buffer: &[u8]) -> Result<Vec<Frame>, ParseError> {
+    let mut position = 0;
+    let mut big_endian = false;
+    // (link type, timestamp units per second) of each interface of the section
+    let mut interfaces: Vec<(u32, u64)> = Vec::new();
+    let mut frames = Vec::new();
+
+    while position < buffer.len() {
+        let mut header = BinaryReader::new(&buffer[position..]);
+        // section header type reads the same in both byte orders
+        if header.read_u32()? == PCAPNG_SECTION_HEADER {
+            // byte order is only known after the length
+            header.skip_bytes(4);
+            big_endian = header.read_u32()? != PCAPNG_BYTE_ORDER_MAGIC;
+            interfaces.clear();
+        }
+
+        let mut header = EndianReader {
+            data: BinaryReader::new(&buffer[position..]),
+            big_endian,
+        };
+        let block_type = header.read_u32()?;
+        let block_len = header.read_u32()? as usize;
+        if block_len < 12 || position + block_len > buffer.len() {
+            return Err(ParseError::UnexpectedEof);
+        }
+
+        let body = &buffer[position + 8..position + block_len - 4];
+        let mut data = EndianReader {
+            data: BinaryReader::new(body),
+            big_endian,
+        };
+
+        if block_type == PCAPNG_INTERFACE_DESCRIPTION {
+            let link_type = data.read_u16()? as u32;
+            data.data.skip_bytes(6); // reserved, snaplen
+
+            let mut units_per_second = 1_000_000;
+            while data.data.remaining() >= 4 {
+                let code = data.read_u16()?;
+                let len = data.read_u16()? as usize;
+                if code == PCAPNG_OPTION_END {
+                    break;
+                }
+                let value = data.data.read_bytes(len)?;
+                if code == PCAPNG_OPTION_TSRESOL && len == 1 {
+                    // most significant bit set: power of two, otherwise of ten
+                    units_per_second = if value[0] & 0x80 != 0 {
+                        1u64 << (value[0] & 0x7F).min(63)
+                    } else {
+                        10u64.pow((value[0] as u32).min(19))
+                    };
+                }
+                data.data.skip_bytes((4 - len % 4) % 4);
+            }
+
+            interfaces.push((link_type, units_per_second));
+        } else if block_type == PCAPNG_ENHANCED_PACKET {
+            let interface = data.read_u32()? as usize;
+            let high = data.read_u32()? as u64;
+            let low = data.read_u32()? as u64;
+            let captured_len = data.read_u32()? as usize;
+            data.data.skip_bytes(4); // original length
+
+            let (link_type, units_per_second) =
+                *interfaces.get(interface).ok_or(ParseError::UnexpectedEof)?;
+            frames.push(Frame {
+                timestamp: to_millis(high << 32 | low, units_per_second),
+                link_type,
+                data: data.data.read_bytes(captured_len)?.to_vec(),
+            });
+        } else if block_type == PCAPNG_SIMPLE_PACKET {
+            let original_len = data.read_u32()? as usize;
+            let (link_type, _) = *interfaces.first().ok_or(ParseError::UnexpectedEof)?;
+            let captured_len = original_len.min(data.data.remaining());
+
+            // simple packets have no timestamp
+            frames.push(Frame {
+                timestamp: frames
+                    .last()
+                    .map(|frame: &Frame| frame.timestamp)
+                    .unwrap_or(0),
+                link_type,
+                data: data.data.read_bytes(captured_len)?.to_vec(),
+            });
+        }
+
+        position += block_len;
+    }
+
+    Ok(frames)
+}
+
+fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *data.get(offset)?,
+        *data.get(offset + 1)?,
+    ]))
+}
+
+fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Strips the link layer, returns the IP packet.
+fn ip_packet(frame: &Frame) -> Option<&[u8]> {
+    let data = &frame.data[..];
+    match frame.link_type {
+        LINKTYPE_ETHERNET => {
+            let mut offset = 12;
+            let mut ether_type = be_u16(data, offset)?;
+            while ether_type == ETHERTYPE_VLAN {
+                offset += 4;
+                ether_type = be_u16(data, offset)?;
+            }
+            if ether_type != ETHERTYPE_IPV4 && ether_type != ETHERTYPE_IPV6 {
+                return None;
+            }
+            data.get(offset + 2..)
+        }
+        // 4 bytes address family, in the byte order of the capturing host
+        LINKTYPE_NULL => data.get(4..),
+        LINKTYPE_LINUX_SLL => data.get(16..),
+        LINKTYPE_LINUX_SLL2 => data.get(20..),
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => Some(data),
+        _ => None,
+    }
+}
+
+/// Parses the TCP segment carried by a frame, `None` for anything else.
+pub fn parse_tcp(frame: &Frame) -> Option<TcpSegment> {
+    let ip = ip_packet(frame)?;
+
+    let (src, dst, tcp) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = (ip[0] & 0x0F) as usize * 4;
+            let total_len = be_u16(ip, 2)? as usize;
+            // fragments are not reassembled
+            let fragment = be_u16(ip, 6)? & 0x3FFF;
+            if *ip.get(9)? != IP_PROTOCOL_TCP || fragment != 0 {
+                return None;
+            }
+            let src = Ipv4Addr::from(be_u32(ip, 12)?);
+            let dst = Ipv4Addr::from(be_u32(ip, 16)?);
+            // total length drops the ethernet padding
+            let tcp = ip.get(header_len..total_len.min(ip.len()))?;
+            (IpAddr::V4(src), IpAddr::V4(dst), tcp)
+        }
+        6 => {
+            let payload_len = be_u16(ip, 4)? as usize;
+            if *ip.get(6)? != IP_PROTOCOL_TCP {
+                return None;
+            }
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            let tcp = ip.get(40..(40 + payload_len).min(ip.len()))?;
+            (
+                IpAddr::V6(Ipv6Addr::from(src)),
+                IpAddr::V6(Ipv6Addr::from(dst)),
+                tcp,
+            )
+        }
+        _ => return None,
+    };
+
+    let header_len = (*tcp.get(12)? >> 4) as usize * 4;
+    Some(TcpSegment {
+        timestamp: frame.timestamp,
+        src: (src, be_u16(tcp, 0)?),
+        dst: (dst, be_u16(tcp, 2)?),
+        seq: be_u32(tcp, 4)?,
+        flags: *tcp.get(13)?,
+        payload: tcp.get(header_len..)?.to_vec(),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Ethernet + IPv4 + TCP frame, checksums are left empty.
+    pub(crate) fn build_tcp_frame(
+        src: ([u8; 4], u16),
+        dst: ([u8; 4], u16),
+        seq: u32,
+        flags: u8,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+
+        let total_len = 20 + 20 + payload.len() as u16;
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&total_len.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0x40, 0, 64, IP_PROTOCOL_TCP, 0, 0]);
+        frame.extend_from_slice(&src.0);
+        frame.extend_from_slice(&dst.0);
+
+        frame.extend_from_slice(&src.1.to_be_bytes());
+        frame.extend_from_slice(&dst.1.to_be_bytes());
+        frame.extend_from_slice(&seq.to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 0, 0x50, flags, 0xFF, 0xFF, 0, 0, 0, 0]);
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Classic pcap, microsecond timestamps, little-endian.
+    pub(crate) fn build_pcap(frames: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        let mut pcap = Vec::new();
+        pcap.extend_from_slice(&PCAP_MAGIC_MICROS.to_le_bytes());
+        pcap.extend_from_slice(&[2, 0, 4, 0]);
+        pcap.extend_from_slice(&[0u8; 8]);
+        pcap.extend_from_slice(&65535u32.to_le_bytes());
+        pcap.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+
+        for (timestamp, frame) in frames {
+            pcap.extend_from_slice(&((timestamp / 1000) as u32).to_le_bytes());
+            pcap.extend_from_slice(&((timestamp % 1000 * 1000) as u32).to_le_bytes());
+            pcap.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(frame);
+        }
+        pcap
+    }
+
+    /// Big-endian pcapng with nanosecond timestamps.
+    fn build_pcapng(frames: &[(u64, Vec<u8>)]) -> Vec<u8> {
+        fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+            let len = (body.len() + 12) as u32;
+            let mut block = block_type.to_be_bytes().to_vec();
+            block.extend_from_slice(&len.to_be_bytes());
+            block.extend_from_slice(body);
+            block.extend_from_slice(&len.to_be_bytes());
+            block
+        }
+
+        let mut section = PCAPNG_BYTE_ORDER_MAGIC.to_be_bytes().to_vec();
+        section.extend_from_slice(&[0, 1, 0, 0]);
+        section.extend_from_slice(&u64::MAX.to_be_bytes());
+
+        let mut interface = (LINKTYPE_ETHERNET as u16).to_be_bytes().to_vec();
+        interface.extend_from_slice(&[0, 0, 0, 0, 0xFF, 0xFF]);
+        interface.extend_from_slice(&PCAPNG_OPTION_TSRESOL.to_be_bytes());
+        interface.extend_from_slice(&1u16.to_be_bytes());
+        interface.extend_from_slice(&[9, 0, 0, 0]);
+        interface.extend_from_slice(&[0, 0, 0, 0]);
+
+        let mut pcapng = block(PCAPNG_SECTION_HEADER, &section);
+        pcapng.extend(block(PCAPNG_INTERFACE_DESCRIPTION, &interface));
+        // unknown blocks are skipped
+        pcapng.extend(block(0x0000_0BAD, &[1, 2, 3, 4]));
+
+        for (timestamp, frame) in frames {
+            let nanos = timestamp * 1_000_000;
+            let mut packet = 0u32.to_be_bytes().to_vec();
+            packet.extend_from_slice(&((nanos >> 32) as u32).to_be_bytes());
+            packet.extend_from_slice(&(nanos as u32).to_be_bytes());
+            packet.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            packet.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+            packet.extend_from_slice(frame);
+            packet.resize((packet.len() + 3) & !3, 0);
+            pcapng.extend(block(PCAPNG_ENHANCED_PACKET, &packet));
+        }
+        pcapng
+    }
+
+    #[test]
+    fn reads_pcap_and_pcapng_frames() {
+        let frame = build_tcp_frame(
+            ([10, 0, 0, 1], 6900),
+            ([10, 0, 0, 2], 50000),
+            1000,
+            0x18,
+            &[0x81, 0x00, 0x01],
+        );
+        let frames = vec![(1_700_000_000_123, frame)];
+
+        for file in [build_pcap(&frames), build_pcapng(&frames)] {
+            let read = read_frames(&file).unwrap();
+            assert_eq!(read.len(), 1);
+            assert_eq!(read[0].timestamp, 1_700_000_000_123);
+
+            let segment = parse_tcp(&read[0]).unwrap();
+            assert_eq!(segment.src, (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 6900));
+            assert_eq!(segment.dst.1, 50000);
+            assert_eq!(segment.seq, 1000);
+            assert_eq!(segment.payload, vec![0x81, 0x00, 0x01]);
+        }
+
+        assert!(matches!(
+            read_frames(&[0u8; 24]),
+            Err(ParseError::InvalidMagic)
+        ));
+    }
+}
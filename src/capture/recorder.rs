@@ -0,0 +1,265 @@
+use std::{
+    fs::File,
+    future::Future,
+    io::{self, BufWriter, Write},
+    iter,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc, Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::broadcast;
+
+use crate::metrics;
+
+use super::{
+    format::{write_header, write_record, write_text_record, CapturedPacket},
+    Direction, Stage,
+};
+
+/// Recorder used by the listeners, set once with `start`.
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+/// Live feed of every recorded packet, see `subscribe`.
+static PACKET_BUS: OnceLock<broadcast::Sender<Arc<CapturedPacket>>> = OnceLock::new();
+/// Packets kept for a slow subscriber before it starts missing some.
+static PACKET_BUS_CAPACITY: usize = 4096;
+/// Next id handed by `next_session_id`, 0 is left for "no session".
+static NEXT_SESSION_ID: AtomicU32 = AtomicU32::new(1);
+
+tokio::task_local! {
+    static SESSION_ID: u32;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CaptureFormat {
+    /// Compact form, readable back with `format::read_capture`.
+    Binary,
+    /// One line per packet plus its hex dump, for bug reports.
+    Text,
+}
+
+/// Packets are written by a thread of their own, so recording never waits
+/// on the disk. `finish`, or dropping the recorder, writes what is left.
+pub struct Recorder {
+    packets: Mutex<Option<mpsc::Sender<CapturedPacket>>>,
+    writer: Mutex<Option<thread::JoinHandle<()>>>,
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Recorder {
+    pub fn create(file_name: &str, format: CaptureFormat) -> io::Result<Recorder> {
+        let start = now_millis();
+        let mut writer = BufWriter::new(File::create(file_name)?);
+        match format {
+            CaptureFormat::Binary => write_header(&mut writer, start)?,
+            CaptureFormat::Text => writeln!(writer, "# capture started at {} (unix ms)", start)?,
+        }
+        writer.flush()?;
+
+        let (packets, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("capture-writer".to_string())
+            .spawn(move || write_packets(writer, format, start, receiver))?;
+
+        Ok(Recorder {
+            packets: Mutex::new(Some(packets)),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    /// Queues a packet of the current session, see `scope`.
+    pub fn record(&self, stage: Stage, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let packet = CapturedPacket::new(now_millis(), direction, stage, data)
+            .with_session(current_session());
+        self.queue(packet)
+    }
+
+    fn queue(&self, packet: CapturedPacket) -> io::Result<()> {
+        self.packets
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|packets| packets.send(packet).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "capture writer stopped"))
+    }
+
+    /// Writes the packets still queued and stops the writer, packets
+    /// recorded afterwards are dropped.
+    pub fn finish(&self) {
+        // closing the channel stops the writer once the queue is written
+        self.packets.lock().unwrap().take();
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Flushes each time the queue is drained rather than on every packet,
+/// captures still matter most when the client crashes.
+fn write_packets(
+    mut writer: BufWriter<File>,
+    format: CaptureFormat,
+    start: u64,
+    packets: mpsc::Receiver<CapturedPacket>,
+) {
+    while let Ok(packet) = packets.recv() {
+        let result = iter::once(packet)
+            .chain(packets.try_iter())
+            .try_for_each(|packet| match format {
+                CaptureFormat::Binary => write_record(&mut writer, &packet),
+                CaptureFormat::Text => write_text_record(&mut writer, &packet, start),
+            })
+            .and_then(|_| writer.flush());
+
+        if let Err(e) = result {
+            tracing::warn!(error = %e, "failed to record packet");
+        }
+    }
+}
+
+/// Starts recording every packet to `file_name`. Returns false when a
+/// recorder was already started.
+pub fn start(file_name: &str, format: CaptureFormat) -> io::Result<bool> {
+    let recorder = Recorder::create(file_name, format)?;
+    Ok(RECORDER.set(recorder).is_ok())
+}
+
+/// Writes out what the recorder started with `start` still has queued. The
+/// recorder is never dropped, binaries call this before exiting.
+pub fn finish() {
+    if let Some(recorder) = RECORDER.get() {
+        recorder.finish();
+    }
+}
+
+/// Id for a new session, or a proxied connection.
+pub fn next_session_id() -> u32 {
+    NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Session the current task records for, 0 outside of `scope`.
+pub fn current_session() -> u32 {
+    SESSION_ID.try_with(|session| *session).unwrap_or(0)
+}
+
+/// Runs `future` recording its packets under `session`.
+pub async fn scope<F: Future>(session: u32, future: F) -> F::Output {
+    SESSION_ID.scope(session, future).await
+}
+
+/// Receives every packet recorded from now on, whether a capture file was
+/// started or not.
+pub fn subscribe() -> broadcast::Receiver<Arc<CapturedPacket>> {
+    PACKET_BUS
+        .get_or_init(|| broadcast::channel(PACKET_BUS_CAPACITY).0)
+        .subscribe()
+}
+
+/// Records a packet to the capture file, when `start` was called, and to
+/// the subscribers.
+pub fn record(stage: Stage, direction: Direction, data: &[u8]) {
+    if let [low, high, ..] = data {
+        tracing::trace!(
+            stage = stage.name(),
+            ?direction,
+            packet_id = format_args!("0x{:04X}", u16::from_le_bytes([*low, *high])),
+            len = data.len(),
+            "packet"
+        );
+    }
+    metrics::record_packet(stage, direction, data);
+
+    let session = current_session();
+    if let Some(recorder) = RECORDER.get() {
+        let packet =
+            CapturedPacket::new(now_millis(), direction, stage, data).with_session(session);
+        if let Err(e) = recorder.queue(packet) {
+            tracing::warn!(error = %e, "failed to record packet");
+        }
+    }
+
+    if let Some(bus) = PACKET_BUS.get() {
+        if bus.receiver_count() > 0 {
+            let packet =
+                CapturedPacket::new(now_millis(), direction, stage, data).with_session(session);
+            let _ = bus.send(Arc::new(packet));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::capture::format::read_capture;
+
+    #[test]
+    fn records_binary_and_text_files() {
+        let dir = std::env::temp_dir().join(format!("capture-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let binary_file = dir.join("session.rocap");
+        let text_file = dir.join("session.txt");
+
+        for (file, format) in [
+            (&binary_file, CaptureFormat::Binary),
+            (&text_file, CaptureFormat::Text),
+        ] {
+            let recorder = Recorder::create(file.to_str().unwrap(), format).unwrap();
+            recorder
+                .record(Stage::Char, Direction::Sent, &[0x66, 0x00, 0x00])
+                .unwrap();
+            SESSION_ID.sync_scope(7, || {
+                recorder
+                    .record(Stage::Map, Direction::Received, &[0x83, 0x02, 1, 0, 0, 0])
+                    .unwrap();
+            });
+            recorder.finish();
+            assert!(recorder
+                .record(Stage::Map, Direction::Sent, &[0x7D, 0x00])
+                .is_err());
+        }
+
+        let capture = read_capture(&fs::read(&binary_file).unwrap()).unwrap();
+        let ids: Vec<(u32, Stage, Direction, u16)> = capture
+            .packets
+            .iter()
+            .map(|packet| {
+                (
+                    packet.session,
+                    packet.stage,
+                    packet.direction,
+                    packet.packet_id,
+                )
+            })
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                (0, Stage::Char, Direction::Sent, 0x0066),
+                (7, Stage::Map, Direction::Received, 0x0283)
+            ]
+        );
+
+        let text = fs::read_to_string(&text_file).unwrap();
+        assert!(text.contains("char  -> 0x0066 (3 bytes)"));
+        assert!(text.contains("[7] map   <- 0x0283 (6 bytes)\n    83 02 01 00 00 00\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+use std::fmt;
+
+use crate::{
+    input_message::{DecodedField, InputMessage},
+    protocol::{character_list, game, login},
+    r#const::PACKET_HEADER_LEN,
+};
+
+use super::{format::Capture, Direction, Stage};
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum PacketOutcome {
+    Decoded,
+    /// Not in the packet length table of its stage.
+    UnknownId,
+    /// Recorded length differs from the fixed length of the table.
+    LengthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    /// The handler panicked, usually reading past the end of the packet.
+    DecodeError(String),
+    /// The handler returned without reading the whole packet.
+    LeftoverBytes(usize),
+}
+
+pub struct PacketReport {
+    /// Index of the packet in the capture.
+    pub index: usize,
+    /// Session the packet was recorded for, see `Capture::split_sessions`.
+    pub session: u32,
+    pub stage: Stage,
+    pub packet_id: u16,
+    pub outcome: PacketOutcome,
+}
+
+pub struct ReplayReport {
+    pub packets: Vec<PacketReport>,
+}
+
+impl ReplayReport {
+    pub fn failures(&self) -> impl Iterator<Item = &PacketReport> {
+        self.packets
+            .iter()
+            .filter(|packet| packet.outcome != PacketOutcome::Decoded)
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.failures().next().is_none()
+    }
+}
+
+impl fmt::Display for PacketReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "#{} [{}] {} 0x{:04X}: ",
+            self.index,
+            self.session,
+            self.stage.name(),
+            self.packet_id
+        )?;
+        match &self.outcome {
+            PacketOutcome::Decoded => write!(f, "ok"),
+            PacketOutcome::UnknownId => write!(f, "unknown packet id"),
+            PacketOutcome::LengthMismatch { expected, actual } => {
+                write!(f, "expected {} bytes, got {}", expected, actual)
+            }
+            PacketOutcome::DecodeError(message) => write!(f, "decode error: {}", message),
+            PacketOutcome::LeftoverBytes(count) => write!(f, "{} bytes left unread", count),
+        }
+    }
+}
+
+/// Runs the handler of `stage` on a single packet, the same way the listener
+/// does. Packets the handlers send back are dropped.
+async fn handle_packet(stage: Stage, packet_id: u16, mut data: InputMessage) -> InputMessage {
+    let mut sink = tokio::io::sink();
+    match stage {
+        Stage::Login => {
+            login::login_packet_handler(packet_id, &mut data).await;
+        }
+        Stage::Char => {
+            character_list::char_list_packet_handler(&mut sink, 0, packet_id, &mut data).await;
+        }
+        Stage::Map => {
+            let mut state = game::GameState::default();
+            game::game_packet_handler(&mut sink, &mut state, packet_id, &mut data).await;
+        }
+    }
+    data
+}
+
+pub async fn replay_packet(stage: Stage, data: &[u8]) -> PacketOutcome {
+    decode_packet(stage, data).await.0
+}
+
+/// Same as `replay_packet`, also returning every field the handler read.
+/// Fields are lost when the handler panics.
+pub async fn decode_packet(stage: Stage, data: &[u8]) -> (PacketOutcome, Vec<DecodedField>) {
+    let header_size = PACKET_HEADER_LEN as usize;
+    if data.len() < header_size {
+        return (
+            PacketOutcome::LengthMismatch {
+                expected: header_size,
+                actual: data.len(),
+            },
+            Vec::new(),
+        );
+    }
+    let packet_id = u16::from_le_bytes([data[0], data[1]]);
+
+    let body_start = match stage.packets_len().get(&packet_id) {
+        None => return (PacketOutcome::UnknownId, Vec::new()),
+        Some(&u16::MAX) => {
+            let len = match data {
+                [_, _, low, high, ..] => u16::from_le_bytes([*low, *high]) as usize,
+                _ => 0,
+            };
+            if len != data.len() {
+                return (
+                    PacketOutcome::LengthMismatch {
+                        expected: len,
+                        actual: data.len(),
+                    },
+                    Vec::new(),
+                );
+            }
+            header_size + 2
+        }
+        Some(&len) => {
+            if header_size + len as usize != data.len() {
+                return (
+                    PacketOutcome::LengthMismatch {
+                        expected: header_size + len as usize,
+                        actual: data.len(),
+                    },
+                    Vec::new(),
+                );
+            }
+            header_size
+        }
+    };
+
+    // handlers panic on malformed packets, a task keeps the panic contained
+    let input_message = InputMessage::new(data[body_start..].to_vec()).with_trace();
+    let result = tokio::spawn(handle_packet(stage, packet_id, input_message)).await;
+
+    match result {
+        Ok(data) => {
+            let outcome = match data.position < data.length {
+                true => PacketOutcome::LeftoverBytes(data.length - data.position),
+                false => PacketOutcome::Decoded,
+            };
+            (outcome, data.fields.unwrap_or_default())
+        }
+        Err(e) => {
+            let message = match e.try_into_panic() {
+                Ok(panic) => panic
+                    .downcast_ref::<String>()
+                    .cloned()
+                    .or_else(|| panic.downcast_ref::<&str>().map(|s| s.to_string()))
+                    .unwrap_or_default(),
+                Err(e) => e.to_string(),
+            };
+            (PacketOutcome::DecodeError(message), Vec::new())
+        }
+    }
+}
+
+/// Feeds every received packet of a capture through the handlers.
+pub async fn replay(capture: &Capture) -> ReplayReport {
+    let mut packets = Vec::new();
+    for (index, packet) in capture.packets.iter().enumerate() {
+        if packet.direction != Direction::Received {
+            continue;
+        }
+
+        packets.push(PacketReport {
+            index,
+            session: packet.session,
+            stage: packet.stage,
+            packet_id: packet.packet_id,
+            outcome: replay_packet(packet.stage, &packet.data).await,
+        });
+    }
+
+    ReplayReport { packets }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::capture::format::{read_capture, CapturedPacket};
+
+    fn received(stage: Stage, data: &[u8]) -> CapturedPacket {
+        CapturedPacket::new(0, Direction::Received, stage, data)
+    }
+
+    #[tokio::test]
+    async fn reports_each_packet_outcome() {
+        // object action of type 1 (pick up) stops reading after the target id
+        let mut object_action = vec![0x8A, 0x00];
+        object_action.resize(29, 0);
+        object_action[28] = 1;
+
+        let capture = Capture {
+            start: 0,
+            packets: vec![
+                received(Stage::Map, &[0xDE, 0x0A, 0x32, 0, 0, 0]),
+                CapturedPacket::new(0, Direction::Sent, Stage::Map, &[0x7D, 0x00]),
+                received(Stage::Map, &[0xFF, 0xFF]),
+                received(Stage::Map, &[0xDE, 0x0A, 0x32]),
+                received(Stage::Char, &[0x2D, 0x08, 0x06, 0x00, 9, 0]),
+                received(Stage::Char, &[0x2D, 0x08, 0x1E, 0x00, 9, 0, 0, 9, 9, 1]),
+                received(Stage::Login, &[0x81, 0x00, 0x01]),
+                received(Stage::Map, &object_action),
+            ],
+        };
+
+        let report = replay(&capture).await;
+        let outcomes: Vec<(usize, PacketOutcome)> = report
+            .packets
+            .iter()
+            .map(|packet| (packet.index, packet.outcome.clone()))
+            .collect();
+
+        assert_eq!(outcomes[0], (0, PacketOutcome::Decoded));
+        assert_eq!(outcomes[1], (2, PacketOutcome::UnknownId));
+        assert_eq!(
+            outcomes[2],
+            (
+                3,
+                PacketOutcome::LengthMismatch {
+                    expected: 6,
+                    actual: 3
+                }
+            )
+        );
+        assert!(matches!(outcomes[3], (4, PacketOutcome::DecodeError(_))));
+        assert_eq!(
+            outcomes[4],
+            (
+                5,
+                PacketOutcome::LengthMismatch {
+                    expected: 30,
+                    actual: 10
+                }
+            )
+        );
+        assert_eq!(outcomes[5], (6, PacketOutcome::Decoded));
+        assert_eq!(outcomes[6], (7, PacketOutcome::LeftoverBytes(18)));
+        assert_eq!(report.failures().count(), 5);
+    }
+
+    /// Regression suite: every capture dropped in `data/captures` must decode cleanly.
+    /// `smoke.rocap` is checked in so the suite never runs empty.
+    #[tokio::test]
+    async fn recorded_captures_decode_cleanly() {
+        let mut checked = 0;
+        for entry in fs::read_dir("data/captures").unwrap().flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("rocap") {
+                continue;
+            }
+
+            let capture = read_capture(&fs::read(&path).unwrap()).unwrap();
+            let report = replay(&capture).await;
+            let failures: Vec<String> =
+                report.failures().map(|packet| packet.to_string()).collect();
+            assert!(failures.is_empty(), "{}: {:#?}", path.display(), failures);
+            checked += 1;
+        }
+        assert!(checked > 0, "no captures in data/captures");
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+
+use crate::r#const::PACKET_HEADER_LEN;
+
+pub enum Chunk {
+    /// A whole packet, header included.
+    Packet(Vec<u8>),
+    /// Bytes that could not be split: the raw prefix, or everything from an
+    /// unknown packet id to the end of what was buffered.
+    Raw(Vec<u8>),
+}
+
+impl Chunk {
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Chunk::Packet(data) | Chunk::Raw(data) => data,
+        }
+    }
+}
+
+/// Splits a byte stream into packets with a packet length table, as bytes
+/// come in.
+pub struct PacketSplitter {
+    packets_len: HashMap<u16, u16>,
+    buffer: Vec<u8>,
+    /// Bytes to pass through before the first packet.
+    raw_prefix: usize,
+}
+
+impl PacketSplitter {
+    pub fn new(packets_len: HashMap<u16, u16>) -> PacketSplitter {
+        PacketSplitter {
+            packets_len,
+            buffer: Vec::new(),
+            raw_prefix: 0,
+        }
+    }
+
+    /// The char server sends the account id as 4 raw bytes before its first packet.
+    pub fn with_raw_prefix(mut self, len: usize) -> PacketSplitter {
+        self.raw_prefix = len;
+        self
+    }
+
+    /// Returns the chunks completed by `data`, incomplete packets stay buffered.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Chunk> {
+        let header_size = PACKET_HEADER_LEN as usize;
+        self.buffer.extend_from_slice(data);
+
+        let mut chunks = Vec::new();
+        if self.raw_prefix > 0 {
+            let len = self.raw_prefix.min(self.buffer.len());
+            chunks.push(Chunk::Raw(self.buffer.drain(..len).collect()));
+            self.raw_prefix -= len;
+            if self.raw_prefix > 0 {
+                return chunks;
+            }
+        }
+
+        while self.buffer.len() >= header_size {
+            let packet_id = u16::from_le_bytes([self.buffer[0], self.buffer[1]]);
+            let len = match self.packets_len.get(&packet_id) {
+                Some(&u16::MAX) if self.buffer.len() < header_size + 2 => break,
+                Some(&u16::MAX) => u16::from_le_bytes([self.buffer[2], self.buffer[3]]) as usize,
+                Some(&len) => header_size + len as usize,
+                None => 0,
+            };
+
+            // unknown id or broken length, we lost track of the packets
+            if len < header_size {
+                chunks.push(Chunk::Raw(std::mem::take(&mut self.buffer)));
+                break;
+            }
+            if self.buffer.len() < len {
+                break;
+            }
+            chunks.push(Chunk::Packet(self.buffer.drain(..len).collect()));
+        }
+
+        chunks
+    }
+
+    /// Bytes of an incomplete packet left in the buffer.
+    pub fn remaining(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_packets_across_reads() {
+        let packets_len = HashMap::from([(0x0283, 4), (0x008E, u16::MAX)]);
+        let mut splitter = PacketSplitter::new(packets_len).with_raw_prefix(4);
+
+        let mut chunks = splitter.push(&[1, 0, 0]);
+        chunks.extend(splitter.push(&[0, 0x83, 0x02, 1, 0]));
+        assert!(splitter.remaining() == [0x83, 0x02, 1, 0]);
+        chunks.extend(splitter.push(&[0, 0, 0x8E, 0x00, 0x05, 0x00, b'a', 0x11]));
+        chunks.extend(splitter.push(&[0x22, 0x83]));
+
+        let chunks: Vec<(bool, Vec<u8>)> = chunks
+            .iter()
+            .map(|chunk| (matches!(chunk, Chunk::Packet(_)), chunk.data().to_vec()))
+            .collect();
+        assert_eq!(
+            chunks,
+            vec![
+                (false, vec![1, 0, 0]),
+                (false, vec![0]),
+                (true, vec![0x83, 0x02, 1, 0, 0, 0]),
+                (true, vec![0x8E, 0x00, 0x05, 0x00, b'a']),
+                (false, vec![0x11, 0x22, 0x83]),
+            ]
+        );
+        assert!(splitter.remaining().is_empty());
+    }
+}
@@ -1,13 +1,23 @@
 pub mod network {
     use std::io::{Error, ErrorKind};
-    use tokio::{io::AsyncWriteExt, net::TcpStream};
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
 
-    use crate::network_message::NetworkMessage;
+    use crate::{
+        capture::{recorder, Direction, Stage},
+        network_message::NetworkMessage,
+    };
 
-    pub async fn write_message(
-        stream: &mut TcpStream,
+    pub async fn write_message<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        stage: Stage,
         network_message: &NetworkMessage,
     ) -> Result<(), std::io::Error> {
+        recorder::record(
+            stage,
+            Direction::Sent,
+            &network_message.buffer[0..network_message.length as usize],
+        );
+
         let write_result: Result<(), Error> = stream
             .write_all(&network_message.buffer[0..network_message.length as usize])
             .await;
@@ -1,7 +1,24 @@
+use crate::capture::recorder::CaptureFormat;
+
 // host settings
 pub static LOGIN_SERVER_ADDR: &str = "192.168.1.9:6900";
+pub static LOGIN_USERNAME: &str = "test2";
+pub static LOGIN_PASSWORD: &str = "test123";
+
+// packet capture, None disables it
+pub static CAPTURE_FILE: Option<&str> = None;
+pub static CAPTURE_FORMAT: CaptureFormat = CaptureFormat::Text;
+
+// log filter when RUST_LOG is not set, see `logging::init`
+pub static LOG_FILTER: &str = "info";
 
 pub static PACKET_HEADER_LEN: u8 = 2;
 
+// the official client sends its ClientTick about every 12 seconds
+pub static CLIENT_TICK_INTERVAL_SECS: u64 = 12;
+
+// how long `Session::shutdown` waits for the server to ack the quit
+pub static SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+
 // engine settings
-pub static WORKER_THREADS: u8 = 2;
\ No newline at end of file
+pub static WORKER_THREADS: u8 = 2;
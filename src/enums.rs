@@ -112,3 +112,11 @@ pub enum DropEffectMode {
     // Orange was replaced by green in 2020-03-04
     DropEffectOrangePillar,
 }
+
+/// `Restart` request type, the server only answers `CharSelect`.
+#[derive(num_enum::TryFromPrimitive, Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum RestartType {
+    Respawn = 0,
+    CharSelect = 1,
+}
@@ -1,7 +1,43 @@
+use std::fmt;
+
 pub struct InputMessage {
     pub data: Vec<u8>,
     pub length: usize,
-    pub position: usize
+    pub position: usize,
+    /// Every read done by the handler, when tracing is on.
+    pub fields: Option<Vec<DecodedField>>,
+}
+
+/// A value read from a packet, `offset` being relative to the packet body.
+#[derive(Clone, PartialEq, Debug)]
+pub struct DecodedField {
+    pub offset: usize,
+    pub value: FieldValue,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum FieldValue {
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    String(String),
+    Bytes(Vec<u8>),
+    Skipped(usize),
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldValue::U8(value) => write!(f, "u8  {} (0x{:02X})", value, value),
+            FieldValue::U16(value) => write!(f, "u16 {} (0x{:04X})", value, value),
+            FieldValue::U32(value) => write!(f, "u32 {} (0x{:08X})", value, value),
+            FieldValue::U64(value) => write!(f, "u64 {}", value),
+            FieldValue::String(value) => write!(f, "str {:?}", value),
+            FieldValue::Bytes(value) => write!(f, "{} bytes {:02X?}", value.len(), value),
+            FieldValue::Skipped(count) => write!(f, "skipped {} bytes", count),
+        }
+    }
 }
 
 impl InputMessage {
@@ -11,19 +47,34 @@ impl InputMessage {
         InputMessage {
             data,
             length,
-            position: 0
+            position: 0,
+            fields: None,
+        }
+    }
+
+    /// Records every value read, see `fields`.
+    pub fn with_trace(mut self) -> InputMessage {
+        self.fields = Some(Vec::new());
+        self
+    }
+
+    fn trace(&mut self, offset: usize, value: FieldValue) {
+        if let Some(fields) = self.fields.as_mut() {
+            fields.push(DecodedField { offset, value });
         }
     }
 
     pub fn read_u8(&mut self) -> u8 {
         let value = self.data[self.position];
         self.position += 1;
+        self.trace(self.position - 1, FieldValue::U8(value));
         value
     }
 
     pub fn read_u16(&mut self) -> u16 {
         let value = u16::from_le_bytes([self.data[self.position], self.data[self.position + 1]]);
         self.position += 2;
+        self.trace(self.position - 2, FieldValue::U16(value));
         value
     }
 
@@ -32,9 +83,10 @@ impl InputMessage {
             self.data[self.position],
             self.data[self.position + 1],
             self.data[self.position + 2],
-            self.data[self.position + 3]
+            self.data[self.position + 3],
         ]);
         self.position += 4;
+        self.trace(self.position - 4, FieldValue::U32(value));
         value
     }
 
@@ -51,18 +103,26 @@ impl InputMessage {
         ]);
 
         self.position += 8;
+        self.trace(self.position - 8, FieldValue::U64(value));
         value
     }
 
     pub fn read_bytes(&mut self, bytes: u32) -> Vec<u8> {
+        let offset = self.position;
+        // traced as a whole, not byte per byte
+        let fields = self.fields.take();
         let mut data = Vec::new();
         for _ in 0..bytes {
             data.push(self.read_u8());
         }
+        self.fields = fields;
+        self.trace(offset, FieldValue::Bytes(data.clone()));
         data
     }
 
     pub fn read_string(&mut self, n: Option<usize>) -> String {
+        let offset = self.position;
+        let fields = self.fields.take();
         let mut string = String::new();
         let mut read = 0;
         let size = n.unwrap_or(0);
@@ -83,6 +143,8 @@ impl InputMessage {
 
             read += 1;
         }
+        self.fields = fields;
+        self.trace(offset, FieldValue::String(string.clone()));
         string
     }
 
@@ -91,10 +153,11 @@ impl InputMessage {
     }
 
     pub fn skip_bytes(&mut self, bytes: usize) {
+        self.trace(self.position, FieldValue::Skipped(bytes));
         self.position += bytes;
     }
 
     pub fn set_position(&mut self, position: usize) {
         self.position = position;
     }
-}
\ No newline at end of file
+}
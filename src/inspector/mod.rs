@@ -0,0 +1,260 @@
+//! Live packet inspector: every packet of the session as it goes through,
+//! with its name, decoded fields and hex dump. Rendered by `ui`.
+
+use std::{collections::HashSet, sync::Arc};
+
+use crate::{
+    capture::{
+        format::CapturedPacket,
+        replay::{decode_packet, PacketOutcome},
+        Direction,
+    },
+    input_message::DecodedField,
+};
+
+pub mod ui;
+
+/// Packets kept in memory, the oldest are dropped first.
+static MAX_PACKETS: usize = 10000;
+
+pub struct InspectedPacket {
+    pub packet: Arc<CapturedPacket>,
+    /// Name from the protocol enums of its stage and direction.
+    pub name: Option<String>,
+    /// Decoder outcome, only server packets are decoded.
+    pub outcome: Option<PacketOutcome>,
+    pub fields: Vec<DecodedField>,
+}
+
+/// Names and decodes a packet taken from `recorder::subscribe`.
+pub async fn inspect(packet: Arc<CapturedPacket>) -> InspectedPacket {
+    let name = packet.stage.packet_name(packet.direction, packet.packet_id);
+    let (outcome, fields) = match packet.direction {
+        Direction::Received => {
+            let (outcome, fields) = decode_packet(packet.stage, &packet.data).await;
+            (Some(outcome), fields)
+        }
+        Direction::Sent => (None, Vec::new()),
+    };
+
+    InspectedPacket {
+        packet,
+        name,
+        outcome,
+        fields,
+    }
+}
+
+/// Parses a filter such as `0086, 0x02EB 9fe`, ids being hexadecimal.
+/// An empty filter shows every packet.
+pub fn parse_filter(filter: &str) -> Result<Option<HashSet<u16>>, String> {
+    let ids = filter
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|id| !id.is_empty())
+        .map(|id| {
+            let hex = id.trim_start_matches("0x").trim_start_matches("0X");
+            u16::from_str_radix(hex, 16).map_err(|_| format!("invalid packet id: {}", id))
+        })
+        .collect::<Result<HashSet<u16>, String>>()?;
+
+    Ok(match ids.is_empty() {
+        true => None,
+        false => Some(ids),
+    })
+}
+
+#[derive(Default)]
+pub struct Inspector {
+    packets: Vec<InspectedPacket>,
+    /// Packets received while paused, shown on resume.
+    pending: Vec<InspectedPacket>,
+    paused: bool,
+    filter: Option<HashSet<u16>>,
+    /// Index in `visible`, `None` follows the latest packet.
+    selected: Option<usize>,
+    /// Packets the subscriber missed because it was too slow.
+    pub dropped: u64,
+}
+
+impl Inspector {
+    pub fn new() -> Inspector {
+        Inspector::default()
+    }
+
+    pub fn push(&mut self, packet: InspectedPacket) {
+        match self.paused {
+            true => self.pending.push(packet),
+            false => self.append(vec![packet]),
+        }
+    }
+
+    fn append(&mut self, packets: Vec<InspectedPacket>) {
+        self.packets.extend(packets);
+        if self.packets.len() > MAX_PACKETS {
+            let removed = self.packets.len() - MAX_PACKETS;
+            let visible_removed = self.packets[..removed]
+                .iter()
+                .filter(|packet| self.is_visible(packet))
+                .count();
+            self.packets.drain(..removed);
+            self.selected = self
+                .selected
+                .map(|selected| selected.saturating_sub(visible_removed));
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        if !self.paused {
+            let pending = std::mem::take(&mut self.pending);
+            self.append(pending);
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn filter(&self) -> Option<&HashSet<u16>> {
+        self.filter.as_ref()
+    }
+
+    pub fn set_filter(&mut self, filter: Option<HashSet<u16>>) {
+        self.filter = filter;
+        self.selected = None;
+    }
+
+    fn is_visible(&self, packet: &InspectedPacket) -> bool {
+        match &self.filter {
+            Some(ids) => ids.contains(&packet.packet.packet_id),
+            None => true,
+        }
+    }
+
+    /// Packets passing the filter, oldest first.
+    pub fn visible(&self) -> Vec<&InspectedPacket> {
+        self.packets
+            .iter()
+            .filter(|packet| self.is_visible(packet))
+            .collect()
+    }
+
+    /// Index in `visible` of the selected packet, the latest one when
+    /// following.
+    pub fn selected(&self) -> Option<usize> {
+        let count = self.visible().len();
+        match self.selected {
+            Some(selected) if selected < count => Some(selected),
+            _ => count.checked_sub(1),
+        }
+    }
+
+    pub fn selected_packet(&self) -> Option<&InspectedPacket> {
+        let selected = self.selected()?;
+        self.visible().into_iter().nth(selected)
+    }
+
+    pub fn select_previous(&mut self) {
+        self.selected = self.selected().map(|selected| selected.saturating_sub(1));
+    }
+
+    /// Moving past the last packet goes back to following.
+    pub fn select_next(&mut self) {
+        let count = self.visible().len();
+        self.selected = match self.selected() {
+            Some(selected) if selected + 1 < count => Some(selected + 1),
+            _ => None,
+        };
+    }
+
+    pub fn follow(&mut self) {
+        self.selected = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.packets.clear();
+        self.pending.clear();
+        self.selected = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::Stage;
+
+    fn packet(direction: Direction, data: &[u8]) -> InspectedPacket {
+        let packet = CapturedPacket::new(0, direction, Stage::Map, data);
+        InspectedPacket {
+            packet: Arc::new(packet),
+            name: None,
+            outcome: None,
+            fields: Vec::new(),
+        }
+    }
+
+    fn visible_ids(inspector: &Inspector) -> Vec<u16> {
+        inspector
+            .visible()
+            .iter()
+            .map(|packet| packet.packet.packet_id)
+            .collect()
+    }
+
+    #[test]
+    fn filters_pauses_and_follows() {
+        let mut inspector = Inspector::new();
+        inspector.push(packet(Direction::Sent, &[0x7D, 0x00]));
+        inspector.push(packet(Direction::Received, &[0x83, 0x02, 1, 0, 0, 0]));
+        inspector.push(packet(Direction::Received, &[0x86, 0x00]));
+        assert_eq!(inspector.selected(), Some(2));
+
+        inspector.select_previous();
+        inspector.select_previous();
+        inspector.select_previous();
+        assert_eq!(inspector.selected(), Some(0));
+        inspector.select_next();
+        inspector.select_next();
+        inspector.select_next();
+        assert_eq!(inspector.selected(), Some(2));
+
+        inspector.set_filter(parse_filter("0x0283, 86").unwrap());
+        assert_eq!(visible_ids(&inspector), vec![0x0283, 0x0086]);
+        assert!(parse_filter("86 zz").is_err());
+        assert_eq!(parse_filter(" , ").unwrap(), None);
+
+        inspector.toggle_pause();
+        inspector.push(packet(Direction::Received, &[0x86, 0x00]));
+        assert_eq!(inspector.pending(), 1);
+        assert_eq!(visible_ids(&inspector), vec![0x0283, 0x0086]);
+        inspector.toggle_pause();
+        assert_eq!(visible_ids(&inspector), vec![0x0283, 0x0086, 0x0086]);
+        assert_eq!(inspector.selected(), Some(2));
+
+        inspector.clear();
+        assert_eq!(inspector.selected(), None);
+    }
+
+    #[tokio::test]
+    async fn names_and_decodes_server_packets() {
+        let packet = CapturedPacket::new(
+            0,
+            Direction::Received,
+            Stage::Map,
+            &[0xDE, 0x0A, 0x32, 0, 0, 0],
+        );
+        let inspected = inspect(Arc::new(packet)).await;
+        assert_eq!(inspected.name.as_deref(), Some("WeightLimit"));
+        assert_eq!(inspected.outcome, Some(PacketOutcome::Decoded));
+        assert_eq!(inspected.fields.len(), 1);
+
+        let packet = CapturedPacket::new(0, Direction::Sent, Stage::Map, &[0x7D, 0x00]);
+        let inspected = inspect(Arc::new(packet)).await;
+        assert_eq!(inspected.name.as_deref(), Some("AckMap"));
+        assert_eq!(inspected.outcome, None);
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    io::{self, Stderr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, Borders, Paragraph, Row, Table, TableState},
+    Frame, Terminal,
+};
+
+use crate::capture::{
+    format::{format_time, hex_lines},
+    replay::PacketOutcome,
+    Direction,
+};
+
+use super::{parse_filter, InspectedPacket, Inspector};
+
+/// How often the screen is redrawn when no key is pressed.
+static REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+static HELP: &str = "q quit  p/space pause  up/down select  end follow  / filter  c clear";
+
+struct App {
+    inspector: Arc<Mutex<Inspector>>,
+    /// Filter being typed, `None` when not editing.
+    input: Option<String>,
+    /// Last filter error, shown until the next key.
+    error: Option<String>,
+    quit: bool,
+}
+
+/// Runs the inspector on stderr until the user quits, blocking. Stdout is
+/// left to the session, redirect it to keep the screen clean.
+pub fn run(inspector: Arc<Mutex<Inspector>>) -> io::Result<()> {
+    enable_raw_mode()?;
+    execute!(io::stderr(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
+
+    let result = run_app(&mut terminal, inspector);
+
+    disable_raw_mode()?;
+    execute!(io::stderr(), LeaveAlternateScreen)?;
+    result
+}
+
+fn run_app(
+    terminal: &mut Terminal<CrosstermBackend<Stderr>>,
+    inspector: Arc<Mutex<Inspector>>,
+) -> io::Result<()> {
+    let mut app = App {
+        inspector,
+        input: None,
+        error: None,
+        quit: false,
+    };
+
+    while !app.quit {
+        terminal.draw(|frame| draw(frame, &app))?;
+
+        if event::poll(REFRESH_INTERVAL)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    handle_key(&mut app, key);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn handle_key(app: &mut App, key: KeyEvent) {
+    if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+        app.quit = true;
+        return;
+    }
+    app.error = None;
+
+    if let Some(input) = app.input.as_mut() {
+        match key.code {
+            KeyCode::Char(c) => input.push(c),
+            KeyCode::Backspace => {
+                input.pop();
+            }
+            KeyCode::Esc => app.input = None,
+            KeyCode::Enter => {
+                match parse_filter(input) {
+                    Ok(filter) => app.inspector.lock().unwrap().set_filter(filter),
+                    Err(e) => app.error = Some(e),
+                }
+                app.input = None;
+            }
+            _ => {}
+        }
+        return;
+    }
+
+    let mut inspector = app.inspector.lock().unwrap();
+    match key.code {
+        KeyCode::Char('q') | KeyCode::Esc => app.quit = true,
+        KeyCode::Char('p') | KeyCode::Char(' ') => inspector.toggle_pause(),
+        KeyCode::Up | KeyCode::Char('k') => inspector.select_previous(),
+        KeyCode::Down | KeyCode::Char('j') => inspector.select_next(),
+        KeyCode::End | KeyCode::Char('f') => inspector.follow(),
+        KeyCode::Char('c') => inspector.clear(),
+        KeyCode::Char('/') => {
+            let mut ids: Vec<u16> = inspector
+                .filter()
+                .map(|ids| ids.iter().copied().collect())
+                .unwrap_or_default();
+            ids.sort();
+            let ids: Vec<String> = ids.iter().map(|id| format!("{:04X}", id)).collect();
+            app.input = Some(ids.join(" "));
+        }
+        _ => {}
+    }
+}
+
+fn draw(frame: &mut Frame, app: &App) {
+    let inspector = app.inspector.lock().unwrap();
+    let [status_area, body_area, footer_area] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [list_area, details_area] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .areas(body_area);
+
+    let visible = inspector.visible();
+    let mut status = format!("{} packets", visible.len());
+    if let Some(ids) = inspector.filter() {
+        let mut ids: Vec<String> = ids.iter().map(|id| format!("0x{:04X}", id)).collect();
+        ids.sort();
+        status.push_str(&format!("  filter: {}", ids.join(" ")));
+    }
+    if inspector.is_paused() {
+        status.push_str(&format!("  PAUSED ({} pending)", inspector.pending()));
+    }
+    if inspector.dropped > 0 {
+        status.push_str(&format!("  {} dropped", inspector.dropped));
+    }
+    frame.render_widget(
+        Paragraph::new(status).style(Style::default().add_modifier(Modifier::BOLD)),
+        status_area,
+    );
+
+    let rows = visible.iter().map(|packet| packet_row(packet));
+    let widths = [
+        Constraint::Length(12),
+        Constraint::Length(5),
+        Constraint::Length(2),
+        Constraint::Length(6),
+        Constraint::Min(10),
+        Constraint::Length(6),
+    ];
+    let table = Table::new(rows, widths)
+        .header(
+            Row::new(["time", "stage", "", "id", "name", "len"])
+                .style(Style::default().add_modifier(Modifier::UNDERLINED)),
+        )
+        .block(Block::default().borders(Borders::ALL).title("packets"))
+        .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+    let mut table_state = TableState::default().with_selected(inspector.selected());
+    frame.render_stateful_widget(table, list_area, &mut table_state);
+
+    let details = match inspector.selected_packet() {
+        Some(packet) => packet_details(packet),
+        None => vec![Line::from("no packet")],
+    };
+    frame.render_widget(
+        Paragraph::new(details).block(Block::default().borders(Borders::ALL).title("details")),
+        details_area,
+    );
+
+    let footer = match (&app.input, &app.error) {
+        (Some(input), _) => format!("filter (hex ids, enter to apply): {}_", input),
+        (None, Some(error)) => error.clone(),
+        (None, None) => HELP.to_string(),
+    };
+    frame.render_widget(Paragraph::new(footer), footer_area);
+}
+
+fn packet_row(packet: &InspectedPacket) -> Row<'static> {
+    let arrow = match packet.packet.direction {
+        Direction::Sent => "->",
+        Direction::Received => "<-",
+    };
+    let style = match &packet.outcome {
+        Some(PacketOutcome::Decoded) | None => Style::default(),
+        Some(_) => Style::default().fg(Color::Red),
+    };
+
+    Row::new([
+        format_time(packet.packet.timestamp),
+        packet.packet.stage.name().to_string(),
+        arrow.to_string(),
+        format!("{:04X}", packet.packet.packet_id),
+        packet.name.clone().unwrap_or_else(|| "?".to_string()),
+        packet.packet.data.len().to_string(),
+    ])
+    .style(style)
+}
+
+fn packet_details(packet: &InspectedPacket) -> Vec<Line<'static>> {
+    let mut lines = vec![Line::from(format!(
+        "0x{:04X} {}, {} bytes",
+        packet.packet.packet_id,
+        packet.name.as_deref().unwrap_or("unknown"),
+        packet.packet.data.len()
+    ))];
+
+    match &packet.outcome {
+        Some(PacketOutcome::Decoded) => {}
+        Some(outcome) => lines
+            .push(Line::from(format!("! {:?}", outcome)).style(Style::default().fg(Color::Red))),
+        None => lines.push(Line::from("not decoded, sent by the client")),
+    }
+
+    if !packet.fields.is_empty() {
+        lines.push(Line::from(""));
+        lines.push(Line::from("fields (offset in body):"));
+        for field in &packet.fields {
+            lines.push(Line::from(format!(
+                "  +{:<4} {}",
+                field.offset, field.value
+            )));
+        }
+    }
+
+    lines.push(Line::from(""));
+    lines.push(Line::from("hex:"));
+    for line in hex_lines(&packet.packet.data) {
+        lines.push(Line::from(format!("  {}", line)));
+    }
+
+    lines
+}
@@ -0,0 +1,355 @@
+//! Gravity's DES variant used to obfuscate GRF entries.
+//!
+//! It is not real DES: there is no key schedule and only a single round is
+//! applied, which also makes block decryption its own inverse. Ported from
+//! the grfio implementation used by the emulators.
+
+/// Bitmask for accessing individual bits of a byte.
+const MASK: [u8; 8] = [0x80, 0x40, 0x20, 0x10, 0x08, 0x04, 0x02, 0x01];
+
+const IP_TABLE: [u8; 64] = [
+    58, 50, 42, 34, 26, 18, 10, 2, //
+    60, 52, 44, 36, 28, 20, 12, 4, //
+    62, 54, 46, 38, 30, 22, 14, 6, //
+    64, 56, 48, 40, 32, 24, 16, 8, //
+    57, 49, 41, 33, 25, 17, 9, 1, //
+    59, 51, 43, 35, 27, 19, 11, 3, //
+    61, 53, 45, 37, 29, 21, 13, 5, //
+    63, 55, 47, 39, 31, 23, 15, 7, //
+];
+
+const FP_TABLE: [u8; 64] = [
+    40, 8, 48, 16, 56, 24, 64, 32, //
+    39, 7, 47, 15, 55, 23, 63, 31, //
+    38, 6, 46, 14, 54, 22, 62, 30, //
+    37, 5, 45, 13, 53, 21, 61, 29, //
+    36, 4, 44, 12, 52, 20, 60, 28, //
+    35, 3, 43, 11, 51, 19, 59, 27, //
+    34, 2, 42, 10, 50, 18, 58, 26, //
+    33, 1, 41, 9, 49, 17, 57, 25, //
+];
+
+const EXPANSION_TABLE: [u8; 48] = [
+    32, 1, 2, 3, 4, 5, //
+    4, 5, 6, 7, 8, 9, //
+    8, 9, 10, 11, 12, 13, //
+    12, 13, 14, 15, 16, 17, //
+    16, 17, 18, 19, 20, 21, //
+    20, 21, 22, 23, 24, 25, //
+    24, 25, 26, 27, 28, 29, //
+    28, 29, 30, 31, 32, 1, //
+];
+
+const TP_TABLE: [u8; 32] = [
+    16, 7, 20, 21, //
+    29, 12, 28, 17, //
+    1, 15, 23, 26, //
+    5, 18, 31, 10, //
+    2, 8, 24, 14, //
+    32, 27, 3, 9, //
+    19, 13, 30, 6, //
+    22, 11, 4, 25, //
+];
+
+/// Standard DES S-boxes, 4 rows of 16 columns each.
+const S_BOXES: [[u8; 64]; 8] = [
+    [
+        14, 4, 13, 1, 2, 15, 11, 8, 3, 10, 6, 12, 5, 9, 0, 7, //
+        0, 15, 7, 4, 14, 2, 13, 1, 10, 6, 12, 11, 9, 5, 3, 8, //
+        4, 1, 14, 8, 13, 6, 2, 11, 15, 12, 9, 7, 3, 10, 5, 0, //
+        15, 12, 8, 2, 4, 9, 1, 7, 5, 11, 3, 14, 10, 0, 6, 13, //
+    ],
+    [
+        15, 1, 8, 14, 6, 11, 3, 4, 9, 7, 2, 13, 12, 0, 5, 10, //
+        3, 13, 4, 7, 15, 2, 8, 14, 12, 0, 1, 10, 6, 9, 11, 5, //
+        0, 14, 7, 11, 10, 4, 13, 1, 5, 8, 12, 6, 9, 3, 2, 15, //
+        13, 8, 10, 1, 3, 15, 4, 2, 11, 6, 7, 12, 0, 5, 14, 9, //
+    ],
+    [
+        10, 0, 9, 14, 6, 3, 15, 5, 1, 13, 12, 7, 11, 4, 2, 8, //
+        13, 7, 0, 9, 3, 4, 6, 10, 2, 8, 5, 14, 12, 11, 15, 1, //
+        13, 6, 4, 9, 8, 15, 3, 0, 11, 1, 2, 12, 5, 10, 14, 7, //
+        1, 10, 13, 0, 6, 9, 8, 7, 4, 15, 14, 3, 11, 5, 2, 12, //
+    ],
+    [
+        7, 13, 14, 3, 0, 6, 9, 10, 1, 2, 8, 5, 11, 12, 4, 15, //
+        13, 8, 11, 5, 6, 15, 0, 3, 4, 7, 2, 12, 1, 10, 14, 9, //
+        10, 6, 9, 0, 12, 11, 7, 13, 15, 1, 3, 14, 5, 2, 8, 4, //
+        3, 15, 0, 6, 10, 1, 13, 8, 9, 4, 5, 11, 12, 7, 2, 14, //
+    ],
+    [
+        2, 12, 4, 1, 7, 10, 11, 6, 8, 5, 3, 15, 13, 0, 14, 9, //
+        14, 11, 2, 12, 4, 7, 13, 1, 5, 0, 15, 10, 3, 9, 8, 6, //
+        4, 2, 1, 11, 10, 13, 7, 8, 15, 9, 12, 5, 6, 3, 0, 14, //
+        11, 8, 12, 7, 1, 14, 2, 13, 6, 15, 0, 9, 10, 4, 5, 3, //
+    ],
+    [
+        12, 1, 10, 15, 9, 2, 6, 8, 0, 13, 3, 4, 14, 7, 5, 11, //
+        10, 15, 4, 2, 7, 12, 9, 5, 6, 1, 13, 14, 0, 11, 3, 8, //
+        9, 14, 15, 5, 2, 8, 12, 3, 7, 0, 4, 10, 1, 13, 11, 6, //
+        4, 3, 2, 12, 9, 5, 15, 10, 11, 14, 1, 7, 6, 0, 8, 13, //
+    ],
+    [
+        4, 11, 2, 14, 15, 0, 8, 13, 3, 12, 9, 7, 5, 10, 6, 1, //
+        13, 0, 11, 7, 4, 9, 1, 10, 14, 3, 5, 12, 2, 15, 8, 6, //
+        1, 4, 11, 13, 12, 3, 7, 14, 10, 15, 6, 8, 0, 5, 9, 2, //
+        6, 11, 13, 8, 1, 4, 10, 7, 9, 5, 0, 15, 14, 2, 3, 12, //
+    ],
+    [
+        13, 2, 8, 4, 6, 15, 11, 1, 10, 9, 3, 14, 5, 0, 12, 7, //
+        1, 15, 13, 8, 10, 3, 7, 4, 12, 5, 6, 11, 0, 14, 9, 2, //
+        7, 11, 4, 1, 9, 12, 14, 2, 0, 6, 10, 13, 15, 3, 5, 8, //
+        2, 1, 14, 7, 4, 10, 8, 13, 15, 12, 9, 0, 3, 5, 6, 11, //
+    ],
+];
+
+/// Number of leading blocks that are always DES encrypted.
+const HEADER_BLOCKS: usize = 20;
+
+fn permute(block: &[u8; 8], table: &[u8; 64]) -> [u8; 8] {
+    let mut out = [0u8; 8];
+    for (i, &bit) in table.iter().enumerate() {
+        let j = (bit - 1) as usize;
+        if block[j >> 3] & MASK[j & 7] != 0 {
+            out[i >> 3] |= MASK[i & 7];
+        }
+    }
+    out
+}
+
+/// Expands the right half (32 bits) into eight 6-bit values.
+fn expand(block: &[u8; 8]) -> [u8; 8] {
+    let mut out = [0u8; 8];
+    for (i, &bit) in EXPANSION_TABLE.iter().enumerate() {
+        let j = (bit - 1) as usize;
+        if block[4 + (j >> 3)] & MASK[j & 7] != 0 {
+            out[i / 6] |= MASK[i % 6 + 2];
+        }
+    }
+    out
+}
+
+fn substitute(block: &[u8; 8]) -> [u8; 4] {
+    let mut out = [0u8; 4];
+    for (i, value) in block.iter().enumerate() {
+        let row = ((value >> 4) & 2) | (value & 1);
+        let column = (value >> 1) & 0xF;
+        let nibble = S_BOXES[i][(row * 16 + column) as usize];
+        out[i / 2] |= if i % 2 == 0 { nibble << 4 } else { nibble };
+    }
+    out
+}
+
+fn transpose(block: &[u8; 4]) -> [u8; 4] {
+    let mut out = [0u8; 4];
+    for (i, &bit) in TP_TABLE.iter().enumerate() {
+        let j = (bit - 1) as usize;
+        if block[j >> 3] & MASK[j & 7] != 0 {
+            out[i >> 3] |= MASK[i & 7];
+        }
+    }
+    out
+}
+
+/// Decrypts a single 8 bytes block in place.
+pub fn decrypt_block(block: &mut [u8; 8]) {
+    let mut tmp = permute(block, &IP_TABLE);
+
+    let round = transpose(&substitute(&expand(&tmp)));
+    for i in 0..4 {
+        tmp[i] ^= round[i];
+    }
+
+    *block = permute(&tmp, &FP_TABLE);
+}
+
+fn substitution(value: u8) -> u8 {
+    match value {
+        0x00 => 0x2B,
+        0x2B => 0x00,
+        0x6C => 0x80,
+        0x80 => 0x6C,
+        0x01 => 0x68,
+        0x68 => 0x01,
+        0x48 => 0x77,
+        0x77 => 0x48,
+        0x60 => 0xFF,
+        0xFF => 0x60,
+        0xB9 => 0xC0,
+        0xC0 => 0xB9,
+        0xFE => 0xEB,
+        0xEB => 0xFE,
+        _ => value,
+    }
+}
+
+fn shuffle_dec(block: &mut [u8; 8]) {
+    let src = *block;
+    *block = [
+        src[3],
+        src[4],
+        src[6],
+        src[0],
+        src[1],
+        src[2],
+        src[5],
+        substitution(src[7]),
+    ];
+}
+
+fn for_each_block(data: &mut [u8], mut f: impl FnMut(usize, &mut [u8; 8])) {
+    for (i, chunk) in data.chunks_exact_mut(8).enumerate() {
+        let block: &mut [u8; 8] = chunk.try_into().expect("chunk is 8 bytes");
+        f(i, block);
+    }
+}
+
+/// Gap between two DES encrypted blocks of a mixed entry, based on the
+/// number of digits of the entry compressed size.
+fn mixed_cycle(compressed_size: u32) -> usize {
+    let digits = compressed_size.to_string().len();
+    match digits {
+        0..=2 => 1,
+        3..=4 => digits + 1,
+        5..=6 => digits + 9,
+        _ => digits + 15,
+    }
+}
+
+/// Decodes an entry stored with the "mixed" flag: the first 20 blocks are
+/// DES encrypted, then one block every `cycle` blocks, and every 7th block
+/// in between is shuffled.
+pub fn decode_full(data: &mut [u8], compressed_size: u32) {
+    let cycle = mixed_cycle(compressed_size);
+    let mut shuffle_count: usize = 0;
+
+    for_each_block(data, |i, block| {
+        if i < HEADER_BLOCKS || i % cycle == 0 {
+            decrypt_block(block);
+            return;
+        }
+
+        if shuffle_count != 0 && shuffle_count.is_multiple_of(7) {
+            shuffle_dec(block);
+        }
+        shuffle_count += 1;
+    });
+}
+
+/// Decodes an entry stored with the "header only" flag, only the first
+/// 20 blocks are DES encrypted.
+pub fn decode_header(data: &mut [u8]) {
+    for_each_block(data, |i, block| {
+        if i < HEADER_BLOCKS {
+            decrypt_block(block);
+        }
+    });
+}
+
+/// Decodes a file name of a 0x1xx archive table: nibbles are swapped, then
+/// every block is DES encrypted. A trailing partial block is left as is.
+pub fn decode_name(data: &mut [u8]) {
+    for_each_block(data, |_, block| {
+        for byte in block.iter_mut() {
+            *byte = byte.rotate_left(4);
+        }
+        decrypt_block(block);
+    });
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn shuffle_enc(block: &mut [u8; 8]) {
+        let src = *block;
+        *block = [
+            src[3],
+            src[4],
+            src[5],
+            src[0],
+            src[1],
+            src[6],
+            src[2],
+            substitution(src[7]),
+        ];
+    }
+
+    /// Inverse of `decode_full`, used to build encrypted fixtures.
+    pub(crate) fn encode_full(data: &mut [u8], compressed_size: u32) {
+        let cycle = mixed_cycle(compressed_size);
+        let mut shuffle_count: usize = 0;
+
+        for_each_block(data, |i, block| {
+            if i < HEADER_BLOCKS || i % cycle == 0 {
+                decrypt_block(block);
+                return;
+            }
+
+            if shuffle_count != 0 && shuffle_count.is_multiple_of(7) {
+                shuffle_enc(block);
+            }
+            shuffle_count += 1;
+        });
+    }
+
+    /// Inverse of `decode_header`, used to build encrypted fixtures.
+    pub(crate) fn encode_header(data: &mut [u8]) {
+        decode_header(data);
+    }
+
+    /// Inverse of `decode_name`, used to build 0x1xx fixtures.
+    pub(crate) fn encode_name(data: &mut [u8]) {
+        for_each_block(data, |_, block| {
+            decrypt_block(block);
+            for byte in block.iter_mut() {
+                *byte = byte.rotate_left(4);
+            }
+        });
+    }
+
+    #[test]
+    fn block_decryption_is_an_involution() {
+        let original = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0];
+        let mut block = original;
+
+        decrypt_block(&mut block);
+        assert_ne!(block, original);
+        decrypt_block(&mut block);
+        assert_eq!(block, original);
+    }
+
+    #[test]
+    fn shuffle_round_trip() {
+        for last in [0x00, 0x2B, 0x6C, 0x48, 0x10] {
+            let original = [1, 2, 3, 4, 5, 6, 7, last];
+            let mut block = original;
+
+            shuffle_enc(&mut block);
+            shuffle_dec(&mut block);
+            assert_eq!(block, original);
+        }
+    }
+
+    #[test]
+    fn mixed_cycle_matches_digit_count() {
+        assert_eq!(mixed_cycle(9), 1);
+        assert_eq!(mixed_cycle(99), 1);
+        assert_eq!(mixed_cycle(100), 4);
+        assert_eq!(mixed_cycle(9999), 5);
+        assert_eq!(mixed_cycle(10000), 14);
+        assert_eq!(mixed_cycle(999999), 15);
+        assert_eq!(mixed_cycle(1000000), 22);
+    }
+
+    #[test]
+    fn full_round_trip_touches_shuffled_blocks() {
+        let original: Vec<u8> = (0..1024u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut data = original.clone();
+
+        encode_full(&mut data, 1000);
+        assert_ne!(data, original);
+        decode_full(&mut data, 1000);
+        assert_eq!(data, original);
+    }
+}
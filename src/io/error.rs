@@ -0,0 +1,46 @@
+use std::fmt;
+
+/// Error returned by the client resource parsers (`.grf`, `.gat`, ...).
+#[derive(Debug)]
+pub enum ParseError {
+    Io(std::io::Error),
+    /// The file does not start with the expected signature.
+    InvalidMagic,
+    /// The file uses a format version we do not know how to read.
+    UnsupportedVersion(u32),
+    /// The data ended before the expected structure was fully read.
+    UnexpectedEof,
+    /// Zlib stream of an archive entry could not be inflated.
+    Decompress(std::io::Error),
+    /// The requested archive entry does not exist.
+    EntryNotFound(String),
+    /// A count or dimension read from the file is too large to be real.
+    InvalidSize,
+    /// A field holds a value outside of its known range.
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "io error: {}", e),
+            ParseError::InvalidMagic => write!(f, "invalid file signature"),
+            ParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported version: {:#x}", version)
+            }
+            ParseError::UnexpectedEof => write!(f, "unexpected end of data"),
+            ParseError::Decompress(e) => write!(f, "failed to decompress: {}", e),
+            ParseError::EntryNotFound(name) => write!(f, "entry not found: {}", name),
+            ParseError::InvalidSize => write!(f, "invalid size"),
+            ParseError::InvalidField(field) => write!(f, "invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<std::io::Error> for ParseError {
+    fn from(e: std::io::Error) -> Self {
+        ParseError::Io(e)
+    }
+}
@@ -1,10 +1,53 @@
-use std::{fs::File, io::{Cursor, Read, Seek, SeekFrom}};
+use std::{fs::File, io::Read};
+
+use super::{error::ParseError, reader::BinaryReader};
+
+pub static GAT_MAGIC: &[u8; 4] = b"GRAT";
+
+/// Cell types as interpreted by the server (see rAthena `map_gat2cell`).
+#[derive(num_enum::TryFromPrimitive, Clone, Copy, PartialEq, Debug)]
+#[repr(u8)]
+pub enum GatCellType {
+    Walkable = 0,
+    NonWalkable = 1,
+    Unknown2 = 2,  // walkable
+    Water = 3,     // walkable water
+    Unknown4 = 4,  // walkable
+    Snipeable = 5, // gap, non-walkable but can be shot through
+    Unknown6 = 6,  // walkable
+}
+
+impl GatCellType {
+    pub fn is_walkable(self) -> bool {
+        !matches!(self, GatCellType::NonWalkable | GatCellType::Snipeable)
+    }
+
+    pub fn is_shootable(self) -> bool {
+        self != GatCellType::NonWalkable
+    }
+}
+
+/// Cell queries shared by every walkability source (gat files, server map cache).
+pub trait CellMap {
+    fn width(&self) -> u32;
+    fn height(&self) -> u32;
+    fn cell_type(&self, x: u16, y: u16) -> Option<GatCellType>;
+
+    fn is_walkable(&self, x: u16, y: u16) -> bool {
+        self.cell_type(x, y).is_some_and(GatCellType::is_walkable)
+    }
+
+    fn is_shootable(&self, x: u16, y: u16) -> bool {
+        self.cell_type(x, y).is_some_and(GatCellType::is_shootable)
+    }
+}
 
 pub struct GatData {
     magic: [u8; 6],
     width: u32,
     height: u32,
     blocks: Vec<GatBlock>,
+    water_level: Option<f32>,
 }
 
 pub struct GatBlock {
@@ -23,43 +66,104 @@ impl GatData {
             width: 0,
             height: 0,
             blocks: Vec::new(),
+            water_level: None,
         }
     }
 
-    pub fn parse(file_name: &str) -> GatData {
-        let mut gat_data = GatData::new();
-        let mut file = File::open(file_name).expect("file not found");
+    pub fn block(&self, x: u16, y: u16) -> Option<&GatBlock> {
+        if x as u32 >= self.width || y as u32 >= self.height {
+            return None;
+        }
+        self.blocks.get(x as usize + y as usize * self.width as usize)
+    }
+
+    /// Approximate heap size, used to cap the map cache.
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<GatData>() + self.blocks.capacity() * std::mem::size_of::<GatBlock>()
+    }
+
+    pub fn water_level(&self) -> Option<f32> {
+        self.water_level
+    }
+
+    /// Marks walkable cells below the rsw water level as water cells, the same
+    /// way the server map cache is generated: only the upper left corner counts.
+    pub fn apply_water_level(&mut self, water_level: f32) {
+        for block in self.blocks.iter_mut() {
+            let under_water = block.upper_left_height > water_level;
+            if block.r#type == GatCellType::Walkable as u8 && under_water {
+                block.r#type = GatCellType::Water as u8;
+            }
+        }
+        self.water_level = Some(water_level);
+    }
+
+    /// How deep the cell is under water, `None` when the map has no water or
+    /// the cell is above the water level.
+    pub fn water_depth(&self, x: u16, y: u16) -> Option<f32> {
+        let water_level = self.water_level?;
+        let depth = self.block(x, y)?.upper_left_height - water_level;
+        if depth > 0.0 {
+            Some(depth)
+        } else {
+            None
+        }
+    }
+
+    pub fn parse(file_name: &str) -> Result<GatData, ParseError> {
+        let mut file = File::open(file_name)?;
         let mut buffer = Vec::new();
-        let mut tmp_buffer = [0u8; 32];
 
         // read all file buffer until end
-        file.read_to_end(&mut buffer).expect("failed to read file");
-
-        let mut cursor = Cursor::new(buffer);
-
-        // skip gat header
-        cursor.seek(SeekFrom::Start(6)).expect("failed to seek");
-
-        cursor.read(&mut tmp_buffer[0..4]).expect("failed to read width");
-        let width = u32::from_le_bytes(tmp_buffer[0..4].try_into().expect("failed to convert width"));
-        cursor.read(&mut tmp_buffer[0..4]).expect("failed to read width");
-        let height = u32::from_le_bytes(tmp_buffer[0..4].try_into().expect("failed to convert height"));
-        println!("width: {}, height: {}", width, height);
-
-        for _ in 0..width {
-            for _ in 0..height {
-                cursor.read(&mut tmp_buffer[0..4]).expect("failed to read upper_left_height");
-                let upper_left_height = f32::from_le_bytes(tmp_buffer[0..4].try_into().expect("failed to convert upper_left_height"));
-                cursor.read(&mut tmp_buffer[0..4]).expect("failed to read upper_right_height");
-                let upper_right_height = f32::from_le_bytes(tmp_buffer[0..4].try_into().expect("failed to convert upper_right_height"));
-                cursor.read(&mut tmp_buffer[0..4]).expect("failed to read lower_left_height");
-                let lower_left_height = f32::from_le_bytes(tmp_buffer[0..4].try_into().expect("failed to convert lower_left_height"));
-                cursor.read(&mut tmp_buffer[0..4]).expect("failed to read lower_right_height");
-                let lower_right_height = f32::from_le_bytes(tmp_buffer[0..4].try_into().expect("failed to convert lower_right_height"));
-                cursor.read(&mut tmp_buffer[0..1]).expect("failed to read type");
-                let r#type = u8::from_le_bytes(tmp_buffer[0..1].try_into().expect("failed to convert type"));
-                cursor.read(&mut tmp_buffer[0..3]).expect("failed to read unknown");
-                let unknown = tmp_buffer[0..3].try_into().expect("failed to convert unknown");
+        file.read_to_end(&mut buffer)?;
+
+        GatData::from_bytes(&buffer)
+    }
+
+    /// Flat map built from cell types only, e.g. from the server map cache.
+    /// Every cell height is 0.
+    pub fn from_cells(width: u32, height: u32, cells: &[u8]) -> GatData {
+        let mut gat_data = GatData::new();
+        gat_data.magic.copy_from_slice(b"GRAT\x01\x02");
+        gat_data.width = width;
+        gat_data.height = height;
+        gat_data.blocks = cells
+            .iter()
+            .map(|&r#type| GatBlock {
+                upper_left_height: 0.0,
+                upper_right_height: 0.0,
+                lower_left_height: 0.0,
+                lower_right_height: 0.0,
+                r#type,
+                unknown: [0; 3],
+            })
+            .collect();
+        gat_data
+    }
+
+    /// Parses a gat file already loaded in memory, e.g. read from a GRF archive.
+    pub fn from_bytes(buffer: &[u8]) -> Result<GatData, ParseError> {
+        let mut gat_data = GatData::new();
+        let mut data = BinaryReader::new(buffer);
+
+        // gat header, "GRAT" + major/minor version
+        gat_data.magic.copy_from_slice(data.read_bytes(6)?);
+        if &gat_data.magic[0..4] != GAT_MAGIC {
+            return Err(ParseError::InvalidMagic);
+        }
+
+        gat_data.width = data.read_u32()?;
+        gat_data.height = data.read_u32()?;
+        tracing::debug!(width = gat_data.width, height = gat_data.height, "gat size");
+
+        for _ in 0..gat_data.width {
+            for _ in 0..gat_data.height {
+                let upper_left_height = data.read_f32()?;
+                let upper_right_height = data.read_f32()?;
+                let lower_left_height = data.read_f32()?;
+                let lower_right_height = data.read_f32()?;
+                let r#type = data.read_u8()?;
+                let unknown = data.read_bytes(3)?.try_into().expect("failed to convert unknown");
 
                 gat_data.blocks.push(GatBlock {
                     upper_left_height,
@@ -72,6 +176,87 @@ impl GatData {
             }
         }
 
-        gat_data
+        Ok(gat_data)
+    }
+}
+
+impl CellMap for GatData {
+    fn width(&self) -> u32 {
+        self.width
+    }
+
+    fn height(&self) -> u32 {
+        self.height
+    }
+
+    fn cell_type(&self, x: u16, y: u16) -> Option<GatCellType> {
+        self.block(x, y).and_then(|block| block.cell_type())
+    }
+}
+
+impl GatBlock {
+    /// Average of the four corners. Heights grow downwards, like in the client.
+    pub fn height(&self) -> f32 {
+        (self.upper_left_height
+            + self.upper_right_height
+            + self.lower_left_height
+            + self.lower_right_height)
+            / 4.0
+    }
+
+    pub fn cell_type(&self) -> Option<GatCellType> {
+        GatCellType::try_from(self.r#type).ok()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Builds a gat file from rows of `(height, type)` cells, first row is y = 0.
+    pub(crate) fn build_gat(cells: &[&[(f32, u8)]]) -> Vec<u8> {
+        let mut gat = b"GRAT\x01\x02".to_vec();
+        gat.extend_from_slice(&(cells[0].len() as u32).to_le_bytes());
+        gat.extend_from_slice(&(cells.len() as u32).to_le_bytes());
+        for row in cells {
+            for (height, cell_type) in row.iter() {
+                for _ in 0..4 {
+                    gat.extend_from_slice(&height.to_le_bytes());
+                }
+                gat.extend_from_slice(&[*cell_type, 0, 0, 0]);
+            }
+        }
+        gat
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn water_level_turns_low_walkable_cells_into_water() {
+        let data = build_gat(&[&[(0.0, 0), (5.0, 0)], &[(5.0, 1), (-5.0, 0)]]);
+        let mut gat = GatData::from_bytes(&data).unwrap();
+
+        assert_eq!(gat.cell_type(1, 0), Some(GatCellType::Walkable));
+        gat.apply_water_level(2.0);
+
+        assert_eq!(gat.cell_type(0, 0), Some(GatCellType::Walkable));
+        assert_eq!(gat.cell_type(1, 0), Some(GatCellType::Water));
+        assert_eq!(gat.cell_type(0, 1), Some(GatCellType::NonWalkable));
+        assert_eq!(gat.water_depth(1, 0), Some(3.0));
+        assert_eq!(gat.water_depth(1, 1), None);
+        assert!(gat.block(2, 0).is_none());
+    }
+
+    #[test]
+    fn water_level_uses_the_upper_left_corner_only() {
+        let mut data = build_gat(&[&[(-5.0, 0), (9.0, 0)]]);
+        // first corners on the other side of the water level than the averages
+        data[14..18].copy_from_slice(&3.0f32.to_le_bytes());
+        data[34..38].copy_from_slice(&0.0f32.to_le_bytes());
+        let mut gat = GatData::from_bytes(&data).unwrap();
+        gat.apply_water_level(2.0);
+
+        assert_eq!(gat.cell_type(0, 0), Some(GatCellType::Water));
+        assert_eq!(gat.water_depth(0, 0), Some(1.0));
+        assert_eq!(gat.cell_type(1, 0), Some(GatCellType::Walkable));
+        assert_eq!(gat.water_depth(1, 0), None);
+    }
+}
@@ -0,0 +1,407 @@
+use std::{fs::File, io::Read};
+
+use super::{
+    error::ParseError,
+    gat::{CellMap, GatData},
+    reader::BinaryReader,
+};
+
+pub static GND_MAGIC: &[u8; 4] = b"GRGN";
+
+/// Highest version we know how to read (1.9).
+pub static GND_MAX_VERSION: u16 = 0x0109;
+
+/// A gnd cube covers 2x2 gat cells.
+pub static GND_GAT_RATIO: u32 = 2;
+
+pub struct GndData {
+    /// `(major << 8) | minor`, e.g. 0x0107 for 1.7
+    pub version: u16,
+    pub width: u32,
+    pub height: u32,
+    pub zoom: f32,
+    pub textures: Vec<String>,
+    pub lightmap_per_cell_x: i32,
+    pub lightmap_per_cell_y: i32,
+    pub lightmap_size_cell: i32,
+    pub lightmaps: Vec<GndLightmap>,
+    pub surfaces: Vec<GndSurface>,
+    pub cubes: Vec<GndCube>,
+    /// Since 1.8 the water settings are stored here instead of the rsw file,
+    /// 1.9 splits the map into several water zones.
+    pub water: Vec<GndWater>,
+    pub water_split_width: u32,
+    pub water_split_height: u32,
+}
+
+pub struct GndLightmap {
+    pub brightness: [u8; 64],
+    pub color: [u8; 192],
+}
+
+pub struct GndSurface {
+    pub u: [f32; 4],
+    pub v: [f32; 4],
+    pub texture_id: i16,
+    pub lightmap_id: u16,
+    /// BGRA
+    pub color: [u8; 4],
+}
+
+pub struct GndCube {
+    /// bottom left, bottom right, top left, top right
+    pub heights: [f32; 4],
+    /// Surface index or -1 when the face is not drawn.
+    pub tile_up: i32,
+    pub tile_front: i32,
+    pub tile_right: i32,
+}
+
+pub struct GndWater {
+    pub level: f32,
+    pub r#type: i32,
+    pub wave_height: f32,
+    pub wave_speed: f32,
+    pub wave_pitch: f32,
+    pub anim_speed: i32,
+}
+
+/// A gat cell whose height doesn't match the ground mesh under it.
+pub struct HeightMismatch {
+    pub x: u16,
+    pub y: u16,
+    pub gat_height: f32,
+    pub gnd_height: f32,
+}
+
+fn read_water(data: &mut BinaryReader) -> Result<GndWater, ParseError> {
+    Ok(GndWater {
+        level: data.read_f32()?,
+        r#type: data.read_i32()?,
+        wave_height: data.read_f32()?,
+        wave_speed: data.read_f32()?,
+        wave_pitch: data.read_f32()?,
+        anim_speed: data.read_i32()?,
+    })
+}
+
+impl GndData {
+    pub fn parse(file_name: &str) -> Result<GndData, ParseError> {
+        let mut file = File::open(file_name)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        GndData::from_bytes(&buffer)
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Result<GndData, ParseError> {
+        let mut data = BinaryReader::new(buffer);
+
+        if data.read_bytes(4)? != GND_MAGIC {
+            return Err(ParseError::InvalidMagic);
+        }
+        let major = data.read_u8()?;
+        let minor = data.read_u8()?;
+        let version = (major as u16) << 8 | minor as u16;
+        if version > GND_MAX_VERSION {
+            return Err(ParseError::UnsupportedVersion(version as u32));
+        }
+
+        let width = data.read_u32()?;
+        let height = data.read_u32()?;
+        let zoom = data.read_f32()?;
+
+        let texture_count = data.read_u32()?;
+        let texture_name_len = data.read_u32()? as usize;
+        let mut textures = Vec::new();
+        for _ in 0..texture_count {
+            textures.push(data.read_string(Some(texture_name_len))?);
+        }
+
+        let lightmap_count = data.read_u32()?;
+        let lightmap_per_cell_x = data.read_i32()?;
+        let lightmap_per_cell_y = data.read_i32()?;
+        let lightmap_size_cell = data.read_i32()?;
+        let mut lightmaps = Vec::new();
+        for _ in 0..lightmap_count {
+            lightmaps.push(GndLightmap {
+                brightness: data.read_bytes(64)?.try_into().expect("read 64 bytes"),
+                color: data.read_bytes(192)?.try_into().expect("read 192 bytes"),
+            });
+        }
+
+        let surface_count = data.read_u32()?;
+        let mut surfaces = Vec::new();
+        for _ in 0..surface_count {
+            surfaces.push(GndSurface {
+                u: [
+                    data.read_f32()?,
+                    data.read_f32()?,
+                    data.read_f32()?,
+                    data.read_f32()?,
+                ],
+                v: [
+                    data.read_f32()?,
+                    data.read_f32()?,
+                    data.read_f32()?,
+                    data.read_f32()?,
+                ],
+                texture_id: data.read_i16()?,
+                lightmap_id: data.read_u16()?,
+                color: data.read_bytes(4)?.try_into().expect("read 4 bytes"),
+            });
+        }
+
+        let cube_count = width.checked_mul(height).ok_or(ParseError::InvalidSize)?;
+        let mut cubes = Vec::new();
+        for _ in 0..cube_count {
+            let heights = [
+                data.read_f32()?,
+                data.read_f32()?,
+                data.read_f32()?,
+                data.read_f32()?,
+            ];
+
+            // tiles were 16 bits before 1.6
+            let (tile_up, tile_front, tile_right) = if version >= 0x0106 {
+                (data.read_i32()?, data.read_i32()?, data.read_i32()?)
+            } else {
+                (
+                    data.read_u16()? as i16 as i32,
+                    data.read_u16()? as i16 as i32,
+                    data.read_u16()? as i16 as i32,
+                )
+            };
+
+            cubes.push(GndCube {
+                heights,
+                tile_up,
+                tile_front,
+                tile_right,
+            });
+        }
+
+        let mut water = Vec::new();
+        let mut water_split_width = 1;
+        let mut water_split_height = 1;
+        if version >= 0x0108 {
+            water.push(read_water(&mut data)?);
+        }
+        if version >= 0x0109 {
+            water_split_width = data.read_u32()?;
+            water_split_height = data.read_u32()?;
+            let water_count = water_split_width
+                .checked_mul(water_split_height)
+                .ok_or(ParseError::InvalidSize)?;
+            water.clear();
+            for _ in 0..water_count {
+                water.push(read_water(&mut data)?);
+            }
+        }
+
+        Ok(GndData {
+            version,
+            width,
+            height,
+            zoom,
+            textures,
+            lightmap_per_cell_x,
+            lightmap_per_cell_y,
+            lightmap_size_cell,
+            lightmaps,
+            surfaces,
+            cubes,
+            water,
+            water_split_width,
+            water_split_height,
+        })
+    }
+
+    pub fn cube(&self, x: u32, y: u32) -> Option<&GndCube> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        self.cubes.get((x + y * self.width) as usize)
+    }
+
+    /// Ground height at a position given in gat cell units, interpolated
+    /// between the four corners of the cube under it.
+    pub fn height_at(&self, x: f32, y: f32) -> Option<f32> {
+        if x < 0.0 || y < 0.0 {
+            return None;
+        }
+
+        let ratio = GND_GAT_RATIO as f32;
+        let cube = self.cube((x / ratio) as u32, (y / ratio) as u32)?;
+        let fx = (x % ratio) / ratio;
+        let fy = (y % ratio) / ratio;
+
+        let [bottom_left, bottom_right, top_left, top_right] = cube.heights;
+        let bottom = bottom_left + (bottom_right - bottom_left) * fx;
+        let top = top_left + (top_right - top_left) * fx;
+        Some(bottom + (top - bottom) * fy)
+    }
+
+    /// Compares every gat cell average height against the ground mesh at the
+    /// cell center, returning the cells that differ by more than `tolerance`.
+    pub fn cross_check_gat(&self, gat: &GatData, tolerance: f32) -> Vec<HeightMismatch> {
+        let mut mismatches = Vec::new();
+
+        // cell coordinates are u16, bigger maps are only checked up to there
+        let max_cells = u16::MAX as u32;
+        let width = gat.width().min(self.width.saturating_mul(GND_GAT_RATIO)).min(max_cells);
+        let height = gat.height().min(self.height.saturating_mul(GND_GAT_RATIO)).min(max_cells);
+
+        for y in 0..height as u16 {
+            for x in 0..width as u16 {
+                let Some(block) = gat.block(x, y) else {
+                    continue;
+                };
+                let Some(gnd_height) = self.height_at(x as f32 + 0.5, y as f32 + 0.5) else {
+                    continue;
+                };
+
+                let gat_height = block.height();
+                if (gat_height - gnd_height).abs() > tolerance {
+                    mismatches.push(HeightMismatch {
+                        x,
+                        y,
+                        gat_height,
+                        gnd_height,
+                    });
+                }
+            }
+        }
+
+        mismatches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::gat::tests::build_gat;
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    /// 1x1 cube map sloping from 0 (left) to 4 (right).
+    fn build_gnd(minor: u8) -> Vec<u8> {
+        let mut gnd = b"GRGN".to_vec();
+        gnd.extend_from_slice(&[1, minor]);
+        gnd.extend_from_slice(&1u32.to_le_bytes());
+        gnd.extend_from_slice(&1u32.to_le_bytes());
+        gnd.extend(floats(&[10.0]));
+
+        gnd.extend_from_slice(&1u32.to_le_bytes());
+        gnd.extend_from_slice(&80u32.to_le_bytes());
+        let mut texture = b"grass.bmp".to_vec();
+        texture.resize(80, 0);
+        gnd.extend(texture);
+
+        gnd.extend_from_slice(&1u32.to_le_bytes());
+        for value in [8i32, 8, 1] {
+            gnd.extend_from_slice(&value.to_le_bytes());
+        }
+        gnd.extend_from_slice(&[0xFF; 256]);
+
+        gnd.extend_from_slice(&1u32.to_le_bytes());
+        gnd.extend(floats(&[0.0, 1.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0]));
+        gnd.extend_from_slice(&0i16.to_le_bytes());
+        gnd.extend_from_slice(&0u16.to_le_bytes());
+        gnd.extend_from_slice(&[0xFF; 4]);
+
+        gnd.extend(floats(&[0.0, 4.0, 0.0, 4.0]));
+        if minor >= 6 {
+            for tile in [0i32, -1, -1] {
+                gnd.extend_from_slice(&tile.to_le_bytes());
+            }
+        } else {
+            for tile in [0i16, -1, -1] {
+                gnd.extend_from_slice(&tile.to_le_bytes());
+            }
+        }
+
+        if minor >= 8 {
+            gnd.extend(floats(&[1.0]));
+            gnd.extend_from_slice(&0i32.to_le_bytes());
+            gnd.extend(floats(&[1.0, 2.0, 50.0]));
+            gnd.extend_from_slice(&3i32.to_le_bytes());
+        }
+        if minor >= 9 {
+            gnd.extend_from_slice(&1u32.to_le_bytes());
+            gnd.extend_from_slice(&1u32.to_le_bytes());
+            gnd.extend(floats(&[2.5]));
+            gnd.extend_from_slice(&0i32.to_le_bytes());
+            gnd.extend(floats(&[1.0, 2.0, 50.0]));
+            gnd.extend_from_slice(&3i32.to_le_bytes());
+        }
+        gnd
+    }
+
+    #[test]
+    fn parses_cubes_and_water_across_versions() {
+        for minor in [5, 7, 8, 9] {
+            let gnd = GndData::from_bytes(&build_gnd(minor)).unwrap();
+
+            assert_eq!(gnd.textures, vec!["grass.bmp".to_string()]);
+            assert_eq!(gnd.lightmaps.len(), 1);
+            assert_eq!(gnd.surfaces.len(), 1);
+            assert_eq!(gnd.cube(0, 0).unwrap().tile_up, 0);
+            assert_eq!(gnd.cube(0, 0).unwrap().tile_front, -1);
+            assert!(gnd.cube(1, 0).is_none());
+
+            match minor {
+                8 => assert_eq!(gnd.water[0].level, 1.0),
+                9 => assert_eq!(gnd.water[0].level, 2.5),
+                _ => assert!(gnd.water.is_empty()),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_dimensions() {
+        let oversized = 0x10000u32.to_le_bytes();
+
+        let mut gnd = build_gnd(9);
+        gnd[6..10].copy_from_slice(&oversized);
+        gnd[10..14].copy_from_slice(&oversized);
+        assert!(matches!(
+            GndData::from_bytes(&gnd),
+            Err(ParseError::InvalidSize)
+        ));
+
+        // water split sizes, before the last water block
+        let mut gnd = build_gnd(9);
+        let split = gnd.len() - 32;
+        gnd[split..split + 4].copy_from_slice(&oversized);
+        gnd[split + 4..split + 8].copy_from_slice(&oversized);
+        assert!(matches!(
+            GndData::from_bytes(&gnd),
+            Err(ParseError::InvalidSize)
+        ));
+    }
+
+    #[test]
+    fn cross_check_reports_cells_off_the_ground_mesh() {
+        let gnd = GndData::from_bytes(&build_gnd(7)).unwrap();
+        assert_eq!(gnd.height_at(1.0, 1.0), Some(2.0));
+
+        let matching = build_gat(&[&[(1.0, 0), (3.0, 0)], &[(1.0, 0), (3.0, 0)]]);
+        let gat = GatData::from_bytes(&matching).unwrap();
+        assert!(gnd.cross_check_gat(&gat, 0.01).is_empty());
+
+        let off = build_gat(&[&[(1.0, 0), (3.0, 0)], &[(1.0, 0), (9.0, 0)]]);
+        let gat = GatData::from_bytes(&off).unwrap();
+        let mismatches = gnd.cross_check_gat(&gat, 0.01);
+        assert_eq!(mismatches.len(), 1);
+        assert_eq!((mismatches[0].x, mismatches[0].y), (1, 1));
+        assert_eq!(mismatches[0].gnd_height, 3.0);
+
+        // dimensions too large for the cell grid must not overflow
+        let mut gnd = gnd;
+        gnd.height = u32::MAX;
+        assert_eq!(gnd.cross_check_gat(&gat, 0.01).len(), 1);
+    }
+}
@@ -0,0 +1,457 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Seek, SeekFrom},
+};
+
+use encoding_rs::EUC_KR;
+
+use super::{
+    des,
+    error::ParseError,
+    reader::{inflate, BinaryReader},
+};
+
+pub static GRF_MAGIC: &[u8; 16] = b"Master of Magic\0";
+pub static GRF_HEADER_LEN: u32 = 46;
+
+/// Entry flags, as stored in the file table.
+pub static GRF_FLAG_FILE: u8 = 0x01;
+pub static GRF_FLAG_MIXCRYPT: u8 = 0x02;
+pub static GRF_FLAG_DES: u8 = 0x04;
+
+pub struct GrfEntry {
+    /// Decoded from CP949.
+    pub name: String,
+    pub compressed_size: u32,
+    pub compressed_size_aligned: u32,
+    pub uncompressed_size: u32,
+    pub flags: u8,
+    pub offset: u32,
+}
+
+impl GrfEntry {
+    pub fn is_file(&self) -> bool {
+        self.flags & GRF_FLAG_FILE != 0
+    }
+
+    pub fn is_mixcrypt(&self) -> bool {
+        self.flags & GRF_FLAG_MIXCRYPT != 0
+    }
+
+    pub fn is_des(&self) -> bool {
+        self.flags & GRF_FLAG_DES != 0
+    }
+}
+
+/// Read-only view of a GRF archive (versions 0x102, 0x103 and 0x200).
+///
+/// Entries are indexed by their normalized path: lowercase with `\` as
+/// separator, so `data/Prontera.gat` and `data\prontera.gat` are the same.
+pub struct Grf<R: Read + Seek> {
+    reader: R,
+    pub version: u32,
+    entries: HashMap<String, GrfEntry>,
+}
+
+impl Grf<BufReader<File>> {
+    pub fn open(file_name: &str) -> Result<Self, ParseError> {
+        let file = File::open(file_name)?;
+        Grf::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read + Seek> Grf<R> {
+    pub fn new(mut reader: R) -> Result<Self, ParseError> {
+        let mut header = [0u8; GRF_HEADER_LEN as usize];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| ParseError::UnexpectedEof)?;
+
+        let mut data = BinaryReader::new(&header);
+        if data.read_bytes(16)? != GRF_MAGIC {
+            return Err(ParseError::InvalidMagic);
+        }
+        data.skip_bytes(14); // encryption key, unused
+        let table_offset = data.read_u32()?;
+        let seed = data.read_u32()?;
+        let file_count = data.read_u32()?.wrapping_sub(seed).wrapping_sub(7);
+        let version = data.read_u32()?;
+
+        let entries = match version {
+            0x102 | 0x103 => read_table_v1(&mut reader, table_offset, file_count)?,
+            0x200 => read_table_v2(&mut reader, table_offset, file_count)?,
+            _ => return Err(ParseError::UnsupportedVersion(version)),
+        };
+
+        Ok(Grf {
+            reader,
+            version,
+            entries,
+        })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &GrfEntry> {
+        self.entries.values()
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&GrfEntry> {
+        self.entries.get(&normalize_path(name))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.entry(name).is_some()
+    }
+
+    /// Reads, decrypts and inflates an entry.
+    pub fn read(&mut self, name: &str) -> Result<Vec<u8>, ParseError> {
+        let entry = self
+            .entries
+            .get(&normalize_path(name))
+            .ok_or_else(|| ParseError::EntryNotFound(name.to_string()))?;
+
+        let mut buffer = vec![0u8; entry.compressed_size_aligned as usize];
+        self.reader
+            .seek(SeekFrom::Start(entry.offset as u64 + GRF_HEADER_LEN as u64))?;
+        self.reader
+            .read_exact(&mut buffer)
+            .map_err(|_| ParseError::UnexpectedEof)?;
+
+        if entry.is_mixcrypt() {
+            des::decode_full(&mut buffer, entry.compressed_size);
+        } else if entry.is_des() {
+            des::decode_header(&mut buffer);
+        }
+
+        buffer.truncate(entry.compressed_size as usize);
+        inflate(&buffer, entry.uncompressed_size)
+    }
+}
+
+/// 0x1xx file table: stored as is up to the end of the archive, with DES
+/// encrypted names and sizes shifted by constants. Encryption is not
+/// flagged, it depends on the extension.
+fn read_table_v1<R: Read + Seek>(
+    reader: &mut R,
+    table_offset: u32,
+    file_count: u32,
+) -> Result<HashMap<String, GrfEntry>, ParseError> {
+    reader.seek(SeekFrom::Start(table_offset as u64 + GRF_HEADER_LEN as u64))?;
+    let mut table = Vec::new();
+    reader.read_to_end(&mut table)?;
+
+    let mut entries = HashMap::new();
+    let mut data = BinaryReader::new(&table);
+    for _ in 0..file_count {
+        if data.is_eof() {
+            break;
+        }
+
+        // 2 unknown bytes, the name, then 4 unknown bytes
+        let len = data.read_u32()? as usize;
+        let mut name = data
+            .read_bytes(len)?
+            .get(2..len.saturating_sub(4))
+            .ok_or(ParseError::UnexpectedEof)?
+            .to_vec();
+        des::decode_name(&mut name);
+
+        let compressed_size = data.read_u32()?;
+        let compressed_size_aligned = data.read_u32()?;
+        let uncompressed_size = data.read_u32()?;
+        let mut entry = GrfEntry {
+            name: decode_name(&name),
+            compressed_size: compressed_size
+                .wrapping_sub(uncompressed_size)
+                .wrapping_sub(0x2CB),
+            compressed_size_aligned: compressed_size_aligned.wrapping_sub(37579),
+            uncompressed_size,
+            flags: data.read_u8()?,
+            offset: data.read_u32()?,
+        };
+
+        if entry.is_file() {
+            entry.flags |= if is_header_only(&entry.name) {
+                GRF_FLAG_DES
+            } else {
+                GRF_FLAG_MIXCRYPT
+            };
+            entries.insert(normalize_path(&entry.name), entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// 0x200 file table: a zlib stream prefixed by its sizes.
+fn read_table_v2<R: Read + Seek>(
+    reader: &mut R,
+    table_offset: u32,
+    file_count: u32,
+) -> Result<HashMap<String, GrfEntry>, ParseError> {
+    reader.seek(SeekFrom::Start(table_offset as u64 + GRF_HEADER_LEN as u64))?;
+    let mut sizes = [0u8; 8];
+    reader
+        .read_exact(&mut sizes)
+        .map_err(|_| ParseError::UnexpectedEof)?;
+    let mut data = BinaryReader::new(&sizes);
+    let compressed_size = data.read_u32()?;
+    let uncompressed_size = data.read_u32()?;
+
+    let mut compressed = vec![0u8; compressed_size as usize];
+    reader
+        .read_exact(&mut compressed)
+        .map_err(|_| ParseError::UnexpectedEof)?;
+    let table = inflate(&compressed, uncompressed_size)?;
+
+    let mut entries = HashMap::new();
+    let mut data = BinaryReader::new(&table);
+    for _ in 0..file_count {
+        if data.is_eof() {
+            break;
+        }
+
+        let name = decode_name(data.read_null_terminated()?);
+        let entry = GrfEntry {
+            name,
+            compressed_size: data.read_u32()?,
+            compressed_size_aligned: data.read_u32()?,
+            uncompressed_size: data.read_u32()?,
+            flags: data.read_u8()?,
+            offset: data.read_u32()?,
+        };
+
+        // directories are listed too, we only care about files
+        if entry.is_file() {
+            entries.insert(normalize_path(&entry.name), entry);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Names are CP949 (Korean), up to the first null byte.
+fn decode_name(bytes: &[u8]) -> String {
+    let len = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    let (name, _) = EUC_KR.decode_without_bom_handling(&bytes[..len]);
+    name.into_owned()
+}
+
+/// In 0x1xx archives these are only encrypted on their first blocks, any
+/// other file is fully encrypted.
+fn is_header_only(name: &str) -> bool {
+    let name = name.to_lowercase();
+    [".gnd", ".gat", ".act", ".str"]
+        .iter()
+        .any(|extension| name.ends_with(extension))
+}
+
+pub fn normalize_path(name: &str) -> String {
+    name.replace('/', "\\").to_lowercase()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::{Cursor, Write};
+
+    use flate2::{write::ZlibEncoder, Compression};
+
+    use super::*;
+    use crate::io::des::tests::{encode_full, encode_header, encode_name};
+
+    pub(crate) fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Builds a version 0x200 archive in memory.
+    pub(crate) fn build_grf(files: &[(&str, &[u8], u8)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        let mut table = Vec::new();
+
+        for (name, content, flags) in files {
+            let mut compressed = deflate(content);
+            let compressed_size = compressed.len() as u32;
+            if flags & (GRF_FLAG_MIXCRYPT | GRF_FLAG_DES) != 0 {
+                compressed.resize((compressed.len() + 7) & !7, 0);
+            }
+
+            if flags & GRF_FLAG_MIXCRYPT != 0 {
+                encode_full(&mut compressed, compressed_size);
+            } else if flags & GRF_FLAG_DES != 0 {
+                encode_header(&mut compressed);
+            }
+
+            table.extend_from_slice(&EUC_KR.encode(name).0);
+            table.push(0);
+            table.extend_from_slice(&compressed_size.to_le_bytes());
+            table.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            table.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            table.push(*flags);
+            table.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            body.extend_from_slice(&compressed);
+        }
+
+        let seed = 0u32;
+        let mut grf = Vec::new();
+        grf.extend_from_slice(GRF_MAGIC);
+        grf.extend_from_slice(&[0u8; 14]);
+        grf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        grf.extend_from_slice(&seed.to_le_bytes());
+        grf.extend_from_slice(&(files.len() as u32 + seed + 7).to_le_bytes());
+        grf.extend_from_slice(&0x200u32.to_le_bytes());
+        grf.extend_from_slice(&body);
+
+        let compressed_table = deflate(&table);
+        grf.extend_from_slice(&(compressed_table.len() as u32).to_le_bytes());
+        grf.extend_from_slice(&(table.len() as u32).to_le_bytes());
+        grf.extend_from_slice(&compressed_table);
+        grf
+    }
+
+    /// Builds a version 0x103 archive in memory, entries are encrypted
+    /// based on their extension like the client expects.
+    pub(crate) fn build_grf_v1(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        let mut table = Vec::new();
+
+        for (name, content) in files {
+            let mut compressed = deflate(content);
+            let compressed_size = compressed.len() as u32;
+            compressed.resize((compressed.len() + 7) & !7, 0);
+            if is_header_only(name) {
+                encode_header(&mut compressed);
+            } else {
+                encode_full(&mut compressed, compressed_size);
+            }
+
+            let mut encoded_name = EUC_KR.encode(name).0.into_owned();
+            encoded_name.resize((encoded_name.len() + 8) & !7, 0);
+            encode_name(&mut encoded_name);
+
+            table.extend_from_slice(&(encoded_name.len() as u32 + 6).to_le_bytes());
+            table.extend_from_slice(&[0u8; 2]);
+            table.extend_from_slice(&encoded_name);
+            table.extend_from_slice(&[0u8; 4]);
+            let uncompressed_size = content.len() as u32;
+            table.extend_from_slice(&(compressed_size + uncompressed_size + 715).to_le_bytes());
+            table.extend_from_slice(&(compressed.len() as u32 + 37579).to_le_bytes());
+            table.extend_from_slice(&uncompressed_size.to_le_bytes());
+            table.push(GRF_FLAG_FILE);
+            table.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            body.extend_from_slice(&compressed);
+        }
+
+        let seed = 0u32;
+        let mut grf = Vec::new();
+        grf.extend_from_slice(GRF_MAGIC);
+        grf.extend_from_slice(&[0u8; 14]);
+        grf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        grf.extend_from_slice(&seed.to_le_bytes());
+        grf.extend_from_slice(&(files.len() as u32 + seed + 7).to_le_bytes());
+        grf.extend_from_slice(&0x103u32.to_le_bytes());
+        grf.extend_from_slice(&body);
+        grf.extend_from_slice(&table);
+        grf
+    }
+
+    /// Pseudo random content, so zlib output spans enough DES blocks.
+    fn fixture_content(len: usize) -> Vec<u8> {
+        let mut seed: u32 = 0x1234_5678;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_plain_and_encrypted_entries() {
+        let plain = b"plain entry".to_vec();
+        let header = fixture_content(600);
+        let mixed = fixture_content(4000);
+        let data = build_grf(&[
+            ("data\\plain.txt", &plain, GRF_FLAG_FILE),
+            ("data\\header.rsw", &header, GRF_FLAG_FILE | GRF_FLAG_DES),
+            ("data\\mixed.gat", &mixed, GRF_FLAG_FILE | GRF_FLAG_MIXCRYPT),
+        ]);
+
+        let mut grf = Grf::new(Cursor::new(data)).unwrap();
+        assert_eq!(grf.entries().count(), 3);
+        assert!(grf.entry("data\\mixed.gat").unwrap().is_mixcrypt());
+
+        assert_eq!(grf.read("data\\plain.txt").unwrap(), plain);
+        assert_eq!(grf.read("data\\header.rsw").unwrap(), header);
+        assert_eq!(grf.read("data\\mixed.gat").unwrap(), mixed);
+    }
+
+    #[test]
+    fn reads_version_1_archives() {
+        let gat = fixture_content(600);
+        let txt = fixture_content(4000);
+        let data = build_grf_v1(&[("data\\prontera.gat", &gat), ("data\\readme.txt", &txt)]);
+
+        let mut grf = Grf::new(Cursor::new(data)).unwrap();
+        assert_eq!(grf.version, 0x103);
+        assert_eq!(grf.entries().count(), 2);
+        assert!(grf.entry("data\\prontera.gat").unwrap().is_des());
+        assert!(grf.entry("data\\readme.txt").unwrap().is_mixcrypt());
+
+        assert_eq!(grf.read("data\\prontera.gat").unwrap(), gat);
+        assert_eq!(grf.read("data\\readme.txt").unwrap(), txt);
+    }
+
+    #[test]
+    fn names_are_decoded_from_cp949() {
+        let name = "data\\texture\\유저인터페이스\\basic.bmp";
+        let data = build_grf(&[(name, b"bmp", GRF_FLAG_FILE)]);
+        let grf = Grf::new(Cursor::new(data)).unwrap();
+        assert_eq!(grf.entries().next().unwrap().name, name);
+
+        let data = build_grf_v1(&[(name, b"bmp")]);
+        let mut grf = Grf::new(Cursor::new(data)).unwrap();
+        assert_eq!(grf.read(name).unwrap(), b"bmp");
+    }
+
+    #[test]
+    fn lookup_is_case_and_separator_insensitive() {
+        let data = build_grf(&[("data\\Prontera.gat", b"gat", GRF_FLAG_FILE)]);
+        let mut grf = Grf::new(Cursor::new(data)).unwrap();
+
+        assert!(grf.contains("DATA/prontera.GAT"));
+        assert_eq!(grf.read("data/prontera.gat").unwrap(), b"gat");
+        assert!(matches!(
+            grf.read("data\\missing.gat"),
+            Err(ParseError::EntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn directories_are_not_listed() {
+        let data = build_grf(&[("data", b"", 0), ("data\\a.txt", b"a", GRF_FLAG_FILE)]);
+        let grf = Grf::new(Cursor::new(data)).unwrap();
+
+        assert_eq!(grf.entries().count(), 1);
+    }
+
+    #[test]
+    fn rejects_invalid_magic_and_version() {
+        let mut data = build_grf(&[("data\\a.txt", b"a", GRF_FLAG_FILE)]);
+        data[42..46].copy_from_slice(&0x300u32.to_le_bytes());
+        assert!(matches!(
+            Grf::new(Cursor::new(data.clone())),
+            Err(ParseError::UnsupportedVersion(0x300))
+        ));
+
+        data[0] = b'X';
+        assert!(matches!(
+            Grf::new(Cursor::new(data)),
+            Err(ParseError::InvalidMagic)
+        ));
+    }
+}
@@ -0,0 +1,160 @@
+use std::{collections::HashMap, fs::File, io::Read};
+
+use super::{
+    error::ParseError,
+    gat::{CellMap, GatCellType},
+    reader::{inflate, BinaryReader},
+};
+
+/// `MAP_NAME_LENGTH` on the server, 11 chars + null-terminator.
+pub static MAP_CACHE_NAME_LEN: usize = 12;
+
+/// rAthena `map_cache.dat`: the walkability of every map the server loads,
+/// with water cells already resolved from the rsw files.
+pub struct MapCacheFile {
+    pub file_size: u32,
+    maps: HashMap<String, MapCacheData>,
+}
+
+/// Cell types of a single map, one byte per cell.
+pub struct MapCacheData {
+    pub name: String,
+    pub width: u16,
+    pub height: u16,
+    pub cells: Vec<u8>,
+}
+
+impl MapCacheFile {
+    pub fn parse(file_name: &str) -> Result<MapCacheFile, ParseError> {
+        let mut file = File::open(file_name)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        MapCacheFile::from_bytes(&buffer)
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Result<MapCacheFile, ParseError> {
+        let mut data = BinaryReader::new(buffer);
+
+        // main header
+        let file_size = data.read_u32()?;
+        let map_count = data.read_u16()?;
+
+        let mut maps = HashMap::new();
+        for _ in 0..map_count {
+            // map info header
+            let name = data.read_string(Some(MAP_CACHE_NAME_LEN))?;
+            let width = data.read_u16()?;
+            let height = data.read_u16()?;
+            let len = data.read_u32()?;
+
+            let cells = inflate(data.read_bytes(len as usize)?, width as u32 * height as u32)?;
+            if cells.len() != width as usize * height as usize {
+                return Err(ParseError::UnexpectedEof);
+            }
+
+            maps.insert(
+                name.to_lowercase(),
+                MapCacheData {
+                    name,
+                    width,
+                    height,
+                    cells,
+                },
+            );
+        }
+
+        Ok(MapCacheFile { file_size, maps })
+    }
+
+    /// Looks a map up by name, with or without the `.gat` extension.
+    pub fn get(&self, map_name: &str) -> Option<&MapCacheData> {
+        let map_name = map_name.to_lowercase();
+        let map_name = map_name.strip_suffix(".gat").unwrap_or(&map_name);
+        self.maps.get(map_name)
+    }
+
+    pub fn maps(&self) -> impl Iterator<Item = &MapCacheData> {
+        self.maps.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.maps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.is_empty()
+    }
+}
+
+impl CellMap for MapCacheData {
+    fn width(&self) -> u32 {
+        self.width as u32
+    }
+
+    fn height(&self) -> u32 {
+        self.height as u32
+    }
+
+    fn cell_type(&self, x: u16, y: u16) -> Option<GatCellType> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        let cell = self.cells[x as usize + y as usize * self.width as usize];
+        GatCellType::try_from(cell).ok()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::io::grf::tests::deflate;
+
+    /// Builds a map cache from `(name, width, cells)` entries.
+    pub(crate) fn build_map_cache(maps: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (name, width, cells) in maps {
+            let compressed = deflate(cells);
+            let mut name = name.as_bytes().to_vec();
+            name.resize(MAP_CACHE_NAME_LEN, 0);
+
+            body.extend(name);
+            body.extend_from_slice(&width.to_le_bytes());
+            body.extend_from_slice(&(cells.len() as u16 / width).to_le_bytes());
+            body.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+            body.extend(compressed);
+        }
+
+        let mut map_cache = Vec::new();
+        map_cache.extend_from_slice(&(body.len() as u32 + 6).to_le_bytes());
+        map_cache.extend_from_slice(&(maps.len() as u16).to_le_bytes());
+        map_cache.extend(body);
+        map_cache
+    }
+
+    #[test]
+    fn reads_cells_of_every_map() {
+        let data = build_map_cache(&[
+            ("prontera", 3, &[0, 1, 5, 3, 0, 0]),
+            ("geffen", 2, &[1, 1, 1, 1]),
+        ]);
+        let map_cache = MapCacheFile::from_bytes(&data).unwrap();
+        assert_eq!(map_cache.len(), 2);
+
+        let prontera = map_cache.get("Prontera.gat").unwrap();
+        assert_eq!((prontera.width(), prontera.height()), (3, 2));
+        assert!(prontera.is_walkable(0, 0));
+        assert!(!prontera.is_walkable(1, 0));
+        assert!(prontera.is_shootable(2, 0));
+        assert_eq!(prontera.cell_type(0, 1), Some(GatCellType::Water));
+        assert_eq!(prontera.cell_type(3, 0), None);
+        assert!(!map_cache.get("geffen").unwrap().is_walkable(1, 1));
+    }
+
+    #[test]
+    fn truncated_file_is_an_error() {
+        let data = build_map_cache(&[("prontera", 3, &[0, 1, 5, 3, 0, 0])]);
+
+        assert!(MapCacheFile::from_bytes(&data[..data.len() - 4]).is_err());
+    }
+}
@@ -1 +1,8 @@
-pub mod gat;
\ No newline at end of file
+pub mod des;
+pub mod error;
+pub mod gat;
+pub mod gnd;
+pub mod grf;
+pub mod mapcache;
+pub mod reader;
+pub mod rsw;
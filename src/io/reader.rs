@@ -0,0 +1,114 @@
+use std::io::Read;
+
+use flate2::read::ZlibDecoder;
+
+use super::error::ParseError;
+
+/// Little-endian reader over an in-memory resource file.
+///
+/// Same idea as `InputMessage`, but every read is bounds checked since
+/// resource files come from disk and may be truncated or corrupted.
+pub struct BinaryReader<'a> {
+    pub data: &'a [u8],
+    pub position: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+    pub fn new(data: &'a [u8]) -> BinaryReader<'a> {
+        BinaryReader { data, position: 0 }
+    }
+
+    pub fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], ParseError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .filter(|&end| end <= self.data.len())
+            .ok_or(ParseError::UnexpectedEof)?;
+        let bytes = &self.data[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, ParseError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_i16(&mut self) -> Result<i16, ParseError> {
+        Ok(self.read_u16()? as i16)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ParseError> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, ParseError> {
+        Ok(self.read_u32()? as i32)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, ParseError> {
+        let bytes = self.read_bytes(8)?;
+        Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, ParseError> {
+        Ok(f32::from_bits(self.read_u32()?))
+    }
+
+    /// Reads a string of `n` bytes, or until the null-terminator when `n` is `None`.
+    /// Bytes after the first null-terminator of a fixed size string are dropped.
+    pub fn read_string(&mut self, n: Option<usize>) -> Result<String, ParseError> {
+        let bytes = match n {
+            Some(size) => self.read_bytes(size)?,
+            None => self.read_null_terminated()?,
+        };
+
+        Ok(bytes
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| byte as char)
+            .collect())
+    }
+
+    /// Reads the bytes up to the null-terminator, which is consumed but not returned.
+    pub fn read_null_terminated(&mut self) -> Result<&'a [u8], ParseError> {
+        let rest = &self.data[self.position.min(self.data.len())..];
+        let len = rest
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or(ParseError::UnexpectedEof)?;
+        let bytes = self.read_bytes(len)?;
+        self.skip_bytes(1); // null-terminator
+        Ok(bytes)
+    }
+
+    pub fn skip_bytes(&mut self, count: usize) {
+        self.position += count;
+    }
+
+    pub fn set_position(&mut self, position: usize) {
+        self.position = position;
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.position)
+    }
+
+    pub fn is_eof(&self) -> bool {
+        self.position >= self.data.len()
+    }
+}
+
+/// Inflates a zlib stream, `uncompressed_size` is only a capacity hint.
+pub fn inflate(data: &[u8], uncompressed_size: u32) -> Result<Vec<u8>, ParseError> {
+    let mut output = Vec::with_capacity(uncompressed_size as usize);
+    ZlibDecoder::new(data)
+        .read_to_end(&mut output)
+        .map_err(ParseError::Decompress)?;
+    Ok(output)
+}
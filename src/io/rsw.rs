@@ -0,0 +1,402 @@
+use std::{fs::File, io::Read};
+
+use super::{error::ParseError, reader::BinaryReader};
+
+pub static RSW_MAGIC: &[u8; 4] = b"GRSW";
+
+/// Highest version we know how to read (2.6).
+pub static RSW_MAX_VERSION: u16 = 0x0206;
+
+pub struct RswData {
+    /// `(major << 8) | minor`, e.g. 0x0109 for 1.9
+    pub version: u16,
+    pub build_number: u32,
+    pub ini_file: String,
+    pub gnd_file: String,
+    pub gat_file: String,
+    pub src_file: String,
+    /// Not present since 2.6, water planes moved to the gnd file.
+    pub water: Option<WaterSettings>,
+    pub light: LightSettings,
+    pub ground: GroundBounds,
+    pub objects: Vec<RswObject>,
+}
+
+pub struct WaterSettings {
+    /// Same axis as gat heights: a cell is under water when its height is greater.
+    pub level: f32,
+    pub r#type: i32,
+    pub wave_height: f32,
+    pub wave_speed: f32,
+    pub wave_pitch: f32,
+    pub anim_speed: i32,
+}
+
+pub struct LightSettings {
+    pub longitude: i32,
+    pub latitude: i32,
+    pub diffuse: [f32; 3],
+    pub ambient: [f32; 3],
+    pub shadow_opacity: f32,
+}
+
+pub struct GroundBounds {
+    pub top: i32,
+    pub bottom: i32,
+    pub left: i32,
+    pub right: i32,
+}
+
+pub enum RswObject {
+    Model(RswModel),
+    Light(RswLight),
+    Sound(RswSound),
+    Effect(RswEffect),
+}
+
+pub struct RswModel {
+    pub name: String,
+    pub anim_type: i32,
+    pub anim_speed: f32,
+    pub block_type: i32,
+    pub file_name: String,
+    pub node_name: String,
+    pub position: [f32; 3],
+    pub rotation: [f32; 3],
+    pub scale: [f32; 3],
+}
+
+pub struct RswLight {
+    pub name: String,
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+    pub range: f32,
+}
+
+pub struct RswSound {
+    pub name: String,
+    pub file_name: String,
+    pub position: [f32; 3],
+    pub volume: f32,
+    pub width: i32,
+    pub height: i32,
+    pub range: f32,
+    pub cycle: f32,
+}
+
+pub struct RswEffect {
+    pub name: String,
+    pub position: [f32; 3],
+    pub effect_id: i32,
+    pub emit_speed: f32,
+    pub params: [f32; 4],
+}
+
+fn read_vec3(data: &mut BinaryReader) -> Result<[f32; 3], ParseError> {
+    Ok([data.read_f32()?, data.read_f32()?, data.read_f32()?])
+}
+
+impl RswData {
+    pub fn parse(file_name: &str) -> Result<RswData, ParseError> {
+        let mut file = File::open(file_name)?;
+        let mut buffer = Vec::new();
+        file.read_to_end(&mut buffer)?;
+
+        RswData::from_bytes(&buffer)
+    }
+
+    pub fn from_bytes(buffer: &[u8]) -> Result<RswData, ParseError> {
+        let mut data = BinaryReader::new(buffer);
+
+        if data.read_bytes(4)? != RSW_MAGIC {
+            return Err(ParseError::InvalidMagic);
+        }
+        let major = data.read_u8()?;
+        let minor = data.read_u8()?;
+        let version = (major as u16) << 8 | minor as u16;
+        if version > RSW_MAX_VERSION {
+            return Err(ParseError::UnsupportedVersion(version as u32));
+        }
+
+        let mut build_number = 0;
+        if version >= 0x0205 {
+            build_number = data.read_u32()?;
+            data.skip_bytes(1); // unknown render flag
+        } else if version >= 0x0202 {
+            build_number = data.read_u8()? as u32;
+        }
+
+        let ini_file = data.read_string(Some(40))?;
+        let gnd_file = data.read_string(Some(40))?;
+        let gat_file = if version >= 0x0104 {
+            data.read_string(Some(40))?
+        } else {
+            String::new()
+        };
+        let src_file = data.read_string(Some(40))?;
+
+        let water = if version < 0x0206 {
+            let mut water = WaterSettings {
+                level: 0.0,
+                r#type: 0,
+                wave_height: 1.0,
+                wave_speed: 2.0,
+                wave_pitch: 50.0,
+                anim_speed: 3,
+            };
+            if version >= 0x0103 {
+                water.level = data.read_f32()?;
+            }
+            if version >= 0x0108 {
+                water.r#type = data.read_i32()?;
+                water.wave_height = data.read_f32()?;
+                water.wave_speed = data.read_f32()?;
+                water.wave_pitch = data.read_f32()?;
+            }
+            if version >= 0x0109 {
+                water.anim_speed = data.read_i32()?;
+            }
+            Some(water)
+        } else {
+            None
+        };
+
+        let mut light = LightSettings {
+            longitude: 45,
+            latitude: 45,
+            diffuse: [1.0, 1.0, 1.0],
+            ambient: [0.3, 0.3, 0.3],
+            shadow_opacity: 0.5,
+        };
+        if version >= 0x0105 {
+            light.longitude = data.read_i32()?;
+            light.latitude = data.read_i32()?;
+            light.diffuse = read_vec3(&mut data)?;
+            light.ambient = read_vec3(&mut data)?;
+        }
+        if version >= 0x0107 {
+            light.shadow_opacity = data.read_f32()?;
+        }
+
+        let mut ground = GroundBounds {
+            top: -500,
+            bottom: 500,
+            left: -500,
+            right: 500,
+        };
+        if version >= 0x0106 {
+            ground.top = data.read_i32()?;
+            ground.bottom = data.read_i32()?;
+            ground.left = data.read_i32()?;
+            ground.right = data.read_i32()?;
+        }
+
+        let object_count = data.read_u32()?;
+        let mut objects = Vec::new();
+        for _ in 0..object_count {
+            let object_type = data.read_u32()?;
+            let object = match object_type {
+                1 => {
+                    let mut name = String::new();
+                    let mut anim_type = 0;
+                    let mut anim_speed = 1.0;
+                    let mut block_type = 0;
+                    if version >= 0x0103 {
+                        name = data.read_string(Some(40))?;
+                        anim_type = data.read_i32()?;
+                        anim_speed = data.read_f32()?;
+                        block_type = data.read_i32()?;
+                    }
+                    if version >= 0x0206 && build_number >= 162 {
+                        data.skip_bytes(1); // unknown
+                    }
+
+                    RswObject::Model(RswModel {
+                        name,
+                        anim_type,
+                        anim_speed,
+                        block_type,
+                        file_name: data.read_string(Some(80))?,
+                        node_name: data.read_string(Some(80))?,
+                        position: read_vec3(&mut data)?,
+                        rotation: read_vec3(&mut data)?,
+                        scale: read_vec3(&mut data)?,
+                    })
+                }
+                2 => RswObject::Light(RswLight {
+                    name: data.read_string(Some(80))?,
+                    position: read_vec3(&mut data)?,
+                    color: read_vec3(&mut data)?,
+                    range: data.read_f32()?,
+                }),
+                3 => RswObject::Sound(RswSound {
+                    name: data.read_string(Some(80))?,
+                    file_name: data.read_string(Some(80))?,
+                    position: read_vec3(&mut data)?,
+                    volume: data.read_f32()?,
+                    width: data.read_i32()?,
+                    height: data.read_i32()?,
+                    range: data.read_f32()?,
+                    cycle: if version >= 0x0200 {
+                        data.read_f32()?
+                    } else {
+                        4.0
+                    },
+                }),
+                4 => RswObject::Effect(RswEffect {
+                    name: data.read_string(Some(80))?,
+                    position: read_vec3(&mut data)?,
+                    effect_id: data.read_i32()?,
+                    emit_speed: data.read_f32()?,
+                    params: [
+                        data.read_f32()?,
+                        data.read_f32()?,
+                        data.read_f32()?,
+                        data.read_f32()?,
+                    ],
+                }),
+                _ => {
+                    // unknown object, we can't know its size so stop here
+                    tracing::warn!(object_type, "unknown rsw object type");
+                    break;
+                }
+            };
+            objects.push(object);
+        }
+
+        // quad tree (2.1+) follows, not needed
+
+        Ok(RswData {
+            version,
+            build_number,
+            ini_file,
+            gnd_file,
+            gat_file,
+            src_file,
+            water,
+            light,
+            ground,
+            objects,
+        })
+    }
+
+    pub fn models(&self) -> impl Iterator<Item = &RswModel> {
+        self.objects.iter().filter_map(|object| match object {
+            RswObject::Model(model) => Some(model),
+            _ => None,
+        })
+    }
+
+    pub fn water_level(&self) -> Option<f32> {
+        self.water.as_ref().map(|water| water.level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed(value: &str, len: usize) -> Vec<u8> {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize(len, 0);
+        bytes
+    }
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    fn build_rsw(major: u8, minor: u8) -> Vec<u8> {
+        let version = (major as u16) << 8 | minor as u16;
+        let mut rsw = b"GRSW".to_vec();
+        rsw.extend_from_slice(&[major, minor]);
+        if version >= 0x0205 {
+            rsw.extend_from_slice(&200u32.to_le_bytes());
+            rsw.push(0);
+        } else if version >= 0x0202 {
+            rsw.push(1);
+        }
+        rsw.extend(fixed("", 40));
+        rsw.extend(fixed("test.gnd", 40));
+        rsw.extend(fixed("test.gat", 40));
+        rsw.extend(fixed("", 40));
+        if version < 0x0206 {
+            rsw.extend(floats(&[-1.5]));
+            rsw.extend_from_slice(&2i32.to_le_bytes());
+            rsw.extend(floats(&[1.0, 2.0, 50.0]));
+            rsw.extend_from_slice(&3i32.to_le_bytes());
+        }
+        rsw.extend_from_slice(&45i32.to_le_bytes());
+        rsw.extend_from_slice(&60i32.to_le_bytes());
+        rsw.extend(floats(&[1.0, 1.0, 1.0, 0.3, 0.3, 0.3, 0.5]));
+        for bound in [-100i32, 100, -100, 100] {
+            rsw.extend_from_slice(&bound.to_le_bytes());
+        }
+
+        rsw.extend_from_slice(&2u32.to_le_bytes());
+        rsw.extend_from_slice(&1u32.to_le_bytes());
+        rsw.extend(fixed("tree01", 40));
+        rsw.extend_from_slice(&0i32.to_le_bytes());
+        rsw.extend(floats(&[1.0]));
+        rsw.extend_from_slice(&0i32.to_le_bytes());
+        if version >= 0x0206 {
+            rsw.push(0);
+        }
+        rsw.extend(fixed("tree.rsm", 80));
+        rsw.extend(fixed("", 80));
+        rsw.extend(floats(&[10.0, -5.0, 20.0, 0.0, 90.0, 0.0, 1.0, 1.0, 1.0]));
+
+        rsw.extend_from_slice(&3u32.to_le_bytes());
+        rsw.extend(fixed("bird", 80));
+        rsw.extend(fixed("bird.wav", 80));
+        rsw.extend(floats(&[0.0, 0.0, 0.0, 0.8]));
+        rsw.extend_from_slice(&10i32.to_le_bytes());
+        rsw.extend_from_slice(&10i32.to_le_bytes());
+        rsw.extend(floats(&[100.0]));
+        if version >= 0x0200 {
+            rsw.extend(floats(&[4.0]));
+        }
+        rsw
+    }
+
+    #[test]
+    fn parses_water_and_objects_across_versions() {
+        for (major, minor) in [(1, 9), (2, 1), (2, 2), (2, 5)] {
+            let rsw = RswData::from_bytes(&build_rsw(major, minor)).unwrap();
+            let water = rsw.water.as_ref().unwrap();
+
+            assert_eq!(rsw.gat_file, "test.gat");
+            assert_eq!(water.level, -1.5);
+            assert_eq!(water.r#type, 2);
+            assert_eq!(water.anim_speed, 3);
+            assert_eq!(rsw.light.latitude, 60);
+            assert_eq!(rsw.objects.len(), 2);
+
+            let model = rsw.models().next().unwrap();
+            assert_eq!(model.name, "tree01");
+            assert_eq!(model.file_name, "tree.rsm");
+            assert_eq!(model.position, [10.0, -5.0, 20.0]);
+            assert!(matches!(&rsw.objects[1], RswObject::Sound(sound) if sound.file_name == "bird.wav"));
+        }
+    }
+
+    #[test]
+    fn water_moved_out_of_rsw_in_2_6() {
+        let rsw = RswData::from_bytes(&build_rsw(2, 6)).unwrap();
+
+        assert_eq!(rsw.build_number, 200);
+        assert!(rsw.water_level().is_none());
+        assert_eq!(rsw.models().next().unwrap().file_name, "tree.rsm");
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let mut data = build_rsw(2, 6);
+        data[5] = 7;
+
+        assert!(matches!(
+            RswData::from_bytes(&data),
+            Err(ParseError::UnsupportedVersion(0x0207))
+        ));
+    }
+}
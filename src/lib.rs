@@ -0,0 +1,17 @@
+pub mod capture;
+pub mod client;
+pub mod r#const;
+pub mod enums;
+pub mod input_message;
+pub mod inspector;
+pub mod io;
+pub mod logging;
+pub mod map;
+pub mod metrics;
+#[cfg(test)]
+mod mock_server;
+pub mod model;
+pub mod network_message;
+pub mod protocol;
+pub mod proxy;
+pub mod swarm;
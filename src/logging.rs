@@ -0,0 +1,17 @@
+//! Log output of the binaries. Every session logs under a `session` span
+//! (`account`, `account_id`) with a child span per stage: `login`, `char`
+//! and `map`.
+//!
+//! The filter comes from `RUST_LOG`, `LOG_FILTER` when unset. With many
+//! sessions running, one can be picked out by its span field:
+//! `RUST_LOG='info,[session{account=bot12}]=debug'`.
+
+use tracing_subscriber::EnvFilter;
+
+use crate::r#const::LOG_FILTER;
+
+/// Installs the log subscriber, does nothing if one is already set.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(LOG_FILTER));
+    let _ = tracing_subscriber::fmt().with_env_filter(filter).try_init();
+}
@@ -1,22 +1,28 @@
-mod r#const;
-mod enums;
-mod input_message;
-mod network_message;
-mod protocol;
-mod client;
-mod model;
-mod io;
-
+use ragnarok_socket::{
+    capture, logging,
+    protocol::{self, session::ReconnectPolicy},
+    r#const,
+};
 use tokio::runtime::Builder;
 
 async fn initialize() {
     // testing io
     //let gat_data = io::gat::GatData::parse("data/gat/pay_dun00.gat");
-    protocol::login::initialize().await;
-
-    loop {
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    logging::init();
+    if let Some(capture_file) = r#const::CAPTURE_FILE {
+        if let Err(e) = capture::recorder::start(capture_file, r#const::CAPTURE_FORMAT) {
+            tracing::error!(error = %e, "failed to start packet capture");
+        }
     }
+    // survives server restarts, ends once out of reconnect attempts
+    let mut session = protocol::login::start_with_reconnect(
+        r#const::LOGIN_SERVER_ADDR,
+        r#const::LOGIN_USERNAME,
+        r#const::LOGIN_PASSWORD,
+        ReconnectPolicy::default(),
+    );
+    while session.next_event().await.is_some() {}
+    capture::recorder::finish();
 }
 
 fn main() {
@@ -0,0 +1,423 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::BufReader,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use crate::io::{error::ParseError, gat::GatData, grf::Grf, mapcache::MapCacheFile, rsw::RswData};
+
+/// Default directory used when the shared cache is not initialized.
+pub static MAP_CACHE_DEFAULT_DIR: &str = "data/gat";
+/// Default memory cap of the shared cache.
+pub static MAP_CACHE_DEFAULT_LIMIT: usize = 256 * 1024 * 1024;
+
+static SHARED_MAP_CACHE: OnceLock<Arc<MapCache>> = OnceLock::new();
+
+pub enum MapSource {
+    /// Directory holding `<map>.gat` files, and optionally `<map>.rsw`.
+    Directory(PathBuf),
+    /// Client archive, maps are read from `data\<map>.gat`.
+    Grf(Mutex<Grf<BufReader<File>>>),
+    /// rAthena `map_cache.dat`, the exact cells the server uses. Has no heights.
+    ServerCache(MapCacheFile),
+}
+
+struct CachedMap {
+    data: Arc<GatData>,
+    size: usize,
+    last_used: u64,
+}
+
+struct MapCacheState {
+    maps: HashMap<String, CachedMap>,
+    memory_used: usize,
+    clock: u64,
+}
+
+/// Parsed maps shared by every session.
+///
+/// Maps are loaded on first use from the first source that has them, and
+/// the least recently used maps are dropped once `memory_limit` is reached.
+/// Sessions keep their `Arc` alive, so dropping a map from the cache never
+/// invalidates a map in use.
+pub struct MapCache {
+    sources: Vec<MapSource>,
+    memory_limit: usize,
+    state: Mutex<MapCacheState>,
+}
+
+/// Map names come as `prontera.gat` in packets, the cache key is `prontera`.
+pub fn normalize_map_name(map_name: &str) -> String {
+    let map_name = map_name.to_lowercase();
+    let map_name = map_name.rsplit(['/', '\\']).next().unwrap_or_default();
+    map_name
+        .strip_suffix(".gat")
+        .or_else(|| map_name.strip_suffix(".rsw"))
+        .unwrap_or(map_name)
+        .to_string()
+}
+
+impl MapCache {
+    pub fn new(memory_limit: usize) -> MapCache {
+        MapCache {
+            sources: Vec::new(),
+            memory_limit,
+            state: Mutex::new(MapCacheState {
+                maps: HashMap::new(),
+                memory_used: 0,
+                clock: 0,
+            }),
+        }
+    }
+
+    pub fn with_directory(mut self, path: &str) -> MapCache {
+        self.sources.push(MapSource::Directory(PathBuf::from(path)));
+        self
+    }
+
+    pub fn with_grf(mut self, file_name: &str) -> Result<MapCache, ParseError> {
+        let grf = Grf::open(file_name)?;
+        self.sources.push(MapSource::Grf(Mutex::new(grf)));
+        Ok(self)
+    }
+
+    pub fn with_server_cache(mut self, file_name: &str) -> Result<MapCache, ParseError> {
+        let map_cache = MapCacheFile::parse(file_name)?;
+        self.sources.push(MapSource::ServerCache(map_cache));
+        Ok(self)
+    }
+
+    /// Returns the parsed map, loading it if needed. Loading reads files and
+    /// inflates GRF entries, async callers run it on the blocking pool.
+    pub fn get(&self, map_name: &str) -> Result<Arc<GatData>, ParseError> {
+        let key = normalize_map_name(map_name);
+
+        if let Some(data) = self.lookup(&key) {
+            return Ok(data);
+        }
+
+        // parse outside of the lock, other sessions may keep using the cache.
+        // if two sessions load the same map at once the first insert wins
+        let data = Arc::new(self.load(&key)?);
+        Ok(self.insert(key, data))
+    }
+
+    /// Returns the parsed map only if it is already loaded, never reads a
+    /// source so it is fine to call from async code.
+    pub fn cached(&self, map_name: &str) -> Option<Arc<GatData>> {
+        self.lookup(&normalize_map_name(map_name))
+    }
+
+    pub fn contains(&self, map_name: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.maps.contains_key(&normalize_map_name(map_name))
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().maps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.state.lock().unwrap().memory_used
+    }
+
+    /// Loads every map found in the sources until the memory limit is
+    /// reached, returns how many maps were loaded.
+    pub fn preload(&self) -> Result<usize, ParseError> {
+        let mut loaded = 0;
+
+        for map_name in self.available_maps()? {
+            if self.contains(&map_name) {
+                continue;
+            }
+
+            let data = Arc::new(self.load(&map_name)?);
+            if self.memory_used() + data.memory_size() > self.memory_limit {
+                break;
+            }
+
+            self.insert(map_name, data);
+            loaded += 1;
+        }
+
+        Ok(loaded)
+    }
+
+    /// Drops every cached map.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.maps.clear();
+        state.memory_used = 0;
+    }
+
+    fn lookup(&self, key: &str) -> Option<Arc<GatData>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        let map = state.maps.get_mut(key)?;
+        map.last_used = clock;
+        Some(map.data.clone())
+    }
+
+    fn insert(&self, key: String, data: Arc<GatData>) -> Arc<GatData> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+
+        if let Some(map) = state.maps.get_mut(&key) {
+            map.last_used = clock;
+            return map.data.clone();
+        }
+
+        let size = data.memory_size();
+        state.memory_used += size;
+        state.maps.insert(
+            key.clone(),
+            CachedMap {
+                data: data.clone(),
+                size,
+                last_used: clock,
+            },
+        );
+
+        // evict least recently used maps, never the one we just loaded
+        while state.memory_used > self.memory_limit && state.maps.len() > 1 {
+            let oldest = state
+                .maps
+                .iter()
+                .filter(|(name, _)| **name != key)
+                .min_by_key(|(_, map)| map.last_used)
+                .map(|(name, _)| name.clone())
+                .expect("cache has more than one map");
+
+            if let Some(map) = state.maps.remove(&oldest) {
+                state.memory_used -= map.size;
+            }
+        }
+
+        data
+    }
+
+    fn load(&self, key: &str) -> Result<GatData, ParseError> {
+        for source in self.sources.iter() {
+            match source {
+                MapSource::Directory(path) => {
+                    let gat_path = path.join(format!("{}.gat", key));
+                    if !gat_path.is_file() {
+                        continue;
+                    }
+
+                    let mut gat_data = GatData::from_bytes(&fs::read(&gat_path)?)?;
+                    let rsw_path = path.join(format!("{}.rsw", key));
+                    if let Ok(rsw_data) = fs::read(rsw_path) {
+                        apply_rsw_water(&mut gat_data, &rsw_data);
+                    }
+                    return Ok(gat_data);
+                }
+                MapSource::Grf(grf) => {
+                    let mut grf = grf.lock().unwrap();
+                    let gat_name = format!("data\\{}.gat", key);
+                    if !grf.contains(&gat_name) {
+                        continue;
+                    }
+
+                    let mut gat_data = GatData::from_bytes(&grf.read(&gat_name)?)?;
+                    if let Ok(rsw_data) = grf.read(&format!("data\\{}.rsw", key)) {
+                        apply_rsw_water(&mut gat_data, &rsw_data);
+                    }
+                    return Ok(gat_data);
+                }
+                MapSource::ServerCache(map_cache) => {
+                    let Some(map) = map_cache.get(key) else {
+                        continue;
+                    };
+
+                    return Ok(GatData::from_cells(
+                        map.width as u32,
+                        map.height as u32,
+                        &map.cells,
+                    ));
+                }
+            }
+        }
+
+        Err(ParseError::EntryNotFound(format!("{}.gat", key)))
+    }
+
+    fn available_maps(&self) -> Result<Vec<String>, ParseError> {
+        let mut map_names = Vec::new();
+
+        for source in self.sources.iter() {
+            match source {
+                MapSource::Directory(path) => {
+                    for entry in fs::read_dir(path)? {
+                        let file_name = entry?.file_name().to_string_lossy().to_lowercase();
+                        if file_name.ends_with(".gat") {
+                            map_names.push(normalize_map_name(&file_name));
+                        }
+                    }
+                }
+                MapSource::Grf(grf) => {
+                    let grf = grf.lock().unwrap();
+                    for entry in grf.entries() {
+                        let name = entry.name.to_lowercase();
+                        if name.ends_with(".gat") {
+                            map_names.push(normalize_map_name(&name));
+                        }
+                    }
+                }
+                MapSource::ServerCache(map_cache) => {
+                    for map in map_cache.maps() {
+                        map_names.push(normalize_map_name(&map.name));
+                    }
+                }
+            }
+        }
+
+        map_names.sort();
+        map_names.dedup();
+        Ok(map_names)
+    }
+}
+
+/// Water cells are not flagged in the gat file itself, the server derives
+/// them from the rsw water level.
+fn apply_rsw_water(gat_data: &mut GatData, rsw_data: &[u8]) {
+    match RswData::from_bytes(rsw_data) {
+        Ok(rsw_data) => {
+            if let Some(water_level) = rsw_data.water_level() {
+                gat_data.apply_water_level(water_level);
+            }
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "failed to parse rsw");
+        }
+    }
+}
+
+/// Sets the cache shared by every session, must be called before the first
+/// session enters a map. Returns false if it was already initialized.
+pub fn init_shared(cache: MapCache) -> bool {
+    SHARED_MAP_CACHE.set(Arc::new(cache)).is_ok()
+}
+
+/// Cache shared by every session, defaults to the `data/gat` directory.
+pub fn shared() -> Arc<MapCache> {
+    SHARED_MAP_CACHE
+        .get_or_init(|| {
+            Arc::new(MapCache::new(MAP_CACHE_DEFAULT_LIMIT).with_directory(MAP_CACHE_DEFAULT_DIR))
+        })
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{
+        gat::{tests::build_gat, CellMap, GatCellType},
+        grf::{tests::build_grf, GRF_FLAG_FILE},
+        mapcache::tests::build_map_cache,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("ragnarok-socket-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn small_gat() -> Vec<u8> {
+        build_gat(&[&[(0.0, 0), (0.0, 1)], &[(0.0, 0), (0.0, 0)]])
+    }
+
+    #[test]
+    fn loads_once_and_shares_the_same_map() {
+        let dir = temp_dir("cache-shared");
+        fs::write(dir.join("prontera.gat"), small_gat()).unwrap();
+        let cache = MapCache::new(1024 * 1024).with_directory(dir.to_str().unwrap());
+        assert!(cache.cached("prontera").is_none());
+
+        let first = cache.get("prontera.gat").unwrap();
+        let second = cache.get("PRONTERA").unwrap();
+
+        assert!(Arc::ptr_eq(&first, &second));
+        assert!(Arc::ptr_eq(&first, &cache.cached("prontera").unwrap()));
+        assert_eq!(cache.len(), 1);
+        assert!(matches!(
+            cache.get("geffen.gat"),
+            Err(ParseError::EntryNotFound(_))
+        ));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn evicts_least_recently_used_maps_over_the_limit() {
+        let dir = temp_dir("cache-evict");
+        for map_name in ["a", "b", "c"] {
+            fs::write(dir.join(format!("{}.gat", map_name)), small_gat()).unwrap();
+        }
+        let map_size = GatData::from_bytes(&small_gat()).unwrap().memory_size();
+        let cache = MapCache::new(map_size * 2).with_directory(dir.to_str().unwrap());
+
+        let a = cache.get("a").unwrap();
+        cache.get("b").unwrap();
+        cache.get("a").unwrap();
+        cache.get("c").unwrap();
+
+        assert!(cache.contains("a"));
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("c"));
+        assert!(cache.memory_used() <= map_size * 2);
+        // evicted maps stay valid for whoever holds them
+        assert_eq!(a.width(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn preloads_from_grf() {
+        let dir = temp_dir("cache-grf");
+        let gat = small_gat();
+        let grf = build_grf(&[
+            ("data\\prontera.gat", &gat, GRF_FLAG_FILE),
+            ("data\\geffen.gat", &gat, GRF_FLAG_FILE),
+            ("data\\readme.txt", b"not a map", GRF_FLAG_FILE),
+        ]);
+        let grf_path = dir.join("data.grf");
+        fs::write(&grf_path, grf).unwrap();
+
+        let cache = MapCache::new(1024 * 1024)
+            .with_grf(grf_path.to_str().unwrap())
+            .unwrap();
+
+        assert_eq!(cache.preload().unwrap(), 2);
+        assert!(cache.contains("geffen"));
+        assert!(cache.contains("prontera.gat"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_server_map_cache() {
+        let dir = temp_dir("cache-server");
+        let map_cache = build_map_cache(&[("prontera", 2, &[0, 1, 5, 3])]);
+        let map_cache_path = dir.join("map_cache.dat");
+        fs::write(&map_cache_path, map_cache).unwrap();
+
+        let cache = MapCache::new(1024 * 1024)
+            .with_server_cache(map_cache_path.to_str().unwrap())
+            .unwrap();
+        let prontera = cache.get("prontera.gat").unwrap();
+
+        assert!(prontera.is_walkable(0, 0));
+        assert!(!prontera.is_walkable(1, 0));
+        assert_eq!(prontera.cell_type(1, 1), Some(GatCellType::Water));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,3 @@
+pub mod cache;
+pub mod movement;
+pub mod path;
@@ -0,0 +1,252 @@
+//! Where units are while they walk, replaying the server walk timer
+//! (rAthena `unit_walktoxy_timer`) from the move packets.
+//!
+//! Everything runs on server ticks, see `protocol::clock::ServerClock` to
+//! get the current one.
+
+use std::collections::HashMap;
+
+use crate::io::gat::CellMap;
+
+use super::path::{path_search, CellCheck, MOVE_COST, MOVE_DIAGONAL_COST};
+
+/// `DEFAULT_WALK_SPEED`: milliseconds per cell, used until the server sends
+/// the unit speed.
+pub static DEFAULT_WALK_SPEED: u16 = 150;
+
+/// A walk started by the server at `start_tick`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Walk {
+    /// Cells walked through, the start and target cells included.
+    cells: Vec<(u16, u16)>,
+    start_tick: u32,
+    speed: u16,
+}
+
+impl Walk {
+    /// Follows the server path on `map`. Without a map, or when the map
+    /// has no path, the unit is assumed to walk straight.
+    pub fn new<M: CellMap>(
+        map: Option<&M>,
+        from: (u16, u16),
+        to: (u16, u16),
+        start_tick: u32,
+        speed: u16,
+    ) -> Walk {
+        let cells = map
+            .and_then(|map| path_search(map, from, to, CellCheck::NoPass))
+            .unwrap_or_else(|| straight_path(from, to));
+
+        Walk {
+            cells,
+            start_tick,
+            speed,
+        }
+    }
+
+    pub fn cells(&self) -> &[(u16, u16)] {
+        &self.cells
+    }
+
+    pub fn target(&self) -> (u16, u16) {
+        *self.cells.last().unwrap()
+    }
+
+    fn step_time(&self, step: usize) -> u32 {
+        let (from, to) = (self.cells[step], self.cells[step + 1]);
+        let cost = if from.0 != to.0 && from.1 != to.1 {
+            MOVE_DIAGONAL_COST
+        } else {
+            MOVE_COST
+        };
+        self.speed as u32 * cost / MOVE_COST
+    }
+
+    /// Server tick the unit reaches its target at.
+    pub fn end_tick(&self) -> u32 {
+        let duration: u32 = (0..self.cells.len() - 1)
+            .map(|step| self.step_time(step))
+            .sum();
+        self.start_tick.wrapping_add(duration)
+    }
+
+    /// Cell the server has the unit on at `server_tick`. A unit enters the
+    /// next cell once the whole step time went by.
+    pub fn cell_at(&self, server_tick: u32) -> (u16, u16) {
+        let elapsed = server_tick.wrapping_sub(self.start_tick) as i32;
+        if elapsed <= 0 {
+            return self.cells[0];
+        }
+
+        let mut elapsed = elapsed as u32;
+        for step in 0..self.cells.len() - 1 {
+            let step_time = self.step_time(step);
+            if elapsed < step_time {
+                return self.cells[step];
+            }
+            elapsed -= step_time;
+        }
+        self.target()
+    }
+}
+
+/// Diagonal first then straight, like the server direct path.
+fn straight_path(from: (u16, u16), to: (u16, u16)) -> Vec<(u16, u16)> {
+    let (mut x, mut y) = (from.0 as i32, from.1 as i32);
+    let mut cells = vec![from];
+    while (x, y) != (to.0 as i32, to.1 as i32) {
+        x += (to.0 as i32 - x).signum();
+        y += (to.1 as i32 - y).signum();
+        cells.push((x as u16, y as u16));
+    }
+    cells
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Unit {
+    pub speed: u16,
+    /// Last cell known for sure, the walk start while walking.
+    pub position: (u16, u16),
+    pub walk: Option<Walk>,
+}
+
+impl Unit {
+    pub fn cell_at(&self, server_tick: u32) -> (u16, u16) {
+        match &self.walk {
+            Some(walk) => walk.cell_at(server_tick),
+            None => self.position,
+        }
+    }
+}
+
+/// Units in sight, by block id (account id for players).
+#[derive(Default)]
+pub struct Units {
+    units: HashMap<u32, Unit>,
+}
+
+impl Units {
+    pub fn get(&self, id: u32) -> Option<&Unit> {
+        self.units.get(&id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.units.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.units.is_empty()
+    }
+
+    /// A unit standing on `position`, replacing what was known about it.
+    pub fn spawn(&mut self, id: u32, position: (u16, u16), speed: u16) {
+        self.units.insert(
+            id,
+            Unit {
+                speed,
+                position,
+                walk: None,
+            },
+        );
+    }
+
+    pub fn set_speed(&mut self, id: u32, speed: u16) {
+        if let Some(unit) = self.units.get_mut(&id) {
+            unit.speed = speed;
+        }
+    }
+
+    /// The server started moving the unit, units not seen yet are added
+    /// with the default speed.
+    pub fn walk<M: CellMap>(
+        &mut self,
+        map: Option<&M>,
+        id: u32,
+        from: (u16, u16),
+        to: (u16, u16),
+        start_tick: u32,
+    ) {
+        let unit = self.units.entry(id).or_insert(Unit {
+            speed: DEFAULT_WALK_SPEED,
+            position: from,
+            walk: None,
+        });
+        unit.position = from;
+        unit.walk = Some(Walk::new(map, from, to, start_tick, unit.speed));
+    }
+
+    /// `StopPos` correction: the unit is on `position` and stands there.
+    pub fn stop(&mut self, id: u32, position: (u16, u16)) {
+        if let Some(unit) = self.units.get_mut(&id) {
+            unit.position = position;
+            unit.walk = None;
+        }
+    }
+
+    pub fn remove(&mut self, id: u32) {
+        self.units.remove(&id);
+    }
+
+    pub fn clear(&mut self) {
+        self.units.clear();
+    }
+
+    /// Cell of the unit at `server_tick`, `None` for units not in sight.
+    pub fn cell_at(&self, id: u32, server_tick: u32) -> Option<(u16, u16)> {
+        self.units.get(&id).map(|unit| unit.cell_at(server_tick))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::gat::GatData;
+
+    fn no_map() -> Option<&'static GatData> {
+        None
+    }
+
+    #[test]
+    fn walk_enters_a_cell_once_the_step_is_done() {
+        // two diagonal steps then one straight, 210 + 210 + 150
+        let walk = Walk::new(no_map(), (10, 10), (13, 12), 1_000, 150);
+        assert_eq!(walk.cells(), &[(10, 10), (11, 11), (12, 12), (13, 12)]);
+        assert_eq!(walk.end_tick(), 1_570);
+
+        assert_eq!(walk.cell_at(500), (10, 10));
+        assert_eq!(walk.cell_at(1_209), (10, 10));
+        assert_eq!(walk.cell_at(1_210), (11, 11));
+        assert_eq!(walk.cell_at(1_569), (12, 12));
+        assert_eq!(walk.cell_at(9_000), (13, 12));
+    }
+
+    #[test]
+    fn walk_follows_the_map_path() {
+        // 5x5 map with a wall on x = 2, except on y = 3
+        let mut cells = vec![0u8; 25];
+        for y in 0..3 {
+            cells[y * 5 + 2] = 1;
+        }
+        let map = GatData::from_cells(5, 5, &cells);
+
+        let walk = Walk::new(Some(&map), (1, 1), (3, 1), 0, 100);
+        assert_eq!(walk.target(), (3, 1));
+        assert!(walk.cells().contains(&(2, 3)));
+    }
+
+    #[test]
+    fn stop_pos_corrects_a_walking_unit() {
+        let mut units = Units::default();
+        units.spawn(7, (50, 50), 200);
+        units.walk(no_map(), 7, (50, 50), (55, 50), 0);
+        assert_eq!(units.cell_at(7, 400), Some((52, 50)));
+
+        units.stop(7, (51, 50));
+        assert_eq!(units.cell_at(7, 5_000), Some((51, 50)));
+
+        // never seen before, walks at the default speed
+        units.walk(no_map(), 8, (0, 0), (0, 3), 0);
+        assert_eq!(units.cell_at(8, 300), Some((0, 2)));
+        assert_eq!(units.cell_at(9, 300), None);
+    }
+}
@@ -0,0 +1,412 @@
+//! Range and line-of-sight checks, matching the server formulas
+//! (rAthena `path.cpp` and `battle_check_range`).
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+};
+
+use crate::io::gat::CellMap;
+
+/// Default `area_size` battle config, how far units can see each other.
+pub static AREA_SIZE: i32 = 14;
+
+/// Cell checks used by the server path functions.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CellCheck {
+    /// `CELL_CHKWALL`: blocks only cells that can't be walked nor shot through.
+    Wall,
+    /// `CELL_CHKNOPASS`: blocks every non-walkable cell, snipeable gaps included.
+    NoPass,
+}
+
+/// `map_getcellp`: out of bounds cells (the last row and column included)
+/// only block `NoPass` checks.
+fn is_blocked(map: &impl CellMap, x: i32, y: i32, check: CellCheck) -> bool {
+    if x < 0 || y < 0 || x >= map.width() as i32 - 1 || y >= map.height() as i32 - 1 {
+        return check == CellCheck::NoPass;
+    }
+
+    match check {
+        CellCheck::Wall => {
+            !map.is_walkable(x as u16, y as u16) && !map.is_shootable(x as u16, y as u16)
+        }
+        CellCheck::NoPass => !map.is_walkable(x as u16, y as u16),
+    }
+}
+
+/// Square distance used by the server for ranges and areas.
+pub fn distance(dx: i32, dy: i32) -> i32 {
+    dx.abs().max(dy.abs())
+}
+
+pub fn check_distance(dx: i32, dy: i32, range: i32) -> bool {
+    distance(dx, dy) <= range
+}
+
+/// Circular distance used by the client, which is also used by the server
+/// for player attacks and skills.
+pub fn distance_client(dx: i32, dy: i32) -> i32 {
+    let distance = ((dx * dx + dy * dy) as f64).sqrt();
+    // bonus factor used by the client, makes straight lines one cell longer
+    (distance - 0.1).max(0.0) as i32
+}
+
+pub fn check_distance_client(dx: i32, dy: i32, range: i32) -> bool {
+    distance_client(dx, dy) <= range.max(0)
+}
+
+/// `path_search_long`: walks a straight line between both cells and fails on
+/// the first blocked cell. The walk always goes left to right, so the result
+/// is the same whatever end the check starts from.
+pub fn path_search_long(
+    map: &impl CellMap,
+    from: (u16, u16),
+    to: (u16, u16),
+    check: CellCheck,
+) -> bool {
+    let (mut x0, mut y0) = (from.0 as i32, from.1 as i32);
+    let (mut x1, mut y1) = (to.0 as i32, to.1 as i32);
+
+    let mut dx = x1 - x0;
+    if dx < 0 {
+        std::mem::swap(&mut x0, &mut x1);
+        std::mem::swap(&mut y0, &mut y1);
+        dx = -dx;
+    }
+    let dy = y1 - y0;
+
+    if is_blocked(map, x1, y1, check) {
+        return false;
+    }
+
+    let weight = if dx > dy.abs() { dx } else { dy.abs() };
+    let (mut wx, mut wy) = (0, 0);
+
+    while x0 != x1 || y0 != y1 {
+        if is_blocked(map, x0, y0, check) {
+            return false;
+        }
+
+        wx += dx;
+        wy += dy;
+        if wx >= weight {
+            wx -= weight;
+            x0 += 1;
+        }
+        if wy >= weight {
+            wy -= weight;
+            y0 += 1;
+        } else if wy < 0 {
+            wy += weight;
+            y0 -= 1;
+        }
+    }
+
+    true
+}
+
+/// Whether the server considers a cell in sight from another, snipeable
+/// gaps don't block the view.
+pub fn has_line_of_sight(map: &impl CellMap, from: (u16, u16), to: (u16, u16)) -> bool {
+    path_search_long(map, from, to, CellCheck::Wall)
+}
+
+/// `battle_check_range` for a player: circular range check, then line of
+/// sight unless the target is adjacent. `range` is the attack range sent in
+/// `AtkRange` or the skill range from the skill tree.
+pub fn check_range(map: &impl CellMap, from: (u16, u16), to: (u16, u16), range: i32) -> bool {
+    let dx = to.0 as i32 - from.0 as i32;
+    let dy = to.1 as i32 - from.1 as i32;
+
+    if !check_distance_client(dx, dy, range) {
+        return false;
+    }
+
+    let distance = distance(dx, dy);
+    if distance < 2 {
+        // no need for path checking
+        return true;
+    }
+    if distance > AREA_SIZE {
+        // out of sight
+        return false;
+    }
+
+    has_line_of_sight(map, from, to)
+}
+
+/// `MAX_WALKPATH`: the server refuses longer walks.
+pub static MAX_WALKPATH: usize = 32;
+/// Path costs of a straight and a diagonal step, also the ratio between
+/// their walk times.
+pub static MOVE_COST: u32 = 10;
+pub static MOVE_DIAGONAL_COST: u32 = 14;
+
+/// Neighbours in the order the server looks at them, `(dx, dy)`.
+static NEIGHBOURS: [(i32, i32); 8] = [
+    (1, -1),
+    (1, 0),
+    (1, 1),
+    (0, 1),
+    (-1, 1),
+    (-1, 0),
+    (-1, -1),
+    (0, -1),
+];
+
+/// `path_search`: the cells a unit walks through from `from` to `to`, both
+/// included. Tries the straight walk first, diagonal then straight, and
+/// falls back to an A* search where diagonals can't cut corners. `None`
+/// when the target is blocked or further than `MAX_WALKPATH` steps. The
+/// start cell is not checked, like on the server, so a unit standing on a
+/// blocked cell can still walk off it.
+pub fn path_search(
+    map: &impl CellMap,
+    from: (u16, u16),
+    to: (u16, u16),
+    check: CellCheck,
+) -> Option<Vec<(u16, u16)>> {
+    let (x0, y0) = (from.0 as i32, from.1 as i32);
+    let (x1, y1) = (to.0 as i32, to.1 as i32);
+
+    if is_blocked(map, x1, y1, check) {
+        return None;
+    }
+    if from == to {
+        return Some(vec![from]);
+    }
+
+    direct_path(map, (x0, y0), (x1, y1), check)
+        .or_else(|| astar_path(map, (x0, y0), (x1, y1), check))
+}
+
+fn direct_path(
+    map: &impl CellMap,
+    from: (i32, i32),
+    to: (i32, i32),
+    check: CellCheck,
+) -> Option<Vec<(u16, u16)>> {
+    let (mut x, mut y) = from;
+    let mut path = vec![(x as u16, y as u16)];
+
+    while (x, y) != to {
+        if path.len() > MAX_WALKPATH {
+            return None;
+        }
+        x += (to.0 - x).signum();
+        y += (to.1 - y).signum();
+        // the target was checked already
+        if (x, y) != to && is_blocked(map, x, y, check) {
+            return None;
+        }
+        path.push((x as u16, y as u16));
+    }
+
+    Some(path)
+}
+
+fn astar_path(
+    map: &impl CellMap,
+    from: (i32, i32),
+    to: (i32, i32),
+    check: CellCheck,
+) -> Option<Vec<(u16, u16)>> {
+    struct Node {
+        g_cost: u32,
+        steps: usize,
+        parent: Option<(i32, i32)>,
+        closed: bool,
+    }
+
+    let heuristic =
+        |(x, y): (i32, i32)| MOVE_COST * ((to.0 - x).unsigned_abs() + (to.1 - y).unsigned_abs());
+
+    let mut nodes: HashMap<(i32, i32), Node> = HashMap::new();
+    // ties go to the node opened first
+    let mut open = BinaryHeap::new();
+    let mut opened: u32 = 0;
+
+    nodes.insert(
+        from,
+        Node {
+            g_cost: 0,
+            steps: 0,
+            parent: None,
+            closed: false,
+        },
+    );
+    open.push(Reverse((heuristic(from), opened, from)));
+
+    while let Some(Reverse((_, _, current))) = open.pop() {
+        let node = nodes.get_mut(&current).unwrap();
+        if node.closed {
+            continue;
+        }
+        node.closed = true;
+
+        if current == to {
+            let mut path = vec![(to.0 as u16, to.1 as u16)];
+            let mut cell = current;
+            while let Some(parent) = nodes[&cell].parent {
+                path.push((parent.0 as u16, parent.1 as u16));
+                cell = parent;
+            }
+            path.reverse();
+            return Some(path);
+        }
+
+        let (g_cost, steps) = (node.g_cost, node.steps);
+        if steps >= MAX_WALKPATH {
+            continue;
+        }
+
+        let (x, y) = current;
+        for (dx, dy) in NEIGHBOURS {
+            let next = (x + dx, y + dy);
+            if is_blocked(map, next.0, next.1, check) {
+                continue;
+            }
+            let diagonal = dx != 0 && dy != 0;
+            if diagonal && (is_blocked(map, x + dx, y, check) || is_blocked(map, x, y + dy, check))
+            {
+                continue;
+            }
+
+            let cost = g_cost
+                + if diagonal {
+                    MOVE_DIAGONAL_COST
+                } else {
+                    MOVE_COST
+                };
+            match nodes.get(&next) {
+                Some(known) if known.closed || known.g_cost <= cost => continue,
+                _ => {}
+            }
+            nodes.insert(
+                next,
+                Node {
+                    g_cost: cost,
+                    steps: steps + 1,
+                    parent: Some(current),
+                    closed: false,
+                },
+            );
+            opened += 1;
+            open.push(Reverse((cost + heuristic(next), opened, next)));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::gat::GatCellType;
+
+    struct TestMap {
+        rows: Vec<&'static str>,
+    }
+
+    /// `.` walkable, `#` wall, `~` snipeable gap. First row is y = 0.
+    impl CellMap for TestMap {
+        fn width(&self) -> u32 {
+            self.rows[0].len() as u32
+        }
+
+        fn height(&self) -> u32 {
+            self.rows.len() as u32
+        }
+
+        fn cell_type(&self, x: u16, y: u16) -> Option<GatCellType> {
+            match self.rows.get(y as usize)?.as_bytes().get(x as usize)? {
+                b'#' => Some(GatCellType::NonWalkable),
+                b'~' => Some(GatCellType::Snipeable),
+                _ => Some(GatCellType::Walkable),
+            }
+        }
+    }
+
+    fn test_map() -> TestMap {
+        TestMap {
+            rows: vec![
+                "..........",
+                "....#.....",
+                "....~.....",
+                "..........",
+                "..........",
+            ],
+        }
+    }
+
+    #[test]
+    fn distances_match_server_formulas() {
+        assert_eq!(distance(3, -5), 5);
+        assert!(check_distance(-2, 2, 2));
+        assert_eq!(distance_client(1, 0), 0);
+        assert_eq!(distance_client(3, 4), 4);
+        assert!(check_distance_client(4, 4, 5));
+        assert!(!check_distance_client(5, 5, 5));
+    }
+
+    #[test]
+    fn walls_block_sight_but_gaps_do_not() {
+        let map = test_map();
+
+        assert!(!has_line_of_sight(&map, (2, 1), (7, 1)));
+        assert!(!has_line_of_sight(&map, (7, 1), (2, 1)));
+        assert!(has_line_of_sight(&map, (2, 2), (7, 2)));
+        assert!(!path_search_long(&map, (2, 2), (7, 2), CellCheck::NoPass));
+        assert!(!has_line_of_sight(&map, (2, 0), (7, 3)));
+        assert!(has_line_of_sight(&map, (5, 0), (8, 3)));
+    }
+
+    #[test]
+    fn last_row_and_column_are_out_of_bounds() {
+        let map = test_map();
+
+        assert!(has_line_of_sight(&map, (0, 4), (9, 4)));
+        assert!(!path_search_long(&map, (0, 3), (9, 3), CellCheck::NoPass));
+    }
+
+    #[test]
+    fn range_check_uses_client_distance_and_sight() {
+        let map = test_map();
+
+        assert!(check_range(&map, (3, 1), (4, 1), 1));
+        assert!(!check_range(&map, (3, 1), (5, 1), 2));
+        assert!(!check_range(&map, (2, 1), (6, 1), 9));
+        assert!(check_range(&map, (2, 2), (6, 2), 9));
+        assert!(!check_range(&map, (0, 0), (9, 3), 5));
+    }
+
+    #[test]
+    fn path_search_walks_straight_or_around_walls() {
+        let map = test_map();
+
+        assert_eq!(
+            path_search(&map, (0, 0), (3, 2), CellCheck::NoPass),
+            Some(vec![(0, 0), (1, 1), (2, 2), (3, 2)])
+        );
+
+        // (4, 1) and (4, 2) block the straight walk, the way around the top
+        // can't cut the corner of (4, 1)
+        assert_eq!(
+            path_search(&map, (2, 1), (6, 1), CellCheck::NoPass),
+            Some(vec![(2, 1), (3, 0), (4, 0), (5, 0), (6, 1)])
+        );
+
+        assert_eq!(path_search(&map, (2, 1), (4, 1), CellCheck::NoPass), None);
+    }
+
+    #[test]
+    fn path_search_walks_off_a_blocked_start_cell() {
+        let map = test_map();
+
+        assert_eq!(
+            path_search(&map, (4, 1), (6, 1), CellCheck::NoPass),
+            Some(vec![(4, 1), (5, 1), (6, 1)])
+        );
+    }
+}
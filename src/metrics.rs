@@ -0,0 +1,424 @@
+//! Per-session counters and latencies, kept in an in-process registry and
+//! optionally served in the Prometheus text format.
+//!
+//! Packets are counted where they are recorded, see `recorder::record`,
+//! against the metrics of the session the task runs for. Stages spawned by
+//! a session carry its metrics with `in_current_session`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex, OnceLock, Weak},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info};
+
+use crate::{
+    capture::{Direction, Stage},
+    protocol::{
+        character_list::CharListClient,
+        game::{GameClient, GameServer},
+        login::{LoginClient, LoginServer},
+    },
+};
+
+static REGISTRY: OnceLock<MetricsRegistry> = OnceLock::new();
+
+type Latency = fn(&SessionCounters) -> Option<Duration>;
+
+tokio::task_local! {
+    static SESSION_METRICS: Arc<SessionMetrics>;
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct PacketCount {
+    pub packets: u64,
+    pub bytes: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct SessionCounters {
+    pub packets: HashMap<(Stage, Direction, u16), PacketCount>,
+    /// From `REQAUTH`, `ReqAuthHashed` or `SsoLogin` sent to login `AuthOk`.
+    pub login: Option<Duration>,
+    /// From char select sent to map `AuthOk`.
+    pub map_load: Option<Duration>,
+    /// Last `ClientTick` round-trip.
+    pub tick_rtt: Option<Duration>,
+    pub tick_rtt_sum: Duration,
+    pub tick_rtt_count: u64,
+
+    req_auth_sent: Option<Instant>,
+    char_select_sent: Option<Instant>,
+    tick_sent: Option<Instant>,
+}
+
+pub struct SessionMetrics {
+    /// Session id, the same one packets are recorded under, see
+    /// `recorder::scope`. Tells apart sessions of the same account.
+    pub id: u32,
+    pub account: String,
+    counters: Mutex<SessionCounters>,
+}
+
+impl SessionMetrics {
+    pub fn new(id: u32, account: &str) -> SessionMetrics {
+        SessionMetrics {
+            id,
+            account: account.to_string(),
+            counters: Mutex::new(SessionCounters::default()),
+        }
+    }
+
+    pub fn snapshot(&self) -> SessionCounters {
+        self.counters.lock().unwrap().clone()
+    }
+
+    pub fn record_packet(&self, stage: Stage, direction: Direction, data: &[u8]) {
+        let packet_id = match data {
+            [low, high, ..] => u16::from_le_bytes([*low, *high]),
+            _ => return,
+        };
+        let now = Instant::now();
+
+        let mut counters = self.counters.lock().unwrap();
+        let count = counters
+            .packets
+            .entry((stage, direction, packet_id))
+            .or_default();
+        count.packets += 1;
+        count.bytes += data.len() as u64;
+
+        match (stage, direction) {
+            // whichever login mode the session uses
+            (Stage::Login, Direction::Sent)
+                if packet_id == LoginClient::REQAUTH as u16
+                    || packet_id == LoginClient::ReqAuthHashed as u16
+                    || packet_id == LoginClient::SsoLogin as u16 =>
+            {
+                counters.req_auth_sent = Some(now);
+            }
+            (Stage::Login, Direction::Received) if packet_id == LoginServer::AuthOk as u16 => {
+                if let Some(sent) = counters.req_auth_sent.take() {
+                    counters.login = Some(now - sent);
+                }
+            }
+            (Stage::Char, Direction::Sent) if packet_id == CharListClient::CharSelect as u16 => {
+                counters.char_select_sent = Some(now);
+            }
+            (Stage::Map, Direction::Received) if packet_id == GameServer::AuthOk as u16 => {
+                if let Some(sent) = counters.char_select_sent.take() {
+                    counters.map_load = Some(now - sent);
+                }
+            }
+            (Stage::Map, Direction::Sent) if packet_id == GameClient::ClientTick as u16 => {
+                counters.tick_sent = Some(now);
+            }
+            (Stage::Map, Direction::Received) if packet_id == GameServer::NotifyTime as u16 => {
+                if let Some(sent) = counters.tick_sent.take() {
+                    let rtt = now - sent;
+                    counters.tick_rtt = Some(rtt);
+                    counters.tick_rtt_sum += rtt;
+                    counters.tick_rtt_count += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Metrics of the sessions still running. The session task holds the only
+/// strong reference, so a session leaves the registry when its task ends,
+/// aborted or not.
+#[derive(Default)]
+pub struct MetricsRegistry {
+    sessions: Mutex<Vec<Weak<SessionMetrics>>>,
+}
+
+impl MetricsRegistry {
+    pub fn register(&self, id: u32, account: &str) -> Arc<SessionMetrics> {
+        let metrics = Arc::new(SessionMetrics::new(id, account));
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|session| session.strong_count() > 0);
+        sessions.push(Arc::downgrade(&metrics));
+        metrics
+    }
+
+    pub fn sessions(&self) -> Vec<Arc<SessionMetrics>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|session| session.strong_count() > 0);
+        sessions.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /// Prometheus text exposition format, version 0.0.4. Every series is
+    /// labelled with the session id and account.
+    pub fn render_prometheus(&self) -> String {
+        let sessions = self.sessions();
+        let counters: Vec<(String, SessionCounters)> = sessions
+            .iter()
+            .map(|session| {
+                let labels = format!(
+                    "session=\"{}\",account=\"{}\"",
+                    session.id,
+                    escape(&session.account)
+                );
+                (labels, session.snapshot())
+            })
+            .collect();
+
+        let mut out = String::new();
+        let mut packets: BTreeMap<String, PacketCount> = BTreeMap::new();
+        for (session, counters) in &counters {
+            for ((stage, direction, packet_id), count) in &counters.packets {
+                let direction = match direction {
+                    Direction::Sent => "out",
+                    Direction::Received => "in",
+                };
+                let labels = format!(
+                    "{},stage=\"{}\",direction=\"{}\",packet_id=\"0x{:04X}\"",
+                    session,
+                    stage.name(),
+                    direction,
+                    packet_id
+                );
+                let total = packets.entry(labels).or_default();
+                total.packets += count.packets;
+                total.bytes += count.bytes;
+            }
+        }
+
+        out.push_str("# TYPE ro_packets_total counter\n");
+        for (labels, count) in &packets {
+            let _ = writeln!(out, "ro_packets_total{{{}}} {}", labels, count.packets);
+        }
+        out.push_str("# TYPE ro_bytes_total counter\n");
+        for (labels, count) in &packets {
+            let _ = writeln!(out, "ro_bytes_total{{{}}} {}", labels, count.bytes);
+        }
+
+        let latencies: [(&str, Latency); 3] = [
+            ("ro_login_seconds", |counters| counters.login),
+            ("ro_map_load_seconds", |counters| counters.map_load),
+            ("ro_client_tick_rtt_seconds", |counters| counters.tick_rtt),
+        ];
+        for (name, latency) in latencies {
+            let _ = writeln!(out, "# TYPE {} gauge", name);
+            for (session, counters) in &counters {
+                if let Some(latency) = latency(counters) {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, session, latency.as_secs_f64());
+                }
+            }
+        }
+
+        // the average round-trip, over the scrape interval, is sum / count
+        out.push_str("# TYPE ro_client_tick_rtt_sum_seconds counter\n");
+        for (session, counters) in &counters {
+            let _ = writeln!(
+                out,
+                "ro_client_tick_rtt_sum_seconds{{{}}} {}",
+                session,
+                counters.tick_rtt_sum.as_secs_f64()
+            );
+        }
+        out.push_str("# TYPE ro_client_tick_rtt_count counter\n");
+        for (session, counters) in &counters {
+            let _ = writeln!(
+                out,
+                "ro_client_tick_rtt_count{{{}}} {}",
+                session, counters.tick_rtt_count
+            );
+        }
+
+        out
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub fn registry() -> &'static MetricsRegistry {
+    REGISTRY.get_or_init(MetricsRegistry::default)
+}
+
+/// Metrics of the session the current task runs for.
+pub fn current() -> Option<Arc<SessionMetrics>> {
+    SESSION_METRICS.try_with(|metrics| metrics.clone()).ok()
+}
+
+/// Runs `future` for the session of `metrics`.
+pub async fn scope<F: Future>(metrics: Option<Arc<SessionMetrics>>, future: F) -> F::Output {
+    match metrics {
+        Some(metrics) => SESSION_METRICS.scope(metrics, future).await,
+        None => future.await,
+    }
+}
+
+/// Keeps the session of the current task, for futures about to be spawned.
+pub fn in_current_session<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    scope(current(), future)
+}
+
+/// Counts a packet against the current session, if any.
+pub fn record_packet(stage: Stage, direction: Direction, data: &[u8]) {
+    let _ = SESSION_METRICS.try_with(|metrics| metrics.record_packet(stage, direction, data));
+}
+
+/// Serves `registry()` over HTTP on `addr`, any path. Returns the bound
+/// address. Meant for localhost, there is no authentication.
+pub async fn serve_prometheus(addr: SocketAddr) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let addr = listener.local_addr()?;
+    info!(%addr, "prometheus endpoint started");
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                if let Err(e) = answer_scrape(stream).await {
+                    debug!(error = %e, "metrics scrape failed");
+                }
+            });
+        }
+    });
+
+    Ok(addr)
+}
+
+async fn answer_scrape(mut stream: TcpStream) -> io::Result<()> {
+    // the request itself does not matter, read until its end
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 || request.len() > 16384 {
+            break;
+        }
+        request.extend_from_slice(&buffer[..n]);
+    }
+
+    let body = registry().render_prometheus();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock_server::{MockScript, MockServer, MockStage},
+        protocol::login,
+    };
+
+    #[test]
+    fn measures_latencies_from_packets() {
+        let metrics = SessionMetrics::new(1, "bot1");
+        metrics.record_packet(Stage::Login, Direction::Sent, &[0x64, 0x00, 1, 2]);
+        metrics.record_packet(Stage::Login, Direction::Received, &[0xC4, 0x0A, 0, 0]);
+        metrics.record_packet(Stage::Map, Direction::Received, &[0x7F, 0x00, 1, 0, 0, 0]);
+        metrics.record_packet(Stage::Map, Direction::Sent, &[0x60, 0x03, 1, 0, 0, 0]);
+        metrics.record_packet(Stage::Map, Direction::Received, &[0x7F, 0x00, 1, 0, 0, 0]);
+
+        let counters = metrics.snapshot();
+        assert!(counters.login.is_some());
+        assert!(counters.map_load.is_none());
+
+        // md5 and token logins are timed too
+        for req_auth in [[0xDD, 0x01], [0x25, 0x08]] {
+            let metrics = SessionMetrics::new(2, "bot2");
+            metrics.record_packet(Stage::Login, Direction::Sent, &req_auth);
+            metrics.record_packet(Stage::Login, Direction::Received, &[0xC4, 0x0A, 0, 0]);
+            assert!(metrics.snapshot().login.is_some());
+        }
+        // the first reply had no tick to answer
+        assert_eq!(counters.tick_rtt_count, 1);
+        assert_eq!(
+            counters.packets[&(Stage::Map, Direction::Received, 0x007F)],
+            PacketCount {
+                packets: 2,
+                bytes: 12
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn session_counters_are_served() {
+        let server = MockServer::start(MockScript::default()).await;
+        login::initialize(&server.login_addr.to_string(), "metrics-bot", "pass").await;
+        server
+            .wait_for(MockStage::Map, 0x007D, Duration::from_secs(5))
+            .await
+            .expect("client never acked the map");
+
+        let session = registry()
+            .sessions()
+            .into_iter()
+            .find(|session| session.account == "metrics-bot")
+            .unwrap();
+        let counters = session.snapshot();
+        assert!(counters.login.is_some());
+        assert!(counters.map_load.is_some());
+        assert_eq!(
+            counters.packets[&(Stage::Char, Direction::Sent, 0x0066)],
+            PacketCount {
+                packets: 1,
+                bytes: 3
+            }
+        );
+
+        let addr = serve_prometheus("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let labels = format!("session=\"{}\",account=\"metrics-bot\"", session.id);
+        assert!(response.contains(&format!(
+            "ro_packets_total{{{},stage=\"map\",direction=\"in\",packet_id=\"0x02EB\"}} 1\n",
+            labels
+        )));
+        assert!(response.contains(&format!("ro_map_load_seconds{{{}}} ", labels)));
+    }
+
+    #[test]
+    fn sessions_are_labelled_apart_and_leave_when_done() {
+        let registry = MetricsRegistry::default();
+        let first = registry.register(1, "bot1");
+        let second = registry.register(2, "bot1");
+        first.record_packet(Stage::Char, Direction::Sent, &[0x66, 0x00, 0x00]);
+        second.record_packet(Stage::Char, Direction::Sent, &[0x66, 0x00, 0x00]);
+
+        let out = registry.render_prometheus();
+        for id in [1, 2] {
+            assert!(out.contains(&format!(
+                "ro_packets_total{{session=\"{}\",account=\"bot1\",stage=\"char\",direction=\"out\",packet_id=\"0x0066\"}} 1\n",
+                id
+            )));
+        }
+
+        drop(first);
+        assert_eq!(registry.sessions().len(), 1);
+        assert!(!registry.render_prometheus().contains("session=\"1\""));
+    }
+}
@@ -0,0 +1,561 @@
+//! Scriptable in-process login/char/map server, used to test the protocol
+//! stack without a running emulator.
+//!
+//! The three servers listen on random localhost ports and answer the client
+//! packets with the same sequence rAthena uses, so `login::initialize` can be
+//! driven all the way to the map server. Every packet received from the
+//! client is recorded and can be awaited from the test.
+//!
+//! `start_session` and the event helpers are the fixture shared by the
+//! session tests of every stage.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::Notify,
+};
+
+use crate::protocol::{
+    login::{self, SessionOptions},
+    session::{Session, SessionEvent},
+};
+
+/// How long the event helpers wait, a stuck session fails the test instead
+/// of hanging it.
+pub static EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum MockStage {
+    Login,
+    Char,
+    Map,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReceivedPacket {
+    pub stage: MockStage,
+    pub packet_id: u16,
+    /// Whole packet, header included.
+    pub data: Vec<u8>,
+}
+
+/// What the mock answers, every field has a sensible default.
+#[derive(Clone)]
+pub struct MockScript {
+    pub account_id: u32,
+    pub login_id: u32,
+    pub login_id_2: u32,
+    pub sex: u8,
+    pub server_name: String,
+    pub char_id: u32,
+    pub char_name: String,
+    pub map_name: String,
+    pub position: (u16, u16, u8),
+    /// Server tick when the mock starts, it runs in real time from there.
+    pub server_tick: u32,
+    /// Key sent for MD5 logins.
+    pub hash_key: Vec<u8>,
+    /// Replaces the default answer to a client packet. Packets are sent as
+    /// is, so they must be complete (header and length included).
+    pub overrides: HashMap<(MockStage, u16), Vec<Vec<u8>>>,
+    /// Client packets the server closes the connection on, instead of
+    /// answering.
+    pub closes: HashSet<(MockStage, u16)>,
+}
+
+impl MockScript {
+    /// Answers `packet_id` with `packets` instead of the default answer.
+    pub fn on(mut self, stage: MockStage, packet_id: u16, packets: Vec<Vec<u8>>) -> MockScript {
+        self.overrides.insert((stage, packet_id), packets);
+        self
+    }
+
+    /// Closes the connection when the client sends `packet_id`.
+    pub fn close_on(mut self, stage: MockStage, packet_id: u16) -> MockScript {
+        self.closes.insert((stage, packet_id));
+        self
+    }
+}
+
+impl Default for MockScript {
+    fn default() -> Self {
+        MockScript {
+            account_id: 2000001,
+            login_id: 0x11223344,
+            login_id_2: 0x55667788,
+            sex: 1,
+            server_name: "MockRO".to_string(),
+            char_id: 150001,
+            char_name: "MockChar".to_string(),
+            map_name: "prontera.gat".to_string(),
+            position: (156, 191, 4),
+            server_tick: 7_000_000,
+            hash_key: b"mockkey123456789".to_vec(),
+            overrides: HashMap::new(),
+            closes: HashSet::new(),
+        }
+    }
+}
+
+/// Builds a server packet, the length field is filled by `finish_var`.
+pub struct PacketWriter {
+    pub data: Vec<u8>,
+}
+
+impl PacketWriter {
+    pub fn new(packet_id: u16) -> PacketWriter {
+        PacketWriter {
+            data: packet_id.to_le_bytes().to_vec(),
+        }
+    }
+
+    /// Packet with a length field after the id.
+    pub fn new_var(packet_id: u16) -> PacketWriter {
+        let mut writer = PacketWriter::new(packet_id);
+        writer.u16(0);
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.data.push(value);
+        self
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.data.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.data.extend_from_slice(value);
+        self
+    }
+
+    pub fn zeros(&mut self, count: usize) -> &mut Self {
+        self.data.resize(self.data.len() + count, 0);
+        self
+    }
+
+    /// Fixed size string, padded with null bytes.
+    pub fn string(&mut self, value: &str, len: usize) -> &mut Self {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.resize(len, 0);
+        self.bytes(&bytes)
+    }
+
+    pub fn pos(&mut self, x: u16, y: u16, dir: u8) -> &mut Self {
+        self.u8((x >> 2) as u8);
+        self.u8(((x << 6) | ((y >> 4) & 0x3F)) as u8);
+        self.u8(((y << 4) | (dir as u16 & 0xF)) as u8)
+    }
+
+    /// Walk from `from` to `to`, read back with `read_move_data`.
+    pub fn move_data(&mut self, from: (u16, u16), to: (u16, u16)) -> &mut Self {
+        let ((x0, y0), (x1, y1)) = (from, to);
+        self.u8((x0 >> 2) as u8);
+        self.u8(((x0 << 6) | ((y0 >> 4) & 0x3F)) as u8);
+        self.u8(((y0 << 4) | ((x1 >> 6) & 0xF)) as u8);
+        self.u8(((x1 << 2) | ((y1 >> 8) & 0x3)) as u8);
+        self.u8(y1 as u8);
+        // sub-cell of both ends, the center
+        self.u8(0x88)
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.data.clone()
+    }
+
+    pub fn finish_var(&mut self) -> Vec<u8> {
+        let len = (self.data.len() as u16).to_le_bytes();
+        self.data[2] = len[0];
+        self.data[3] = len[1];
+        self.data.clone()
+    }
+}
+
+/// Length of the packets the client sends, `None` when the length follows
+/// the packet id.
+fn client_packet_len(stage: MockStage, packet_id: u16) -> Option<Option<usize>> {
+    let len = match (stage, packet_id) {
+        (MockStage::Login, 0x0204) => Some(18),
+        (MockStage::Login, 0x0064) => Some(55),
+        (MockStage::Login, 0x01DB) => Some(2),
+        (MockStage::Login, 0x01DD) => Some(47),
+        (MockStage::Login, 0x0825) => None,
+        (MockStage::Char, 0x0065) => Some(17),
+        (MockStage::Char, 0x09A1) => Some(2),
+        (MockStage::Char, 0x0066) => Some(3),
+        // the tick is 4 bytes (rAthena length 19)
+        (MockStage::Map, 0x0436) => Some(19),
+        (MockStage::Map, 0x035F) => Some(5),
+        (MockStage::Map, 0x0437) => Some(7),
+        (MockStage::Map, 0x021D) => Some(6),
+        (MockStage::Map, 0x007D) => Some(2),
+        (MockStage::Map, 0x0360) => Some(6),
+        (MockStage::Map, 0x0361) => Some(5),
+        (MockStage::Map, 0x00F3) => None,
+        (MockStage::Map, 0x0368) => Some(6),
+        (MockStage::Map, 0x00B2) => Some(3),
+        (MockStage::Map, 0x018A) => Some(4),
+        _ => return None,
+    };
+    Some(len)
+}
+
+struct MockState {
+    script: MockScript,
+    char_addr: SocketAddr,
+    map_addr: SocketAddr,
+    started: Instant,
+    received: Mutex<Vec<ReceivedPacket>>,
+    notify: Notify,
+}
+
+pub struct MockServer {
+    pub login_addr: SocketAddr,
+    pub char_addr: SocketAddr,
+    pub map_addr: SocketAddr,
+    state: Arc<MockState>,
+}
+
+impl MockServer {
+    pub async fn start(script: MockScript) -> MockServer {
+        let login_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let char_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let map_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let state = Arc::new(MockState {
+            script,
+            char_addr: char_listener.local_addr().unwrap(),
+            map_addr: map_listener.local_addr().unwrap(),
+            started: Instant::now(),
+            received: Mutex::new(Vec::new()),
+            notify: Notify::new(),
+        });
+
+        let server = MockServer {
+            login_addr: login_listener.local_addr().unwrap(),
+            char_addr: state.char_addr,
+            map_addr: state.map_addr,
+            state: state.clone(),
+        };
+
+        for (stage, listener) in [
+            (MockStage::Login, login_listener),
+            (MockStage::Char, char_listener),
+            (MockStage::Map, map_listener),
+        ] {
+            let state = state.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        handle_connection(state, stage, stream).await;
+                    });
+                }
+            });
+        }
+
+        server
+    }
+
+    /// Every packet received so far, in order.
+    pub fn received(&self) -> Vec<ReceivedPacket> {
+        self.state.received.lock().unwrap().clone()
+    }
+
+    /// Waits until the client sends `packet_id` on `stage`.
+    pub async fn wait_for(
+        &self,
+        stage: MockStage,
+        packet_id: u16,
+        timeout: Duration,
+    ) -> Option<ReceivedPacket> {
+        tokio::time::timeout(timeout, async {
+            loop {
+                let notified = self.state.notify.notified();
+                let found = self
+                    .received()
+                    .into_iter()
+                    .find(|packet| packet.stage == stage && packet.packet_id == packet_id);
+                if found.is_some() {
+                    return found;
+                }
+                notified.await;
+            }
+        })
+        .await
+        .ok()
+        .flatten()
+    }
+
+    /// How many times the client sent `packet_id` on `stage`.
+    pub fn count(&self, stage: MockStage, packet_id: u16) -> usize {
+        self.received()
+            .iter()
+            .filter(|packet| packet.stage == stage && packet.packet_id == packet_id)
+            .count()
+    }
+}
+
+/// Starts a mock server answering with `script`, and a session logging in
+/// to it as `mock`.
+pub async fn start_session(
+    script: MockScript,
+    password: &str,
+    options: SessionOptions,
+) -> (MockServer, Session) {
+    let server = MockServer::start(script).await;
+    let session = login::start_with(&server.login_addr.to_string(), "mock", password, options);
+    (server, session)
+}
+
+/// Next event of `session`, `None` once it ended. Panics after
+/// `EVENT_TIMEOUT`.
+pub async fn next_event(session: &mut Session) -> Option<SessionEvent> {
+    tokio::time::timeout(EVENT_TIMEOUT, session.next_event())
+        .await
+        .expect("no session event in time")
+}
+
+/// Skips events until one matches `wanted`, which is returned.
+pub async fn wait_for_event(
+    session: &mut Session,
+    wanted: impl Fn(&SessionEvent) -> bool,
+) -> SessionEvent {
+    loop {
+        let event = next_event(session).await.expect("session ended");
+        if wanted(&event) {
+            return event;
+        }
+    }
+}
+
+/// Every event until the session ends.
+pub async fn collect_events(session: &mut Session) -> Vec<SessionEvent> {
+    let mut events = Vec::new();
+    while let Some(event) = next_event(session).await {
+        events.push(event);
+    }
+    events
+}
+
+async fn read_client_packet(stage: MockStage, stream: &mut TcpStream) -> Option<ReceivedPacket> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await.ok()?;
+    let packet_id = u16::from_le_bytes(header);
+
+    let mut data = header.to_vec();
+    let len = match client_packet_len(stage, packet_id) {
+        Some(Some(len)) => len,
+        Some(None) => {
+            let mut len = [0u8; 2];
+            stream.read_exact(&mut len).await.ok()?;
+            data.extend_from_slice(&len);
+            u16::from_le_bytes(len) as usize
+        }
+        None => panic!("[mock_server] unknown {:?} packet {:04x}", stage, packet_id),
+    };
+
+    let mut body = vec![0u8; len - data.len()];
+    stream.read_exact(&mut body).await.ok()?;
+    data.extend(body);
+
+    Some(ReceivedPacket {
+        stage,
+        packet_id,
+        data,
+    })
+}
+
+async fn handle_connection(state: Arc<MockState>, stage: MockStage, mut stream: TcpStream) {
+    while let Some(packet) = read_client_packet(stage, &mut stream).await {
+        let close = state.script.closes.contains(&(stage, packet.packet_id));
+        let responses = match state.script.overrides.get(&(stage, packet.packet_id)) {
+            Some(packets) => packets.clone(),
+            None => default_responses(&state, &packet),
+        };
+
+        state.received.lock().unwrap().push(packet);
+        state.notify.notify_waiters();
+        if close {
+            return;
+        }
+
+        for response in responses {
+            if stream.write_all(&response).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn ip_bytes(addr: &SocketAddr) -> [u8; 4] {
+    match addr {
+        SocketAddr::V4(addr) => addr.ip().octets(),
+        SocketAddr::V6(_) => [127, 0, 0, 1],
+    }
+}
+
+fn char_info(script: &MockScript) -> Vec<u8> {
+    let mut writer = PacketWriter { data: Vec::new() };
+    writer
+        .u32(script.char_id)
+        .u64(0) // exp
+        .u32(1000) // zeny
+        .u64(0) // job exp
+        .u32(1) // job level
+        .zeros(4 * 5) // body, health, effect state, virtue, honor
+        .u16(0) // job point
+        .u64(40)
+        .u64(40)
+        .u64(11)
+        .u64(11)
+        .u16(150) // speed
+        .u16(0) // job
+        .u16(1) // head
+        .zeros(2 * 10) // body .. body palette
+        .string(&script.char_name, 24)
+        .bytes(&[1, 1, 1, 1, 1, 1])
+        .u8(0) // slot
+        .u8(0) // hair color
+        .u16(1) // rename
+        .string(&script.map_name, 16)
+        .zeros(4 * 4)
+        .u8(script.sex);
+    writer.finish()
+}
+
+/// The answers rAthena gives to each client packet of the handshake.
+fn default_responses(state: &MockState, packet: &ReceivedPacket) -> Vec<Vec<u8>> {
+    let script = &state.script;
+
+    match (packet.stage, packet.packet_id) {
+        (MockStage::Login, 0x01DB) => {
+            let mut ack_hash = PacketWriter::new_var(0x01DC);
+            ack_hash.bytes(&script.hash_key);
+            vec![ack_hash.finish_var()]
+        }
+        // credentials are not checked, every login mode gets in
+        (MockStage::Login, 0x0064 | 0x01DD | 0x0825) => {
+            let mut auth_ok = PacketWriter::new_var(0x0AC4);
+            auth_ok
+                .u32(script.login_id)
+                .u32(script.account_id)
+                .u32(script.login_id_2)
+                .u32(0) // last ip
+                .zeros(26) // last login
+                .u8(script.sex)
+                .string("0123456789ABCDEF", 17) // web auth token
+                .bytes(&ip_bytes(&state.char_addr))
+                .u16(state.char_addr.port())
+                .string(&script.server_name, 20)
+                .u16(0) // users
+                .u16(0) // type
+                .u16(0) // is new
+                .zeros(128);
+            vec![auth_ok.finish_var()]
+        }
+        (MockStage::Char, 0x0065) => {
+            let mut window_data = PacketWriter::new_var(0x082D);
+            window_data.bytes(&[9, 0, 0, 9, 9]).zeros(20);
+
+            let mut chars_data = PacketWriter::new_var(0x006B);
+            chars_data
+                .bytes(&[9, 9, 0])
+                .zeros(20)
+                .bytes(&char_info(script));
+
+            let mut pin_code_state = PacketWriter::new(0x08B9);
+            pin_code_state.u32(0).u32(script.account_id).u16(0);
+
+            vec![
+                // account id is sent raw, before any packet
+                script.account_id.to_le_bytes().to_vec(),
+                window_data.finish_var(),
+                chars_data.finish_var(),
+                pin_code_state.finish(),
+            ]
+        }
+        (MockStage::Char, 0x09A1) => {
+            let mut char_info_page = PacketWriter::new_var(0x0B72);
+            char_info_page.bytes(&char_info(script));
+            vec![char_info_page.finish_var()]
+        }
+        (MockStage::Char, 0x0066) => {
+            // one character per slot, `char_id` is the first one
+            let slot = packet.data[2] as u32;
+            let mut map_data = PacketWriter::new(0x0AC5);
+            map_data
+                .u32(script.char_id + slot)
+                .string(&script.map_name, 16)
+                .bytes(&ip_bytes(&state.map_addr))
+                .u16(state.map_addr.port())
+                .zeros(128);
+            vec![map_data.finish()]
+        }
+        (MockStage::Map, 0x0436) => {
+            let (x, y, dir) = script.position;
+
+            let mut block_list = PacketWriter::new(0x0283);
+            block_list.u32(script.account_id);
+
+            let mut auth_ok = PacketWriter::new(0x02EB);
+            auth_ok.u32(0).pos(x, y, dir).u8(5).u8(5).u16(0);
+
+            vec![block_list.finish(), auth_ok.finish()]
+        }
+        (MockStage::Map, 0x007D) => {
+            let mut weight_limit = PacketWriter::new(0x0ADE);
+            weight_limit.u32(50);
+
+            vec![weight_limit.finish()]
+        }
+        (MockStage::Map, 0x0368) => {
+            let gid = u32::from_le_bytes(packet.data[2..6].try_into().unwrap());
+            let mut name_ack = PacketWriter::new(0x0A30);
+            name_ack
+                .u32(gid)
+                .string(&script.char_name, 24)
+                .zeros(24 * 3)
+                .u32(0);
+
+            vec![name_ack.finish()]
+        }
+        (MockStage::Map, 0x0360) => {
+            let elapsed = state.started.elapsed().as_millis() as u32;
+            let mut notify_time = PacketWriter::new(0x007F);
+            notify_time.u32(script.server_tick.wrapping_add(elapsed));
+            vec![notify_time.finish()]
+        }
+        // only going back to char select is answered
+        (MockStage::Map, 0x00B2) if packet.data[2] == 1 => {
+            let mut restart_ack = PacketWriter::new(0x00B3);
+            restart_ack.u8(1);
+            vec![restart_ack.finish()]
+        }
+        (MockStage::Map, 0x018A) => {
+            let mut disconnect_ack = PacketWriter::new(0x018B);
+            disconnect_ack.u16(0);
+            vec![disconnect_ack.finish()]
+        }
+        _ => Vec::new(),
+    }
+}
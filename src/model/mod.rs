@@ -1 +1 @@
-pub mod move_data;
\ No newline at end of file
+pub mod move_data;
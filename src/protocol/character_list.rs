@@ -1,15 +1,25 @@
-use std::collections::HashMap;
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use std::{collections::HashMap, net::Ipv4Addr, sync::LazyLock};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite},
+    net::TcpStream,
+};
+use tracing::{debug, info, info_span, warn, Instrument};
 
 use crate::{
+    capture::{recorder, Direction, Stage},
     client::network::write_message,
     input_message::InputMessage,
     network_message::NetworkMessage,
     r#const::PACKET_HEADER_LEN,
 };
 
-use super::{game, login};
+use super::{
+    game,
+    login::{LoginAccepted, ServerInfo},
+    session::{SessionEvent, SessionLink},
+};
 
+#[derive(num_enum::TryFromPrimitive, Debug)]
 #[repr(u16)]
 pub enum CharListClient {
     ReqToConnect = 0x0065,
@@ -17,7 +27,7 @@ pub enum CharListClient {
     CharSelect = 0x0066,
 }
 
-#[derive(num_enum::TryFromPrimitive)]
+#[derive(num_enum::TryFromPrimitive, Debug)]
 #[repr(u16)]
 pub enum CharListServer {
     WindowData = 0x082D,
@@ -27,13 +37,42 @@ pub enum CharListServer {
     PinCodeState = 0x08B9,
     AckCharInfoPerPage = 0x0B72,
     MapData = 0xAC5,
-    MapServerNotReady = 0x0840
+    MapServerNotReady = 0x0840,
 }
 
-pub static mut CHAR_LIST_PACKETS_LEN: Option<HashMap<u16, u16>> = None;
-static mut CHAR_LIST_ACC_ID: u32 = 0;
-static mut CHAR_LIST_LOGIN_ID: u32 = 0;
-static mut CHAR_LIST_SEX: u8 = 0;
+/// Built once, shared by every session.
+pub static CHAR_LIST_PACKETS_LEN: LazyLock<HashMap<u16, u16>> =
+    LazyLock::new(char_list_packets_len);
+
+/// Map server of the selected character.
+pub struct MapServerData {
+    pub char_id: u32,
+    pub map_name: String,
+    pub ip: Ipv4Addr,
+    pub port: u16,
+}
+
+/// Length of the packets we send, for tools reading both directions.
+pub fn char_list_client_packets_len() -> HashMap<u16, u16> {
+    let mut char_list_client_packets_len = HashMap::new();
+    char_list_client_packets_len.insert(CharListClient::ReqToConnect as u16, 15);
+    char_list_client_packets_len.insert(CharListClient::ReqCharList as u16, 0);
+    char_list_client_packets_len.insert(CharListClient::CharSelect as u16, 1);
+    char_list_client_packets_len
+}
+
+pub fn char_list_packets_len() -> HashMap<u16, u16> {
+    let mut char_list_packets_len = HashMap::new();
+    char_list_packets_len.insert(CharListServer::WindowData as u16, u16::MAX);
+    char_list_packets_len.insert(CharListServer::CharsData as u16, u16::MAX);
+    char_list_packets_len.insert(CharListServer::Notify as u16, 4);
+    char_list_packets_len.insert(CharListServer::BanCharacter as u16, u16::MAX);
+    char_list_packets_len.insert(CharListServer::PinCodeState as u16, 10);
+    char_list_packets_len.insert(CharListServer::AckCharInfoPerPage as u16, u16::MAX);
+    char_list_packets_len.insert(CharListServer::MapData as u16, 154);
+    char_list_packets_len.insert(CharListServer::MapServerNotReady as u16, 22);
+    char_list_packets_len
+}
 
 pub async fn char_list_window_data(data: &mut InputMessage) {
     let min_chars = data.read_u8();
@@ -43,7 +82,7 @@ pub async fn char_list_window_data(data: &mut InputMessage) {
     let max_chars = data.read_u8();
     data.skip_bytes(20); // unused bytes
 
-    println!("max chars: {}", max_chars);
+    debug!(max_chars, "window data");
 }
 
 pub async fn parse_char_info(data: &mut InputMessage) {
@@ -79,7 +118,6 @@ pub async fn parse_char_info(data: &mut InputMessage) {
         let bodypalette = data.read_u16();
 
         let char_name = data.read_string(Some(24));
-        println!("char name: {}", char_name);
         let stat_str = data.read_u8();
         let stat_agi = data.read_u8();
         let stat_vit = data.read_u8();
@@ -91,7 +129,6 @@ pub async fn parse_char_info(data: &mut InputMessage) {
         let is_changed_char_name = data.read_u16();
 
         let map_name = data.read_string(Some(16));
-        println!("map name: {}", map_name);
 
         let del_rev_date = data.read_u32();
 
@@ -99,9 +136,16 @@ pub async fn parse_char_info(data: &mut InputMessage) {
         let chr_slot_change_cnt = data.read_u32();
         let chr_name_change_cnt = data.read_u32();
         let sex = data.read_u8();
+        info!(
+            slot = char_num,
+            name = %char_name,
+            map = %map_name,
+            level,
+            job,
+            "character"
+        );
 
         if data.is_eof() {
-            println!("End of chars data");
             break;
         }
     }
@@ -119,13 +163,12 @@ pub async fn char_list_chars_data(data: &mut InputMessage) {
 pub async fn char_list_notify(data: &mut InputMessage) {
     let nb_pages_count = data.read_u32();
 
-    println!("nb pages count: {}", nb_pages_count);
+    debug!(pages = nb_pages_count, "char list pages");
 }
 
 pub async fn char_list_ban_character(data: &mut InputMessage) {
     loop {
         if data.is_eof() {
-            println!("End of ban character data");
             break;
         }
 
@@ -141,10 +184,11 @@ pub async fn char_list_pin_code_state(data: &mut InputMessage) {
     let pin_code_account_id = data.read_u32();
     let pin_code_state = data.read_u16();
 
-    // print all vars
-    println!(
-        "pin code seed: {}, account id: {}, state: {}",
-        pin_code_seed, pin_code_account_id, pin_code_state
+    debug!(
+        seed = pin_code_seed,
+        account_id = pin_code_account_id,
+        state = pin_code_state,
+        "pin code state"
     );
 }
 
@@ -159,7 +203,7 @@ pub async fn read_bytes(stream: &mut TcpStream, len: usize) -> Vec<u8> {
                 total_read += n;
             }
             Err(e) => {
-                println!("Failed to read from stream: {}", e);
+                warn!(error = %e, "failed to read from stream");
                 break;
             }
         }
@@ -168,56 +212,56 @@ pub async fn read_bytes(stream: &mut TcpStream, len: usize) -> Vec<u8> {
     return buffer;
 }
 
-pub async fn char_list_packet_handler(
-    stream: &mut TcpStream,
+/// Returns the map server once the server sends it, which ends the listener.
+/// The character on `slot` is selected once the list is received.
+pub async fn char_list_packet_handler<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    slot: u8,
     packet_id: u16,
     data: &mut InputMessage,
-) -> bool {
-    println!("Packet ID: {:x}, data len: {}", packet_id, data.length);
+) -> Option<MapServerData> {
     let packet_id = CharListServer::try_from(packet_id)
         .expect(format!("missing packet id {:x}", packet_id).as_str());
 
     match packet_id {
         CharListServer::WindowData => {
             char_list_window_data(data).await;
-            return true;
+            return None;
         }
         CharListServer::CharsData => {
             char_list_chars_data(data).await;
-            return true;
+            return None;
         }
         CharListServer::Notify => {
             char_list_notify(data).await;
-            return true;
+            return None;
         }
         CharListServer::BanCharacter => {
             char_list_ban_character(data).await;
-            return true;
+            return None;
         }
         CharListServer::PinCodeState => {
             char_list_pin_code_state(data).await;
             // send req char list
             char_list_reqcharlist(stream).await;
-            return true;
+            return None;
         }
         CharListServer::AckCharInfoPerPage => {
             char_list_ack_char_info_per_page(data).await;
-            // select random character for test purposes
-            char_list_char_select(stream, 0).await;
-            return true;
+            char_list_char_select(stream, slot).await;
+            return None;
         }
         CharListServer::MapServerNotReady => {
             char_list_map_server_not_ready(data).await;
-            return true;
+            return None;
         }
         CharListServer::MapData => {
-            char_list_map_data(data).await;
-            return false;
+            return Some(char_list_map_data(data).await);
         }
     }
 }
 
-pub async fn char_list_listener(stream: &mut TcpStream) {
+pub async fn char_list_listener(stream: &mut TcpStream, slot: u8) -> Option<MapServerData> {
     let mut data_packet_id: u16 = u16::MAX;
 
     let mut total_read: usize = 0;
@@ -227,12 +271,9 @@ pub async fn char_list_listener(stream: &mut TcpStream) {
     let mut parse_len = false;
     let mut has_packet_len = false;
 
-    println!("[char_list_listener] listening for packets..");
+    debug!("listening for packets");
 
-    let game_packets_len;
-    unsafe {
-        game_packets_len = CHAR_LIST_PACKETS_LEN.as_ref().unwrap();
-    }
+    let game_packets_len = &*CHAR_LIST_PACKETS_LEN;
 
     loop {
         stream.readable().await.expect("stream not readable");
@@ -242,7 +283,7 @@ pub async fn char_list_listener(stream: &mut TcpStream) {
         match read_result {
             Ok(n) => {
                 if n == 0 {
-                    println!("Connection closed by server");
+                    info!("connection closed by server");
                     break;
                 }
 
@@ -250,9 +291,6 @@ pub async fn char_list_listener(stream: &mut TcpStream) {
 
                 //println!("total read: {}", total_read);
 
-                // print the buffer as hexadecimal bytes, for debugging purposes
-                // println!("{:02X?}", &buffer[0..total_read]);
-
                 // read some data
                 if !parse_len && data_packet_id != u16::MAX && total_read == packet_len {
                     let header_size = if has_packet_len {
@@ -262,13 +300,15 @@ pub async fn char_list_listener(stream: &mut TcpStream) {
                     };
 
                     //println!("header size: {}, total read {}", header_size, total_read);
+                    recorder::record(Stage::Char, Direction::Received, &buffer[0..packet_len]);
+
                     let mut input_message =
                         InputMessage::new(buffer[header_size..packet_len].to_vec());
                     let result =
-                        char_list_packet_handler(stream, data_packet_id, &mut input_message).await;
+                        char_list_packet_handler(stream, slot, data_packet_id, &mut input_message)
+                            .await;
                     match result {
-                        true => {
-                            println!("packet id {:x} handled", data_packet_id);
+                        None => {
                             // reset packet_len to read the next packet
                             packet_len = PACKET_HEADER_LEN as usize;
                             data_packet_id = u16::MAX;
@@ -276,19 +316,21 @@ pub async fn char_list_listener(stream: &mut TcpStream) {
                             has_packet_len = false;
                             total_read = 0;
                         }
-                        false => {
-                            println!("ending character list listener");
-                            break;
+                        Some(map_server) => {
+                            return Some(map_server);
                         }
                     }
                     continue;
                 }
 
                 if parse_len && total_read == packet_len {
-                    packet_len = u16::from_le_bytes([buffer[header_size], buffer[header_size + 1]]) as usize;
+                    packet_len =
+                        u16::from_le_bytes([buffer[header_size], buffer[header_size + 1]]) as usize;
                     parse_len = false;
 
                     if packet_len == total_read {
+                        recorder::record(Stage::Char, Direction::Received, &buffer[0..packet_len]);
+
                         // we read all the data, we should reset to next packet data!
                         packet_len = PACKET_HEADER_LEN as usize;
                         data_packet_id = u16::MAX;
@@ -304,8 +346,6 @@ pub async fn char_list_listener(stream: &mut TcpStream) {
                     // parse packet id first
                     data_packet_id = u16::from_le_bytes([buffer[0], buffer[1]]);
 
-                    //println!("pckt id: {:x}", data_packet_id);
-
                     let result = game_packets_len.get(&data_packet_id);
                     match result {
                         Some(&len) => {
@@ -326,11 +366,13 @@ pub async fn char_list_listener(stream: &mut TcpStream) {
                 }
             }
             Err(e) => {
-                println!("Failed to read from stream: {}", e);
+                warn!(error = %e, "failed to read from stream");
                 break;
             }
         }
     }
+
+    None
 }
 
 // client->server packets
@@ -341,7 +383,7 @@ pub async fn char_list_reqconnect(
     acc_id: u32,
     sex: u8,
 ) {
-    println!("Sending char list request");
+    debug!("sending connection request");
 
     let mut network_message = NetworkMessage::new();
     network_message.add(CharListClient::ReqToConnect as u16);
@@ -351,7 +393,7 @@ pub async fn char_list_reqconnect(
     network_message.add(0 as u16); // unknown
     network_message.add(sex);
 
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Char, &network_message).await;
 
     // after sending this packet, the server will send 4 bytes as account id
     let account_id_vec = read_bytes(stream, 4).await;
@@ -362,24 +404,24 @@ pub async fn char_list_reqconnect(
         account_id_vec[3],
     ]);
 
-    println!("Received account id from server: {}", account_id);
+    debug!(account_id, "account id received");
 }
 
-pub async fn char_list_reqcharlist(stream: &mut TcpStream) {
-    println!("Sending char list request");
+pub async fn char_list_reqcharlist<W: AsyncWrite + Unpin>(stream: &mut W) {
+    debug!("sending char list request");
 
     let mut network_message = NetworkMessage::new();
     network_message.add(CharListClient::ReqCharList as u16);
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Char, &network_message).await;
 }
 
-pub async fn char_list_char_select(stream: &mut TcpStream, index: u8) {
-    println!("Sending char list select");
+pub async fn char_list_char_select<W: AsyncWrite + Unpin>(stream: &mut W, index: u8) {
+    info!(slot = index, "selecting character");
 
     let mut network_message = NetworkMessage::new();
     network_message.add(CharListClient::CharSelect as u16);
     network_message.add(index);
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Char, &network_message).await;
 }
 
 pub async fn char_list_ack_char_info_per_page(data: &mut InputMessage) {
@@ -391,76 +433,138 @@ pub async fn char_list_map_server_not_ready(data: &mut InputMessage) {
     data.skip_bytes(20);
 }
 
-pub async fn char_list_map_data(data: &mut InputMessage) {
+pub async fn char_list_map_data(data: &mut InputMessage) -> MapServerData {
     let char_id = data.read_u32();
     let map_name = data.read_string(Some(16));
-    let map_ip = data.read_u32();
+    // sent in network order
+    let map_ip = Ipv4Addr::from(data.read_u32().to_le_bytes());
     let map_port = data.read_u16();
     data.skip_bytes(128); // unknown bytes
 
-    println!("char id: {}, map name: {}, map ip: {}, map port: {}", char_id, map_name, map_ip, map_port);
-
-    // reverse ip and convert ip and port to server addr
-    let map_ip_str = format!(
-        "{}.{}.{}.{}",
-        (map_ip & 0xFF),
-        (map_ip >> 8 & 0xFF),
-        (map_ip >> 16 & 0xFF),
-        (map_ip >> 24 & 0xFF)
+    info!(
+        char_id,
+        map = %map_name,
+        server = format_args!("{}:{}", map_ip, map_port),
+        "map server received"
     );
 
-    let acc_id;
-    let login_id;
-    let sex;
-    unsafe {
-        acc_id = CHAR_LIST_ACC_ID;
-        login_id = CHAR_LIST_LOGIN_ID;
-        sex = CHAR_LIST_SEX;
+    MapServerData {
+        char_id,
+        map_name,
+        ip: map_ip,
+        port: map_port,
     }
-
-    // print all vars
-    println!(
-        "char id: {}, map name: {}, map ip: {}, map port: {}",
-        char_id, map_name, map_ip_str, map_port
-    );
-
-    tokio::spawn(async move {
-        game::initialize(&map_ip_str, map_port, acc_id, char_id, login_id, 111111111, sex).await;
-    });
 }
 
-pub async fn initialize(ip: &str, port: u16, login_id: u32, login_id_2: u32, acc_id: u32, sex: u8) {
-    // print login id's
-    println!("login id: {:x}, login id 2: {:x}", login_id, login_id_2);
-    unsafe {
-        CHAR_LIST_ACC_ID = acc_id;
-        CHAR_LIST_LOGIN_ID = login_id;
-        CHAR_LIST_SEX = sex;
-
-        let mut char_list_packets_len = HashMap::new();
-        char_list_packets_len.insert(CharListServer::WindowData as u16, u16::MAX);
-        char_list_packets_len.insert(CharListServer::CharsData as u16, u16::MAX);
-        char_list_packets_len.insert(CharListServer::Notify as u16, 4);
-        char_list_packets_len.insert(CharListServer::BanCharacter as u16, u16::MAX);
-        char_list_packets_len.insert(CharListServer::PinCodeState as u16, 10);
-        char_list_packets_len.insert(CharListServer::AckCharInfoPerPage as u16, u16::MAX);
-        char_list_packets_len.insert(CharListServer::MapData as u16, 154);
-        char_list_packets_len.insert(CharListServer::MapServerNotReady as u16, 22);
-
-        CHAR_LIST_PACKETS_LEN = Some(char_list_packets_len);
-    }
+/// Selects the character on `slot`, then hands over to the map stage. The
+/// map stage may send us back here to select another slot, the login ids
+/// stay valid so no new login is needed.
+pub async fn initialize(
+    login: &LoginAccepted,
+    server: &ServerInfo,
+    mut slot: u8,
+    mut link: SessionLink,
+) {
+    let server_addr = server.addr().to_string();
+    loop {
+        let map_server = async {
+            let stream = TcpStream::connect(&server_addr).await;
+            match stream {
+                Ok(mut stream) => {
+                    info!("connected");
+                    link.emit(SessionEvent::Connected(Stage::Char));
+                    char_list_reqconnect(
+                        &mut stream,
+                        login.login_id,
+                        login.login_id_2,
+                        login.acc_id,
+                        login.sex,
+                    )
+                    .await;
+                    let map_server = char_list_listener(&mut stream, slot).await;
+                    if map_server.is_none() {
+                        link.fail(Stage::Char, "connection closed before MapData");
+                    }
+                    map_server
+                }
+                Err(e) => {
+                    warn!(error = %e, "failed to connect");
+                    link.fail(Stage::Char, &e.to_string());
+                    None
+                }
+            }
+        }
+        .instrument(info_span!("char", server = %server_addr, slot))
+        .await;
 
-    let server_addr = format!("{}:{}", ip, port);
-    let stream = TcpStream::connect(server_addr).await;
-    match stream {
-        Ok(mut stream) => {
-            println!("Connected to character list server: {}:{}", ip, port);
-            // send char list request
-            char_list_reqconnect(&mut stream, login_id, login_id_2, acc_id, sex).await;
-            char_list_listener(&mut stream).await;
+        // the char connection is closed by now, the map stage takes over
+        let Some(map_server) = map_server else {
+            break;
+        };
+        link.emit(SessionEvent::CharSelected {
+            slot,
+            char_id: map_server.char_id,
+            map_name: map_server.map_name.clone(),
+        });
+        let char_select = game::initialize(
+            &map_server,
+            login.acc_id,
+            login.login_id,
+            111111111,
+            login.sex,
+            &mut link,
+        )
+        .await;
+        match char_select {
+            Some(next_slot) => slot = next_slot,
+            None => break,
         }
-        Err(e) => {
-            println!("Failed to connect to server: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        mock_server::{next_event, start_session, MockScript, MockStage},
+        protocol::{login::SessionOptions, session::SessionCommand},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn char_select_switches_character_without_login() {
+        let (server, mut session) =
+            start_session(MockScript::default(), "mock123", SessionOptions::default()).await;
+
+        let mut selected = Vec::new();
+        let mut ready = 0;
+        while ready < 2 {
+            match next_event(&mut session).await.expect("session ended") {
+                SessionEvent::CharSelected { slot, char_id, .. } => selected.push((slot, char_id)),
+                SessionEvent::MapReady { .. } => {
+                    ready += 1;
+                    if ready == 1 {
+                        session.send(SessionCommand::CharSelect { slot: 1 });
+                    }
+                }
+                _ => {}
+            }
         }
+        assert_eq!(selected, vec![(0, 150001), (1, 150002)]);
+
+        let received = server.received();
+        let restart = received
+            .iter()
+            .find(|packet| packet.stage == MockStage::Map && packet.packet_id == 0x00B2)
+            .expect("restart never sent");
+        assert_eq!(restart.data[2], 1);
+        assert_eq!(server.count(MockStage::Login, 0x0064), 1);
+        // same login ids on the second char server connection
+        let connects: Vec<_> = received
+            .iter()
+            .filter(|packet| packet.stage == MockStage::Char && packet.packet_id == 0x0065)
+            .collect();
+        assert_eq!(connects.len(), 2);
+        assert_eq!(connects[0].data, connects[1].data);
     }
 }
@@ -0,0 +1,174 @@
+use std::time::{Duration, Instant};
+
+/// Client tick, in milliseconds since the map connection started, and an
+/// estimate of the server tick it matches.
+///
+/// Both ticks are `u32` milliseconds that wrap, so every comparison is
+/// done on the wrapped difference. The estimate comes from the `ClientTick`
+/// round-trip when there is one: the server answered halfway through it.
+/// Until then server ticks seen in other packets give a lower bound, they
+/// were stamped before we read them.
+pub struct ServerClock {
+    start: Instant,
+    /// Server tick minus client tick, wrapping.
+    offset: Option<u32>,
+    /// Offset measured from a round-trip, not only guessed from one-way ticks.
+    synced: bool,
+    tick_sent: Option<Instant>,
+    rtt: Option<Duration>,
+}
+
+impl Default for ServerClock {
+    fn default() -> Self {
+        ServerClock::new(Instant::now())
+    }
+}
+
+impl ServerClock {
+    pub fn new(start: Instant) -> ServerClock {
+        ServerClock {
+            start,
+            offset: None,
+            synced: false,
+            tick_sent: None,
+            rtt: None,
+        }
+    }
+
+    pub fn client_tick(&self) -> u32 {
+        self.client_tick_at(Instant::now())
+    }
+
+    fn client_tick_at(&self, now: Instant) -> u32 {
+        now.saturating_duration_since(self.start).as_millis() as u32
+    }
+
+    /// Called when sending a `ClientTick`, returns the tick to send.
+    pub fn tick_sent(&mut self) -> u32 {
+        self.tick_sent_at(Instant::now())
+    }
+
+    fn tick_sent_at(&mut self, now: Instant) -> u32 {
+        self.tick_sent = Some(now);
+        self.client_tick_at(now)
+    }
+
+    /// Server answer to our last `ClientTick`.
+    pub fn on_notify_time(&mut self, server_tick: u32) {
+        self.on_notify_time_at(server_tick, Instant::now());
+    }
+
+    fn on_notify_time_at(&mut self, server_tick: u32, now: Instant) {
+        let Some(sent) = self.tick_sent.take() else {
+            // not ours, still a one-way tick
+            self.observe_at(server_tick, now);
+            return;
+        };
+
+        let rtt = now.saturating_duration_since(sent);
+        let answered = self.client_tick_at(sent + rtt / 2);
+        self.offset = Some(server_tick.wrapping_sub(answered));
+        self.synced = true;
+        self.rtt = Some(rtt);
+    }
+
+    /// Server tick stamped on a packet, e.g. a unit starting to move.
+    pub fn observe(&mut self, server_tick: u32) {
+        self.observe_at(server_tick, Instant::now());
+    }
+
+    fn observe_at(&mut self, server_tick: u32, now: Instant) {
+        if self.synced {
+            return;
+        }
+        let offset = server_tick.wrapping_sub(self.client_tick_at(now));
+        match self.offset {
+            Some(current) if (offset.wrapping_sub(current) as i32) <= 0 => {}
+            _ => self.offset = Some(offset),
+        }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Last `ClientTick` round-trip.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Estimated server tick now, `None` before any server tick was seen.
+    pub fn server_tick(&self) -> Option<u32> {
+        self.server_tick_at(Instant::now())
+    }
+
+    pub fn server_tick_at(&self, now: Instant) -> Option<u32> {
+        self.offset
+            .map(|offset| self.client_tick_at(now).wrapping_add(offset))
+    }
+
+    /// Local instant matching a server tick, in the past or the future.
+    pub fn instant_of(&self, server_tick: u32) -> Option<Instant> {
+        let now = Instant::now();
+        let ago = self.server_tick_at(now)?.wrapping_sub(server_tick) as i32;
+        let distance = Duration::from_millis(ago.unsigned_abs() as u64);
+        if ago >= 0 {
+            now.checked_sub(distance)
+        } else {
+            Some(now + distance)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_sets_the_offset() {
+        let start = Instant::now();
+        let mut clock = ServerClock::new(start);
+
+        // one-way tick first, only a lower bound
+        clock.observe_at(5_000, start + Duration::from_millis(100));
+        assert_eq!(
+            clock.server_tick_at(start + Duration::from_millis(100)),
+            Some(5_000)
+        );
+        assert!(!clock.is_synced());
+
+        // sent at 1000, answered at 1200, the server was at 9000 at 1100
+        let tick = clock.tick_sent_at(start + Duration::from_millis(1_000));
+        assert_eq!(tick, 1_000);
+        clock.on_notify_time_at(9_000, start + Duration::from_millis(1_200));
+
+        assert!(clock.is_synced());
+        assert_eq!(clock.rtt(), Some(Duration::from_millis(200)));
+        assert_eq!(
+            clock.server_tick_at(start + Duration::from_millis(2_100)),
+            Some(10_000)
+        );
+
+        // one-way ticks no longer move a measured offset
+        clock.observe_at(50_000, start + Duration::from_millis(2_100));
+        assert_eq!(
+            clock.server_tick_at(start + Duration::from_millis(2_100)),
+            Some(10_000)
+        );
+    }
+
+    #[test]
+    fn one_way_ticks_keep_the_latest_bound_across_wrap() {
+        let start = Instant::now();
+        let mut clock = ServerClock::new(start);
+
+        clock.observe_at(u32::MAX - 10, start);
+        // stamped 30ms after the first one, past the wrap
+        clock.observe_at(19, start);
+        assert_eq!(clock.server_tick_at(start), Some(19));
+
+        // an older tick read late does not move it back
+        clock.observe_at(u32::MAX - 500, start);
+        assert_eq!(clock.server_tick_at(start), Some(19));
+    }
+}
@@ -1,15 +1,43 @@
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::HashMap,
+    io,
+    net::Ipv4Addr,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
 use num_enum::TryFromPrimitive;
-use tokio::{io::AsyncReadExt, net::TcpStream};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite},
+    net::TcpStream,
+    time::{interval_at, Instant, MissedTickBehavior},
+};
+use tracing::{debug, info, info_span, warn, Instrument};
 
 use crate::{
-    client::network::write_message, r#const::PACKET_HEADER_LEN, enums::{DropEffectMode, StatusPoint}, input_message::InputMessage, io, network_message::NetworkMessage, protocol::helper::read_pos
+    capture::{recorder, Direction, Stage},
+    client::network::write_message,
+    enums::{DropEffectMode, RestartType, StatusPoint},
+    input_message::InputMessage,
+    io::gat::GatData,
+    map::{
+        self,
+        movement::{Units, DEFAULT_WALK_SPEED},
+    },
+    model::move_data::MoveData,
+    network_message::NetworkMessage,
+    protocol::helper::read_pos,
+    r#const::{CLIENT_TICK_INTERVAL_SECS, PACKET_HEADER_LEN},
 };
 
-use super::helper::read_move_data;
+use super::{
+    character_list::MapServerData,
+    clock::ServerClock,
+    helper::read_move_data,
+    session::{SessionCommand, SessionEvent, SessionLink},
+};
 
-#[derive(TryFromPrimitive)]
+#[derive(TryFromPrimitive, Debug)]
 #[repr(u16)]
 pub enum GameClient {
     WalkTo = 0x035F,
@@ -20,9 +48,12 @@ pub enum GameClient {
     ClientTick = 0x0360,
     ChangeDir = 0x0361,
     ChatMessage = 0x00F3, // global message
+    RequestName = 0x0368,
+    Restart = 0x00B2,
+    Quit = 0x018A,
 }
 
-#[derive(TryFromPrimitive)]
+#[derive(TryFromPrimitive, Debug)]
 #[repr(u16)]
 pub enum GameServer {
     MapBlockList = 0x0283,
@@ -37,6 +68,7 @@ pub enum GameServer {
     ObjectAction3 = 0x08C8, // tested and received when a monster tries to hit
     ChatMessage = 0x008D,
     ChangeMap = 0x0091,
+    ChangeMapServer = 0x0092,
     ItemDisappear = 0x00A1,
     ParameterChange = 0x00B0,
     NpcClose = 0x00B6,
@@ -70,12 +102,223 @@ pub enum GameServer {
     StatusChange = 0x0196,
     StatusChange2 = 0x0983,
     UnitClear = 0x0080,
+    NotifyTime = 0x007F, // answer to ClientTick
+    NameAck = 0x0A30,    // answer to RequestName
+    RestartAck = 0x00B3,
+    DisconnectAck = 0x018B, // answer to Quit
+}
+
+/// Built once, shared by every session.
+pub static GAME_PACKETS_LEN: LazyLock<HashMap<u16, u16>> = LazyLock::new(game_packets_len);
+
+/// What the map stage keeps between packets.
+#[derive(Default)]
+pub struct GameState {
+    /// Our block id on the map.
+    pub account_id: u32,
+    pub map_name: String,
+    /// `LoadEndAck` sent, the server sees us on the map.
+    pub ready: bool,
+    pub clock: ServerClock,
+    /// Cells of the current map, `None` when no map source has it.
+    pub map: Option<Arc<GatData>>,
+    /// Units in sight, us included.
+    pub units: Units,
+    /// Set when the server moves us to another map server, the listener
+    /// stops there.
+    pub server_change: Option<MapServerChange>,
+    /// `Quit` sent, the server closing the connection is expected.
+    pub quitting: bool,
+    /// The server acked our `Quit`, the listener stops there.
+    pub disconnected: bool,
+    /// Slot asked for with `CharSelect`, until the server lets us go.
+    pub pending_char_select: Option<u8>,
+    /// Set when the server lets us go back to the character list, the
+    /// listener stops there.
+    pub char_select: Option<u8>,
 }
 
-pub static mut GAME_PACKETS_LEN: Option<HashMap<u16, u16>> = None;
+/// Map server to connect to, with the map we enter on it.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MapServerChange {
+    pub map_name: String,
+    pub position: (u16, u16),
+    pub ip: Ipv4Addr,
+    pub port: u16,
+}
+
+impl GameState {
+    pub fn new(account_id: u32, map_name: &str, map: Option<Arc<GatData>>) -> GameState {
+        GameState {
+            account_id,
+            map_name: map_name.to_string(),
+            map,
+            ..GameState::default()
+        }
+    }
+
+    /// Same-server map change: everything seen on the previous map is gone
+    /// and the map must be acked again.
+    async fn enter_map(&mut self, map_name: String, position: (u16, u16)) {
+        let speed = self
+            .units
+            .get(self.account_id)
+            .map_or(DEFAULT_WALK_SPEED, |unit| unit.speed);
+
+        self.map = load_map(&map_name).await;
+        self.map_name = map_name;
+        self.ready = false;
+        self.units.clear();
+        self.units.spawn(self.account_id, position, speed);
+    }
 
-pub async fn game_connect_map_server(
-    stream: &mut TcpStream,
+    /// Cell of a unit now, walks included.
+    pub fn unit_cell(&self, id: u32) -> Option<(u16, u16)> {
+        let server_tick = self.clock.server_tick()?;
+        self.units.cell_at(id, server_tick)
+    }
+
+    fn unit_walk(&mut self, id: u32, move_data: &MoveData, start_tick: u32) {
+        self.units.walk(
+            self.map.as_deref(),
+            id,
+            (move_data.from_x, move_data.from_y),
+            (move_data.to_x, move_data.to_y),
+            start_tick,
+        );
+    }
+}
+
+/// What the client sends once the map is loaded, the server only sends
+/// the units around, the inventory and the status after `LoadEndAck`.
+async fn game_load_end<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    state: &mut GameState,
+) -> SessionEvent {
+    game_request_ack_map(stream).await;
+    // sync the server clock right away, the keepalive comes later
+    game_request_client_tick(stream, state.clock.tick_sent()).await;
+    game_request_effects_option(stream, 0).await;
+    game_request_name(stream, state.account_id).await;
+    state.ready = true;
+
+    let (x, y) = state
+        .units
+        .get(state.account_id)
+        .map_or((0, 0), |unit| unit.position);
+    info!(map = %state.map_name, x, y, "map ready");
+    SessionEvent::MapReady {
+        map_name: state.map_name.clone(),
+        x,
+        y,
+    }
+}
+
+/// Maps are parsed once and shared between sessions. Parsing blocks on
+/// file reads, so a map not cached yet is loaded on the blocking pool.
+async fn load_map(map_name: &str) -> Option<Arc<GatData>> {
+    let cache = map::cache::shared();
+    if let Some(gat_data) = cache.cached(map_name) {
+        return Some(gat_data);
+    }
+
+    let name = map_name.to_string();
+    match tokio::task::spawn_blocking(move || cache.get(&name)).await {
+        Ok(Ok(gat_data)) => Some(gat_data),
+        Ok(Err(e)) => {
+            warn!(map = %map_name, error = %e, "failed to load map");
+            None
+        }
+        Err(e) => {
+            warn!(map = %map_name, error = %e, "map loading task failed");
+            None
+        }
+    }
+}
+
+/// A unit entering the sight, see `parse_unit_data`.
+pub struct UnitData {
+    pub id: u32,
+    pub speed: u16,
+    pub position: (u16, u16),
+    /// Walk and the server tick it started at, for walking units only.
+    pub walk: Option<(MoveData, u32)>,
+}
+
+/// Length of the packets we send, for tools reading both directions.
+pub fn game_client_packets_len() -> HashMap<u16, u16> {
+    let mut game_client_packets_len = HashMap::new();
+    game_client_packets_len.insert(GameClient::WalkTo as u16, 3);
+    game_client_packets_len.insert(GameClient::ConnectMapServer as u16, 17);
+    game_client_packets_len.insert(GameClient::RequestAction as u16, 5);
+    game_client_packets_len.insert(GameClient::EffectsOption as u16, 4);
+    game_client_packets_len.insert(GameClient::AckMap as u16, 0);
+    game_client_packets_len.insert(GameClient::ClientTick as u16, 4);
+    game_client_packets_len.insert(GameClient::ChangeDir as u16, 3);
+    game_client_packets_len.insert(GameClient::ChatMessage as u16, u16::MAX);
+    game_client_packets_len.insert(GameClient::RequestName as u16, 4);
+    game_client_packets_len.insert(GameClient::Restart as u16, 1);
+    game_client_packets_len.insert(GameClient::Quit as u16, 2);
+    game_client_packets_len
+}
+
+pub fn game_packets_len() -> HashMap<u16, u16> {
+    let mut game_packets_len = HashMap::new();
+    game_packets_len.insert(GameServer::MapBlockList as u16, 4);
+    game_packets_len.insert(GameServer::InventoryExpansionInfo as u16, 2);
+    game_packets_len.insert(GameServer::NotifyChangeStatus as u16, 8);
+    game_packets_len.insert(GameServer::AuthOk as u16, 11);
+    game_packets_len.insert(GameServer::DisplayMessage as u16, u16::MAX);
+    game_packets_len.insert(GameServer::ObjectMove as u16, 14);
+    game_packets_len.insert(GameServer::WalkSucceeded as u16, 10);
+    game_packets_len.insert(GameServer::StopPos as u16, 8);
+    game_packets_len.insert(GameServer::ObjectAction as u16, 27);
+    game_packets_len.insert(GameServer::ObjectAction3 as u16, 32);
+    game_packets_len.insert(GameServer::ChatMessage as u16, u16::MAX);
+    game_packets_len.insert(GameServer::ChangeMap as u16, 20);
+    game_packets_len.insert(GameServer::ChangeMapServer as u16, 26);
+    game_packets_len.insert(GameServer::ItemDisappear as u16, 4);
+    game_packets_len.insert(GameServer::ParameterChange as u16, 6);
+    game_packets_len.insert(GameServer::NpcClose as u16, 4);
+    game_packets_len.insert(GameServer::CoupleStatus as u16, 12);
+    game_packets_len.insert(GameServer::AtkRange as u16, 2);
+    game_packets_len.insert(GameServer::MailUnread as u16, 1);
+    game_packets_len.insert(GameServer::QuestsStateList as u16, u16::MAX);
+    game_packets_len.insert(GameServer::SingleAchievementData as u16, 64);
+    game_packets_len.insert(GameServer::AllAchievementsData as u16, u16::MAX);
+    game_packets_len.insert(GameServer::WeightLimit as u16, 4);
+    game_packets_len.insert(GameServer::DropItem as u16, 22);
+    game_packets_len.insert(GameServer::SpriteChange as u16, 13);
+    game_packets_len.insert(GameServer::InventoryStart as u16, u16::MAX);
+    game_packets_len.insert(GameServer::InventoryType as u16, u16::MAX);
+    game_packets_len.insert(GameServer::InventoryEnd as u16, 2);
+    game_packets_len.insert(GameServer::EquipSwitchList as u16, u16::MAX);
+    game_packets_len.insert(GameServer::MapProperty as u16, 6);
+    game_packets_len.insert(GameServer::UnitIdle as u16, u16::MAX);
+    game_packets_len.insert(GameServer::UnitSpawn as u16, u16::MAX);
+    game_packets_len.insert(GameServer::UnitWalking as u16, u16::MAX);
+    game_packets_len.insert(GameServer::UnitChangedDir as u16, 7);
+    game_packets_len.insert(GameServer::UnitClear as u16, 5);
+    game_packets_len.insert(GameServer::ScreenActiveEFST as u16, 26);
+    game_packets_len.insert(GameServer::SkillTree as u16, u16::MAX);
+    game_packets_len.insert(GameServer::ShortcutsKeyList as u16, 269);
+    game_packets_len.insert(GameServer::LongParameterChange as u16, 10);
+    game_packets_len.insert(GameServer::CharacterStatus as u16, 42);
+    game_packets_len.insert(GameServer::UpdateStatus as u16, 3);
+    game_packets_len.insert(GameServer::PartyInvitationState as u16, 1);
+    game_packets_len.insert(GameServer::EquipWindowOpen as u16, 1);
+    game_packets_len.insert(GameServer::ConfigurationChange as u16, 8);
+    game_packets_len.insert(GameServer::StatusChange as u16, 7);
+    game_packets_len.insert(GameServer::StatusChange2 as u16, 27);
+    game_packets_len.insert(GameServer::NotifyTime as u16, 4);
+    game_packets_len.insert(GameServer::NameAck as u16, 104);
+    game_packets_len.insert(GameServer::RestartAck as u16, 1);
+    game_packets_len.insert(GameServer::DisconnectAck as u16, 2);
+    game_packets_len
+}
+
+pub async fn game_connect_map_server<W: AsyncWrite + Unpin>(
+    stream: &mut W,
     acc_id: u32,
     char_id: u32,
     login_id: u32,
@@ -87,13 +330,13 @@ pub async fn game_connect_map_server(
     network_message.add(acc_id);
     network_message.add(char_id);
     network_message.add(login_id);
-    network_message.add(client_tick as u64);
+    network_message.add(client_tick);
     network_message.add(sex);
 
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Map, &network_message).await;
 }
 
-pub async fn game_request_walk_to(stream: &mut TcpStream, x: u16, y: u16, dir: u16) {
+pub async fn game_request_walk_to<W: AsyncWrite + Unpin>(stream: &mut W, x: u16, y: u16, dir: u16) {
     let mut network_message = NetworkMessage::new();
     network_message.add(GameClient::WalkTo as u16);
 
@@ -101,51 +344,89 @@ pub async fn game_request_walk_to(stream: &mut TcpStream, x: u16, y: u16, dir: u
     network_message.add(((x << 6) | ((y >> 4) & 0x3f)) as u8);
     network_message.add(((y << 4) | (dir & 0xf)) as u8);
 
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Map, &network_message).await;
 }
 
-pub async fn game_request_action(stream: &mut TcpStream, target_id: u32, action_type: u8) {
+pub async fn game_request_action<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    target_id: u32,
+    action_type: u8,
+) {
     let mut network_message = NetworkMessage::new();
     network_message.add(GameClient::RequestAction as u16);
     network_message.add(target_id);
     network_message.add(action_type);
 
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Map, &network_message).await;
 }
 
-pub async fn game_request_effects_option(stream: &mut TcpStream, effects_option: u32) {
+pub async fn game_request_effects_option<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    effects_option: u32,
+) {
     let mut network_message = NetworkMessage::new();
     network_message.add(GameClient::EffectsOption as u16);
     network_message.add(effects_option);
 
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Map, &network_message).await;
 }
 
-pub async fn game_request_ack_map(stream: &mut TcpStream) {
+pub async fn game_request_ack_map<W: AsyncWrite + Unpin>(stream: &mut W) {
     let mut network_message = NetworkMessage::new();
     network_message.add(GameClient::AckMap as u16);
 
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Map, &network_message).await;
 }
 
-pub async fn game_request_client_tick(stream: &mut TcpStream, tick: u32) {
+pub async fn game_request_client_tick<W: AsyncWrite + Unpin>(stream: &mut W, tick: u32) {
     let mut network_message = NetworkMessage::new();
     network_message.add(GameClient::ClientTick as u16);
     network_message.add(tick);
 
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Map, &network_message).await;
 }
 
-pub async fn game_request_change_dir(stream: &mut TcpStream, head_dir: u16, dir: u8) {
+pub async fn game_request_change_dir<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    head_dir: u16,
+    dir: u8,
+) {
     let mut network_message = NetworkMessage::new();
     network_message.add(GameClient::ChangeDir as u16);
     network_message.add(head_dir);
     network_message.add(dir);
 
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Map, &network_message).await;
+}
+
+pub async fn game_request_name<W: AsyncWrite + Unpin>(stream: &mut W, id: u32) {
+    let mut network_message = NetworkMessage::new();
+    network_message.add(GameClient::RequestName as u16);
+    network_message.add(id);
+
+    write_message(stream, Stage::Map, &network_message).await;
+}
+
+pub async fn game_request_restart<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    restart_type: RestartType,
+) -> io::Result<()> {
+    let mut network_message = NetworkMessage::new();
+    network_message.add(GameClient::Restart as u16);
+    network_message.add(restart_type as u8);
+
+    write_message(stream, Stage::Map, &network_message).await
 }
 
-pub async fn game_request_chat_message(stream: &mut TcpStream, message: &str) {
+pub async fn game_request_quit<W: AsyncWrite + Unpin>(stream: &mut W) -> io::Result<()> {
+    let mut network_message = NetworkMessage::new();
+    network_message.add(GameClient::Quit as u16);
+    network_message.add(0u16); // type, always 0
+
+    write_message(stream, Stage::Map, &network_message).await
+}
+
+pub async fn game_request_chat_message<W: AsyncWrite + Unpin>(stream: &mut W, message: &str) {
     let mut network_message = NetworkMessage::new();
     network_message.add(GameClient::ChatMessage as u16);
     network_message.add(0 as u16);
@@ -158,7 +439,7 @@ pub async fn game_request_chat_message(stream: &mut TcpStream, message: &str) {
     network_message.buffer[2] = packet_len as u8;
     network_message.buffer[3] = (packet_len >> 8) as u8;
 
-    write_message(stream, &network_message).await;
+    write_message(stream, Stage::Map, &network_message).await;
 }
 
 pub async fn game_map_block_list(data: &mut InputMessage) {
@@ -174,43 +455,54 @@ pub async fn game_notify_change_status(data: &mut InputMessage) {
     let message_id = data.read_u32();
 }
 
-pub async fn game_auth_ok(data: &mut InputMessage) {
+pub async fn game_auth_ok(data: &mut InputMessage) -> SessionEvent {
     let client_tick = data.read_u32();
     let pos = read_pos(data);
 
-    // print x, y, dir
-    println!("x: {}, y: {}, dir: {}", pos.0, pos.1, pos.2);
+    info!(x = pos.0, y = pos.1, dir = pos.2, "map auth ok");
 
     let _unk1 = data.read_u8();
     let _unk2 = data.read_u8();
     let font = data.read_u16();
+
+    SessionEvent::MapAuthOk { x: pos.0, y: pos.1 }
 }
 
 pub async fn game_display_message(data: &mut InputMessage) {
     let remaining_bytes = data.length - data.position;
     let message = data.read_string(Some(remaining_bytes));
 
-    println!("Message: {}", message);
+    info!(%message, "server message");
 }
 
-pub async fn game_object_move(stream: &mut TcpStream, data: &mut InputMessage) {
+/// Returns the unit, its walk and the server tick it started moving at.
+pub async fn game_object_move<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    data: &mut InputMessage,
+) -> (u32, MoveData, u32) {
     let object_id = data.read_u32();
     let move_data = read_move_data(data);
     let server_tick = data.read_u32();
 
     // when any player moves, this triggers
+    (object_id, move_data, server_tick)
 }
 
-pub async fn game_walk_succeeded(data: &mut InputMessage) {
+/// Returns our walk and the server tick we started walking at.
+pub async fn game_walk_succeeded(data: &mut InputMessage) -> (MoveData, u32) {
     let walk_time_start: u32 = data.read_u32();
     let move_data = read_move_data(data);
+
+    (move_data, walk_time_start)
 }
 
 // used for fixing pos too
-pub async fn game_stop_pos(data: &mut InputMessage) {
+pub async fn game_stop_pos(data: &mut InputMessage) -> (u32, (u16, u16)) {
     let object_id = data.read_u32();
     let x = data.read_u16();
     let y = data.read_u16();
+
+    (object_id, (x, y))
 }
 
 pub async fn game_object_action(data: &mut InputMessage) {
@@ -232,7 +524,8 @@ pub async fn game_object_action(data: &mut InputMessage) {
     }
 }
 
-pub async fn game_object_action_3(data: &mut InputMessage) {
+/// Returns the server tick of the action.
+pub async fn game_object_action_3(data: &mut InputMessage) -> u32 {
     let from_object_id = data.read_u32();
     let to_object_id = data.read_u32();
     let server_tick = data.read_u32();
@@ -243,6 +536,8 @@ pub async fn game_object_action_3(data: &mut InputMessage) {
     let div = data.read_u16();
     let r#type = data.read_u8();
     let damage_2 = data.read_u32();
+
+    server_tick
 }
 
 pub async fn game_chat_message(data: &mut InputMessage) {
@@ -250,29 +545,44 @@ pub async fn game_chat_message(data: &mut InputMessage) {
     let remaining_bytes = data.length - data.position;
     let message = data.read_string(Some(remaining_bytes));
 
-    println!("[game_chat_message] {}", message);
+    info!(gid, %message, "chat message");
 }
 
-pub async fn game_change_map(data: &mut InputMessage) {
+/// Returns the new map and where we stand on it.
+pub async fn game_change_map(data: &mut InputMessage) -> (String, (u16, u16)) {
     let mut map_name = data.read_string(Some(16));
     let x = data.read_u16();
     let y = data.read_u16();
 
-    println!("map_name: {}, x: {}, y: {}", map_name, x, y);
+    info!(map = %map_name, x, y, "map changed");
 
-    let mut map_name_new: String = String::new();
-    map_name_new.push_str("data/gat/");
-    map_name_new.push_str(&map_name);
+    (map_name, (x, y))
+}
+
+pub async fn game_change_map_server(data: &mut InputMessage) -> MapServerChange {
+    let map_name = data.read_string(Some(16));
+    let x = data.read_u16();
+    let y = data.read_u16();
+    // sent in network order
+    let ip = Ipv4Addr::from(data.read_u32().to_le_bytes());
+    let port = data.read_u16();
+
+    info!(map = %map_name, x, y, server = format_args!("{}:{}", ip, port), "map server changed");
 
-    // testing io
-    let gat_data = io::gat::GatData::parse(&map_name_new);
+    MapServerChange {
+        map_name,
+        position: (x, y),
+        ip,
+        port,
+    }
 }
 
 pub async fn game_item_disappear(data: &mut InputMessage) {
     let aid = data.read_u32();
 }
 
-pub async fn game_param_change(data: &mut InputMessage) {
+/// Returns our new walk speed when it changed.
+pub async fn game_param_change(data: &mut InputMessage) -> Option<u16> {
     let status_type =
         StatusPoint::try_from_primitive(data.read_u16()).expect("invalid status type");
 
@@ -285,6 +595,7 @@ pub async fn game_param_change(data: &mut InputMessage) {
         }
         StatusPoint::SpSpeed => {
             let value = data.read_u32();
+            return Some(value as u16);
         }
         StatusPoint::SpBaselevel => {
             let value = data.read_u32();
@@ -361,6 +672,8 @@ pub async fn game_param_change(data: &mut InputMessage) {
             // Handle default case if needed
         }
     }
+
+    None
 }
 
 pub async fn game_npc_close(data: &mut InputMessage) {
@@ -499,7 +812,6 @@ pub async fn game_inventory_equip_item(data: &mut InputMessage) {
 
     loop {
         if data.is_eof() {
-            println!("inventory equip items done! EOF");
             break;
         }
 
@@ -544,7 +856,6 @@ pub async fn game_inventory_end(data: &mut InputMessage) {
 pub async fn game_equip_switch_list(data: &mut InputMessage) {
     loop {
         if data.is_eof() {
-            println!("equip switch list done! EOF");
             break;
         }
 
@@ -573,7 +884,7 @@ pub async fn game_map_property(data: &mut InputMessage) {
 }
 
 // internal function
-pub async fn parse_unit_data(data: &mut InputMessage, packet_type: GameServer) {
+pub async fn parse_unit_data(data: &mut InputMessage, packet_type: GameServer) -> UnitData {
     let object_type = data.read_u8();
     let aid = data.read_u32();
     let gid = data.read_u32();
@@ -588,9 +899,10 @@ pub async fn parse_unit_data(data: &mut InputMessage, packet_type: GameServer) {
     let accessory = data.read_u16();
 
     // received only when unit is walking
+    let mut move_start_time = 0;
     match packet_type {
         GameServer::UnitWalking => {
-            let move_start_time = data.read_u32();
+            move_start_time = data.read_u32();
         }
         _ => {}
     }
@@ -608,14 +920,19 @@ pub async fn parse_unit_data(data: &mut InputMessage, packet_type: GameServer) {
     let is_pk_mode_on = data.read_u8() == 1;
     let sex = data.read_u8();
 
-    match packet_type {
+    let (position, walk) = match packet_type {
         GameServer::UnitWalking => {
             let move_data = read_move_data(data);
+            (
+                (move_data.from_x, move_data.from_y),
+                Some((move_data, move_start_time)),
+            )
         }
         _ => {
-            let pos_dir = data.read_bytes(3);
+            let (x, y, _dir) = read_pos(data);
+            ((x, y), None)
         }
-    }
+    };
 
     let x_size = data.read_u8();
     let y_size = data.read_u8();
@@ -635,30 +952,43 @@ pub async fn parse_unit_data(data: &mut InputMessage, packet_type: GameServer) {
     let is_boss = data.read_u8();
     let body = data.read_u16();
     let name = data.read_string(Some(24));
+
+    UnitData {
+        id: aid,
+        speed,
+        position,
+        walk,
+    }
 }
 
-pub async fn game_unit_idle(data: &mut InputMessage) {
-    parse_unit_data(data, GameServer::UnitIdle).await;
+pub async fn game_unit_idle(data: &mut InputMessage) -> UnitData {
+    let unit = parse_unit_data(data, GameServer::UnitIdle).await;
 
     if !data.is_eof() {
         panic!("[game_unit_idle] missing bytes to read!");
     }
+
+    unit
 }
 
-pub async fn game_unit_spawn(data: &mut InputMessage) {
-    parse_unit_data(data, GameServer::UnitSpawn).await;
+pub async fn game_unit_spawn(data: &mut InputMessage) -> UnitData {
+    let unit = parse_unit_data(data, GameServer::UnitSpawn).await;
 
     if !data.is_eof() {
         panic!("[game_unit_spawn] missing bytes to read!");
     }
+
+    unit
 }
 
-pub async fn game_unit_walking(data: &mut InputMessage) {
-    parse_unit_data(data, GameServer::UnitWalking).await;
+pub async fn game_unit_walking(data: &mut InputMessage) -> UnitData {
+    let unit = parse_unit_data(data, GameServer::UnitWalking).await;
 
     if !data.is_eof() {
         panic!("[game_unit_walking] missing bytes to read!");
     }
+
+    unit
 }
 
 pub async fn game_unit_changed_dir(data: &mut InputMessage) {
@@ -673,9 +1003,11 @@ pub async fn game_unit_changed_dir(data: &mut InputMessage) {
 ///     2 = logged out
 ///     3 = teleport
 ///     4 = trickdead
-pub async fn game_unit_clear(data: &mut InputMessage) {
+pub async fn game_unit_clear(data: &mut InputMessage) -> u32 {
     let unit_id = data.read_u32();
     let clear_type = data.read_u8();
+
+    unit_id
 }
 
 pub async fn game_screen_active_esft(data: &mut InputMessage) {
@@ -691,7 +1023,6 @@ pub async fn game_screen_active_esft(data: &mut InputMessage) {
 pub async fn game_skill_tree(data: &mut InputMessage) {
     loop {
         if data.is_eof() {
-            println!("skill tree done! EOF");
             break;
         }
 
@@ -810,12 +1141,46 @@ pub async fn game_status_change_2(data: &mut InputMessage) {
     let val3 = data.read_u32();
 }
 
-pub async fn game_packet_handler(
-    stream: &mut TcpStream,
+pub async fn game_name_ack(data: &mut InputMessage) {
+    let gid = data.read_u32();
+    let name = data.read_string(Some(24));
+    let party_name = data.read_string(Some(24));
+    let guild_name = data.read_string(Some(24));
+    let position_name = data.read_string(Some(24));
+    let title_id = data.read_u32();
+
+    debug!(gid, %name, "name received");
+}
+
+/// Returns the restart type the server allowed.
+pub async fn game_restart_ack(data: &mut InputMessage) -> u8 {
+    let restart_type = data.read_u8();
+
+    debug!(restart_type, "restart acked");
+    restart_type
+}
+
+/// Returns true when the server lets us quit, it refuses while in combat.
+pub async fn game_disconnect_ack(data: &mut InputMessage) -> bool {
+    let result = data.read_u16();
+
+    result == 0
+}
+
+pub async fn game_notify_time(clock: &mut ServerClock, data: &mut InputMessage) {
+    let server_tick = data.read_u32();
+    clock.on_notify_time(server_tick);
+
+    debug!(server_tick, rtt = ?clock.rtt(), "server time synced");
+}
+
+/// Returns the events the session owner should know about.
+pub async fn game_packet_handler<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    state: &mut GameState,
     packet_id: u16,
     data: &mut InputMessage,
-) -> bool {
-    println!("Packet ID: {:x}", packet_id);
+) -> Vec<SessionEvent> {
     let packet_id = GameServer::try_from(packet_id)
         .expect(format!("missing packet id {:x}", packet_id).as_str());
 
@@ -830,38 +1195,59 @@ pub async fn game_packet_handler(
             game_notify_change_status(data).await;
         }
         GameServer::AuthOk => {
-            game_auth_ok(data).await;
+            let event = game_auth_ok(data).await;
+            if let SessionEvent::MapAuthOk { x, y } = event {
+                state
+                    .units
+                    .spawn(state.account_id, (x, y), DEFAULT_WALK_SPEED);
+            }
+            // nothing to load, the map data is loaded on connect
+            let map_ready = game_load_end(stream, state).await;
+            return vec![event, map_ready];
         }
         GameServer::DisplayMessage => {
             game_display_message(data).await;
         }
         GameServer::ObjectMove => {
-            game_object_move(stream, data).await;
+            let (object_id, move_data, server_tick) = game_object_move(stream, data).await;
+            state.clock.observe(server_tick);
+            state.unit_walk(object_id, &move_data, server_tick);
         }
         GameServer::WalkSucceeded => {
-            game_walk_succeeded(data).await;
+            let (move_data, server_tick) = game_walk_succeeded(data).await;
+            state.clock.observe(server_tick);
+            state.unit_walk(state.account_id, &move_data, server_tick);
         }
         GameServer::StopPos => {
             // used for fixing pos too
-            game_stop_pos(data).await;
+            let (object_id, position) = game_stop_pos(data).await;
+            state.units.stop(object_id, position);
         }
         GameServer::ObjectAction => {
             game_object_action(data).await;
         }
         GameServer::ObjectAction3 => {
-            game_object_action_3(data).await;
+            let server_tick = game_object_action_3(data).await;
+            state.clock.observe(server_tick);
         }
         GameServer::ChatMessage => {
             game_chat_message(data).await;
         }
         GameServer::ChangeMap => {
-            game_change_map(data).await;
+            let (map_name, position) = game_change_map(data).await;
+            state.enter_map(map_name, position).await;
+            return vec![game_load_end(stream, state).await];
+        }
+        GameServer::ChangeMapServer => {
+            state.server_change = Some(game_change_map_server(data).await);
         }
         GameServer::ItemDisappear => {
             game_item_disappear(data).await;
         }
         GameServer::ParameterChange => {
-            game_param_change(data).await;
+            if let Some(speed) = game_param_change(data).await {
+                state.units.set_speed(state.account_id, speed);
+            }
         }
         GameServer::NpcClose => {
             game_npc_close(data).await;
@@ -886,10 +1272,6 @@ pub async fn game_packet_handler(
         }
         GameServer::WeightLimit => {
             game_weight_limit(data).await;
-            // after this packet, it seems we can start sending our information?
-            game_request_effects_option(stream, 0).await;
-            // tell to server we are all map data ready
-            game_request_ack_map(stream).await;
         }
         GameServer::DropItem => {
             game_drop_item(data).await;
@@ -913,19 +1295,27 @@ pub async fn game_packet_handler(
             game_map_property(data).await;
         }
         GameServer::UnitIdle => {
-            game_unit_idle(data).await;
+            let unit = game_unit_idle(data).await;
+            state.units.spawn(unit.id, unit.position, unit.speed);
         }
         GameServer::UnitSpawn => {
-            game_unit_spawn(data).await;
+            let unit = game_unit_spawn(data).await;
+            state.units.spawn(unit.id, unit.position, unit.speed);
         }
         GameServer::UnitWalking => {
-            game_unit_walking(data).await;
+            let unit = game_unit_walking(data).await;
+            state.units.spawn(unit.id, unit.position, unit.speed);
+            if let Some((move_data, start_tick)) = unit.walk {
+                state.clock.observe(start_tick);
+                state.unit_walk(unit.id, &move_data, start_tick);
+            }
         }
         GameServer::UnitChangedDir => {
             game_unit_changed_dir(data).await;
         }
         GameServer::UnitClear => {
-            game_unit_clear(data).await;
+            let unit_id = game_unit_clear(data).await;
+            state.units.remove(unit_id);
         }
         GameServer::ScreenActiveEFST => {
             game_screen_active_esft(data).await;
@@ -961,15 +1351,64 @@ pub async fn game_packet_handler(
         GameServer::StatusChange2 => {
             game_status_change_2(data).await;
         }
+        GameServer::NotifyTime => {
+            game_notify_time(&mut state.clock, data).await;
+        }
+        GameServer::NameAck => {
+            game_name_ack(data).await;
+        }
+        GameServer::RestartAck => {
+            if game_restart_ack(data).await == RestartType::CharSelect as u8 {
+                state.char_select = state.pending_char_select.take();
+            }
+        }
+        GameServer::DisconnectAck => {
+            // also the answer when going back to char select is refused
+            if game_disconnect_ack(data).await {
+                info!("quit acked");
+                state.disconnected = true;
+            } else {
+                warn!("server refused to let us leave");
+                state.quitting = false;
+                state.pending_char_select = None;
+            }
+        }
         _ => {
-            println!("Unknown packet id: {:x}", packet_id as u16);
+            debug!(
+                packet_id = format_args!("0x{:04X}", packet_id as u16),
+                "packet not handled"
+            );
         }
     }
 
-    return true;
+    return Vec::new();
+}
+
+/// Sends what the session owner asked for. A failed send ends the stage,
+/// a quit included: nothing would ack it.
+async fn game_run_command<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    state: &mut GameState,
+    command: SessionCommand,
+) -> io::Result<()> {
+    match command {
+        SessionCommand::WalkTo { x, y } => game_request_walk_to(stream, x, y, 0).await,
+        SessionCommand::Chat(message) => game_request_chat_message(stream, &message).await,
+        SessionCommand::Respawn => game_request_restart(stream, RestartType::Respawn).await?,
+        SessionCommand::CharSelect { slot } => {
+            game_request_restart(stream, RestartType::CharSelect).await?;
+            state.pending_char_select = Some(slot);
+        }
+        SessionCommand::Quit => {
+            // leaving on purpose, even if the server never hears about it
+            state.quitting = true;
+            game_request_quit(stream).await?;
+        }
+    }
+    Ok(())
 }
 
-pub async fn game_listener(stream: &mut TcpStream) {
+pub async fn game_listener(stream: &mut TcpStream, state: &mut GameState, link: &mut SessionLink) {
     let mut data_packet_id: u16 = u16::MAX;
 
     let mut total_read: usize = 0;
@@ -979,22 +1418,36 @@ pub async fn game_listener(stream: &mut TcpStream) {
     let mut parse_len = false;
     let mut has_packet_len = false;
 
-    println!("[game_listener] listening for packets..");
+    debug!("listening for packets");
 
-    let game_packets_len;
-    unsafe {
-        game_packets_len = GAME_PACKETS_LEN.as_ref().unwrap();
-    }
+    let game_packets_len = &*GAME_PACKETS_LEN;
+
+    // keeps the connection alive when idle, the server drops silent clients
+    let keepalive_period = Duration::from_secs(CLIENT_TICK_INTERVAL_SECS);
+    let mut keepalive = interval_at(Instant::now() + keepalive_period, keepalive_period);
+    keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     loop {
-        stream.readable().await.expect("stream not readable");
-        // print total read and packet len
-        //println!("total read: {}, packet len: {}", total_read, packet_len);
-        let read_result = stream.read(&mut buffer[total_read..packet_len]).await;
+        // reading is cancel safe, commands and ticks can come between two reads
+        let read_result = tokio::select! {
+            read_result = stream.read(&mut buffer[total_read..packet_len]) => read_result,
+            // commands wait until the server sees us on the map
+            Some(command) = link.next_command(), if state.ready => {
+                if let Err(e) = game_run_command(stream, state, command).await {
+                    warn!(error = %e, "failed to send command");
+                    break;
+                }
+                continue;
+            }
+            _ = keepalive.tick() => {
+                game_request_client_tick(stream, state.clock.tick_sent()).await;
+                continue;
+            }
+        };
         match read_result {
             Ok(n) => {
                 if n == 0 {
-                    println!("Connection closed by server");
+                    info!("connection closed by server");
                     break;
                 }
 
@@ -1002,9 +1455,6 @@ pub async fn game_listener(stream: &mut TcpStream) {
 
                 //println!("total read: {}", total_read);
 
-                // print the buffer as hexadecimal bytes, for debugging purposes
-                // println!("{:02X?}", &buffer[0..total_read]);
-
                 // read some data
                 if !parse_len && data_packet_id != u16::MAX && total_read == packet_len {
                     let header_size = if has_packet_len {
@@ -1014,24 +1464,29 @@ pub async fn game_listener(stream: &mut TcpStream) {
                     };
 
                     //println!("header size: {}, total read {}", header_size, total_read);
+                    recorder::record(Stage::Map, Direction::Received, &buffer[0..packet_len]);
+
                     let mut input_message =
                         InputMessage::new(buffer[header_size..packet_len].to_vec());
                     let result =
-                        game_packet_handler(stream, data_packet_id, &mut input_message).await;
-                    match result {
-                        true => {
-                            println!("packet id {:x} handled", data_packet_id);
-                            // reset packet_len to read the next packet
-                            packet_len = PACKET_HEADER_LEN as usize;
-                            data_packet_id = u16::MAX;
-                            parse_len = false;
-                            has_packet_len = false;
-                            total_read = 0;
-                        }
-                        false => {
-                            break;
-                        }
+                        game_packet_handler(stream, state, data_packet_id, &mut input_message)
+                            .await;
+                    for event in result {
+                        link.emit(event);
+                    }
+                    if state.server_change.is_some() {
+                        debug!("leaving for another map server");
+                        break;
+                    }
+                    if state.disconnected || state.char_select.is_some() {
+                        break;
                     }
+                    // reset packet_len to read the next packet
+                    packet_len = PACKET_HEADER_LEN as usize;
+                    data_packet_id = u16::MAX;
+                    parse_len = false;
+                    has_packet_len = false;
+                    total_read = 0;
                     continue;
                 }
 
@@ -1041,6 +1496,8 @@ pub async fn game_listener(stream: &mut TcpStream) {
                     parse_len = false;
 
                     if packet_len == total_read {
+                        recorder::record(Stage::Map, Direction::Received, &buffer[0..packet_len]);
+
                         // we read all the data, we should reset to next packet data!
                         packet_len = PACKET_HEADER_LEN as usize;
                         data_packet_id = u16::MAX;
@@ -1056,8 +1513,6 @@ pub async fn game_listener(stream: &mut TcpStream) {
                     // parse packet id first
                     data_packet_id = u16::from_le_bytes([buffer[0], buffer[1]]);
 
-                    println!("pckt id: {:x}", data_packet_id);
-
                     let result = game_packets_len.get(&data_packet_id);
                     match result {
                         Some(&len) => {
@@ -1078,84 +1533,233 @@ pub async fn game_listener(stream: &mut TcpStream) {
                 }
             }
             Err(e) => {
-                println!("Failed to read from stream: {}", e);
+                warn!(error = %e, "failed to read from stream");
                 break;
             }
         }
     }
 }
 
+/// Returns the slot to select when the server sent us back to the
+/// character list.
 pub async fn initialize(
-    ip: &str,
-    port: u16,
+    map_server: &MapServerData,
     acc_id: u32,
-    char_id: u32,
     login_id: u32,
     client_tick: u32,
     sex: u8,
-) {
-    unsafe {
-        let mut game_packets_len = HashMap::new();
-        game_packets_len.insert(GameServer::MapBlockList as u16, 4);
-        game_packets_len.insert(GameServer::InventoryExpansionInfo as u16, 2);
-        game_packets_len.insert(GameServer::NotifyChangeStatus as u16, 8);
-        game_packets_len.insert(GameServer::AuthOk as u16, 11);
-        game_packets_len.insert(GameServer::DisplayMessage as u16, u16::MAX);
-        game_packets_len.insert(GameServer::ObjectMove as u16, 14);
-        game_packets_len.insert(GameServer::WalkSucceeded as u16, 10);
-        game_packets_len.insert(GameServer::StopPos as u16, 8);
-        game_packets_len.insert(GameServer::ObjectAction as u16, 27);
-        game_packets_len.insert(GameServer::ObjectAction3 as u16, 32);
-        game_packets_len.insert(GameServer::ChatMessage as u16, u16::MAX);
-        game_packets_len.insert(GameServer::ChangeMap as u16, 20);
-        game_packets_len.insert(GameServer::ItemDisappear as u16, 4);
-        game_packets_len.insert(GameServer::ParameterChange as u16, 6);
-        game_packets_len.insert(GameServer::NpcClose as u16, 4);
-        game_packets_len.insert(GameServer::CoupleStatus as u16, 12);
-        game_packets_len.insert(GameServer::AtkRange as u16, 2);
-        game_packets_len.insert(GameServer::MailUnread as u16, 1);
-        game_packets_len.insert(GameServer::QuestsStateList as u16, u16::MAX);
-        game_packets_len.insert(GameServer::SingleAchievementData as u16, 64);
-        game_packets_len.insert(GameServer::AllAchievementsData as u16, u16::MAX);
-        game_packets_len.insert(GameServer::WeightLimit as u16, 4);
-        game_packets_len.insert(GameServer::DropItem as u16, 22);
-        game_packets_len.insert(GameServer::SpriteChange as u16, 13);
-        game_packets_len.insert(GameServer::InventoryStart as u16, u16::MAX);
-        game_packets_len.insert(GameServer::InventoryType as u16, u16::MAX);
-        game_packets_len.insert(GameServer::InventoryEnd as u16, 2);
-        game_packets_len.insert(GameServer::EquipSwitchList as u16, u16::MAX);
-        game_packets_len.insert(GameServer::MapProperty as u16, 6);
-        game_packets_len.insert(GameServer::UnitIdle as u16, u16::MAX);
-        game_packets_len.insert(GameServer::UnitSpawn as u16, u16::MAX);
-        game_packets_len.insert(GameServer::UnitWalking as u16, u16::MAX);
-        game_packets_len.insert(GameServer::UnitChangedDir as u16, 7);
-        game_packets_len.insert(GameServer::UnitClear as u16, 5);
-        game_packets_len.insert(GameServer::ScreenActiveEFST as u16, 26);
-        game_packets_len.insert(GameServer::SkillTree as u16, u16::MAX);
-        game_packets_len.insert(GameServer::ShortcutsKeyList as u16, 269);
-        game_packets_len.insert(GameServer::LongParameterChange as u16, 10);
-        game_packets_len.insert(GameServer::CharacterStatus as u16, 42);
-        game_packets_len.insert(GameServer::UpdateStatus as u16, 3);
-        game_packets_len.insert(GameServer::PartyInvitationState as u16, 1);
-        game_packets_len.insert(GameServer::EquipWindowOpen as u16, 1);
-        game_packets_len.insert(GameServer::ConfigurationChange as u16, 8);
-        game_packets_len.insert(GameServer::StatusChange as u16, 7);
-        game_packets_len.insert(GameServer::StatusChange2 as u16, 27);
-
-        GAME_PACKETS_LEN = Some(game_packets_len);
-    }
+    link: &mut SessionLink,
+) -> Option<u8> {
+    let char_id = map_server.char_id;
+    let mut server_addr = format!("{}:{}", map_server.ip, map_server.port);
+    let mut map_name = map_server.map_name.clone();
+    // the same session moves between map servers, the caller never sees it
+    loop {
+        let state = async {
+            let stream = TcpStream::connect(&server_addr).await;
+            match stream {
+                Ok(mut stream) => {
+                    info!("connected");
+                    link.emit(SessionEvent::Connected(Stage::Map));
+                    // send connect to map server
+                    game_connect_map_server(
+                        &mut stream,
+                        acc_id,
+                        char_id,
+                        login_id,
+                        client_tick,
+                        sex,
+                    )
+                    .await;
+                    let map = load_map(&map_name).await;
+                    let mut state = GameState::new(acc_id, &map_name, map);
+                    game_listener(&mut stream, &mut state, link).await;
+                    // the server may close right away instead of acking
+                    if state.disconnected || state.quitting {
+                        link.emit(SessionEvent::Disconnected);
+                    } else if state.server_change.is_none() && state.char_select.is_none() {
+                        link.fail(Stage::Map, "connection closed");
+                    }
+                    Some(state)
+                }
+                Err(e) => {
+                    warn!(error = %e, "failed to connect");
+                    link.fail(Stage::Map, &e.to_string());
+                    None
+                }
+            }
+        }
+        .instrument(info_span!("map", server = %server_addr, char_id))
+        .await;
 
-    let server_addr = format!("{}:{}", ip, port);
-    let stream = TcpStream::connect(server_addr).await;
-    match stream {
-        Ok(mut stream) => {
-            println!("Connected to game server: {}:{}", ip, port);
-            // send connect to map server
-            game_connect_map_server(&mut stream, acc_id, char_id, login_id, client_tick, sex).await;
-            game_listener(&mut stream).await;
+        let state = state?;
+        if state.char_select.is_some() {
+            info!("back to character select");
+            return state.char_select;
         }
-        Err(e) => {
-            println!("Failed to connect to server: {}", e);
+        let server_change = state.server_change?;
+        server_addr = format!("{}:{}", server_change.ip, server_change.port);
+        map_name = server_change.map_name;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        mock_server::{next_event, start_session, MockScript, MockServer, MockStage, PacketWriter},
+        protocol::login::SessionOptions,
+    };
+
+    use super::*;
+
+    /// Runs the handler on a whole packet, as the listener does.
+    async fn handle_into<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        state: &mut GameState,
+        packet: Vec<u8>,
+    ) -> Vec<SessionEvent> {
+        let packet_id = u16::from_le_bytes([packet[0], packet[1]]);
+        let header_size = match game_packets_len()[&packet_id] {
+            u16::MAX => 4,
+            _ => 2,
+        };
+        let mut data = InputMessage::new(packet[header_size..].to_vec());
+        game_packet_handler(stream, state, packet_id, &mut data).await
+    }
+
+    async fn handle(state: &mut GameState, packet: Vec<u8>) -> Vec<SessionEvent> {
+        handle_into(&mut tokio::io::sink(), state, packet).await
+    }
+
+    #[tokio::test]
+    async fn walk_packets_move_units() {
+        let mut state = GameState {
+            account_id: 2000001,
+            ..GameState::default()
+        };
+
+        let mut auth_ok = PacketWriter::new(GameServer::AuthOk as u16);
+        auth_ok.u32(0).pos(100, 100, 0).u8(5).u8(5).u16(0);
+        let events = handle(&mut state, auth_ok.finish()).await;
+        assert_eq!(
+            events.last(),
+            Some(&SessionEvent::MapReady {
+                map_name: String::new(),
+                x: 100,
+                y: 100
+            })
+        );
+        assert!(state.ready);
+
+        let mut speed = PacketWriter::new(GameServer::ParameterChange as u16);
+        speed.u16(StatusPoint::SpSpeed as u16).u32(200);
+        handle(&mut state, speed.finish()).await;
+
+        let mut walk = PacketWriter::new(GameServer::WalkSucceeded as u16);
+        walk.u32(50_000).move_data((100, 100), (104, 100));
+        handle(&mut state, walk.finish()).await;
+
+        assert_eq!(state.units.cell_at(2000001, 50_399), Some((101, 100)));
+        assert_eq!(state.units.cell_at(2000001, 50_400), Some((102, 100)));
+        assert_eq!(state.units.cell_at(2000001, 60_000), Some((104, 100)));
+
+        // the server stopped us early
+        let mut stop = PacketWriter::new(GameServer::StopPos as u16);
+        stop.u32(2000001).u16(102).u16(100);
+        handle(&mut state, stop.finish()).await;
+        assert_eq!(state.units.cell_at(2000001, 60_000), Some((102, 100)));
+
+        let mut object_move = PacketWriter::new(GameServer::ObjectMove as u16);
+        object_move
+            .u32(110001)
+            .move_data((90, 90), (90, 95))
+            .u32(55_000);
+        handle(&mut state, object_move.finish()).await;
+        assert_eq!(state.units.cell_at(110001, 55_300), Some((90, 92)));
+
+        let mut clear = PacketWriter::new(GameServer::UnitClear as u16);
+        clear.u32(110001).u8(0);
+        handle(&mut state, clear.finish()).await;
+        assert_eq!(state.units.cell_at(110001, 55_300), None);
+    }
+
+    #[tokio::test]
+    async fn change_map_resets_units_and_acks_again() {
+        let mut state = GameState {
+            account_id: 2000001,
+            ready: true,
+            ..GameState::default()
+        };
+        state.units.spawn(2000001, (10, 10), 120);
+        state.units.spawn(110001, (12, 12), 150);
+
+        let mut change_map = PacketWriter::new(GameServer::ChangeMap as u16);
+        change_map.string("geffen.gat", 16).u16(120).u16(60);
+        let mut sent = Vec::new();
+        let events = handle_into(&mut sent, &mut state, change_map.finish()).await;
+
+        assert_eq!(
+            events,
+            vec![SessionEvent::MapReady {
+                map_name: "geffen.gat".to_string(),
+                x: 120,
+                y: 60
+            }]
+        );
+        assert_eq!(state.units.len(), 1);
+        assert_eq!(state.units.get(2000001).unwrap().speed, 120);
+        assert_eq!(&sent[..2], &(GameClient::AckMap as u16).to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn failed_quit_ends_the_stage() {
+        let (mut stream, server) = tokio::io::duplex(64);
+        drop(server);
+        let mut state = GameState::default();
+
+        let sent = game_run_command(&mut stream, &mut state, SessionCommand::Quit).await;
+        assert!(sent.is_err());
+        assert!(state.quitting);
+    }
+
+    #[tokio::test]
+    async fn map_server_change_reconnects() {
+        let second = MockServer::start(MockScript::default()).await;
+
+        let mut server_move = PacketWriter::new(GameServer::ChangeMapServer as u16);
+        server_move
+            .string("geffen.gat", 16)
+            .u16(120)
+            .u16(60)
+            .bytes(&[127, 0, 0, 1])
+            .u16(second.map_addr.port());
+        let script = MockScript::default().on(
+            MockStage::Map,
+            GameClient::AckMap as u16,
+            vec![server_move.finish()],
+        );
+
+        let (_first, mut session) =
+            start_session(script, "mock123", SessionOptions::default()).await;
+        second
+            .wait_for(
+                MockStage::Map,
+                GameClient::RequestName as u16,
+                Duration::from_secs(5),
+            )
+            .await
+            .expect("client never entered the second map server");
+
+        let mut ready = Vec::new();
+        while ready.len() < 2 {
+            match next_event(&mut session).await.expect("session ended") {
+                SessionEvent::MapReady { map_name, .. } => ready.push(map_name),
+                SessionEvent::Failed { stage, reason } => {
+                    panic!("session failed at {:?}: {}", stage, reason)
+                }
+                _ => {}
+            }
         }
+        assert_eq!(ready, vec!["prontera.gat", "geffen.gat"]);
     }
 }
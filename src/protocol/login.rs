@@ -1,45 +1,277 @@
-#[derive(num_enum::TryFromPrimitive)]
+#[derive(num_enum::TryFromPrimitive, Debug)]
 #[repr(u16)]
 pub enum LoginServer {
     AuthOk = 0x0AC4,
     AuthResult = 0x0081,
+    RefuseLogin = 0x083E,
+    RefuseLoginOld = 0x006A, // same as RefuseLogin, with a u8 code
+    AckHash = 0x01DC,        // key for ReqAuthHashed
 }
 
+#[derive(num_enum::TryFromPrimitive, Debug)]
 #[repr(u16)]
 pub enum LoginClient {
     UDPCLHASH = 0x0204,
     REQAUTH = 0x0064,
+    ReqHash = 0x01DB,
+    ReqAuthHashed = 0x01DD, // REQAUTH with MD5(key + password)
+    SsoLogin = 0x0825,      // token instead of a password
 }
 
 use crate::{
-    client::network::write_message, r#const::{LOGIN_SERVER_ADDR, PACKET_HEADER_LEN}, enums, input_message::InputMessage, network_message::NetworkMessage, protocol::character_list
+    capture::{recorder, Direction, Stage},
+    client::network::write_message,
+    enums,
+    input_message::InputMessage,
+    metrics,
+    network_message::NetworkMessage,
+    protocol::{
+        character_list,
+        session::{ReconnectPolicy, Session, SessionCommand, SessionEvent, SessionLink},
+    },
+    r#const::PACKET_HEADER_LEN,
+};
+use md5::{Digest, Md5};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{Ipv4Addr, SocketAddrV4},
+    sync::{Arc, LazyLock},
 };
-use std::collections::HashMap;
 use tokio::{io::AsyncReadExt, net::TcpStream};
+use tracing::{debug, info, info_span, warn, Instrument};
 
-pub static mut LOGIN_PACKETS_LEN: Option<HashMap<u16, u16>> = None;
+/// Built once, shared by every session.
+pub static LOGIN_PACKETS_LEN: LazyLock<HashMap<u16, u16>> = LazyLock::new(login_packets_len);
 pub static MAX_CREDENTIAL_LEN: u8 = 23; // 23 = len | 1 = null-terminator reserved
+static CLIENT_VERSION: u32 = 0x80000001;
+static CLIENT_TYPE: u8 = 2;
+
+/// What the char server needs, once the login server accepted us.
+pub struct LoginAccepted {
+    pub login_id: u32,
+    pub acc_id: u32,
+    pub login_id_2: u32,
+    pub sex: u8,
+    /// Char servers, in the order the login server listed them.
+    pub servers: Vec<ServerInfo>,
+}
+
+/// A char server entry of `AuthOk`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ServerInfo {
+    pub name: String,
+    pub ip: Ipv4Addr,
+    pub port: u16,
+    pub users: u16,
+    /// 0 normal, 1 maintenance, 2 over 18, 3 paying, 4 free to play.
+    pub server_type: u16,
+    pub is_new: bool,
+}
+
+impl ServerInfo {
+    pub fn addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.ip, self.port)
+    }
+}
+
+/// Picks a char server from the list, `None` ends the session.
+pub type ServerPicker = Arc<dyn Fn(&[ServerInfo]) -> Option<usize> + Send + Sync>;
+
+/// Which char server to connect to once logged in.
+#[derive(Clone)]
+pub enum ServerSelect {
+    /// Position in the server list.
+    Index(usize),
+    /// Picks from the server list, `None` ends the session.
+    Callback(ServerPicker),
+}
+
+impl ServerSelect {
+    pub fn select<'a>(&self, servers: &'a [ServerInfo]) -> Option<&'a ServerInfo> {
+        let index = match self {
+            ServerSelect::Index(index) => *index,
+            ServerSelect::Callback(callback) => callback(servers)?,
+        };
+        servers.get(index)
+    }
+}
+
+/// The first server, like the client has it selected.
+impl Default for ServerSelect {
+    fn default() -> Self {
+        ServerSelect::Index(0)
+    }
+}
+
+impl fmt::Debug for ServerSelect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerSelect::Index(index) => f.debug_tuple("Index").field(index).finish(),
+            ServerSelect::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
 
-async fn login_packet_handler(packet_id: u16, data: &mut InputMessage) -> bool {
-    println!("Packet ID: {:x}", packet_id);
+/// Why the login server refused us.
+#[derive(Clone, PartialEq, Debug)]
+pub enum LoginError {
+    UnregisteredId,
+    IncorrectPassword,
+    Expired,
+    Rejected,
+    BlockedByGm,
+    ClientVersion,
+    /// Banned until `until`, in server time and in the server date format
+    /// (`%Y-%m-%d %H:%M:%S` by default on rAthena).
+    Banned {
+        until: String,
+    },
+    ServerFull,
+    ServerClosed,
+    AlreadyLoggedWithId,
+    AlreadyOnline,
+    /// Refused with a code not listed here, never retried since it may as
+    /// well be for good.
+    Unknown(u32),
+}
+
+impl LoginError {
+    /// Decodes the code of `RefuseLogin`, `until` is only set for bans.
+    pub fn from_refuse_code(code: u32, until: String) -> LoginError {
+        match code {
+            0 => LoginError::UnregisteredId,
+            1 => LoginError::IncorrectPassword,
+            2 => LoginError::Expired,
+            3 => LoginError::Rejected,
+            4 => LoginError::BlockedByGm,
+            5 => LoginError::ClientVersion,
+            6 => LoginError::Banned { until },
+            7 => LoginError::ServerFull,
+            _ => LoginError::Unknown(code),
+        }
+    }
+
+    /// Whether logging in again later may work, a wrong password will not.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            LoginError::ServerFull
+                | LoginError::ServerClosed
+                | LoginError::AlreadyLoggedWithId
+                | LoginError::AlreadyOnline
+        )
+    }
+}
+
+impl From<enums::AuthResult> for LoginError {
+    fn from(result: enums::AuthResult) -> Self {
+        match result {
+            enums::AuthResult::ServerClosed => LoginError::ServerClosed,
+            enums::AuthResult::AlreadyLoggedWithId => LoginError::AlreadyLoggedWithId,
+            enums::AuthResult::AlreadyOnline => LoginError::AlreadyOnline,
+        }
+    }
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginError::UnregisteredId => write!(f, "unregistered id"),
+            LoginError::IncorrectPassword => write!(f, "incorrect password"),
+            LoginError::Expired => write!(f, "account expired"),
+            LoginError::Rejected => write!(f, "rejected by the server"),
+            LoginError::BlockedByGm => write!(f, "blocked by the GM team"),
+            LoginError::ClientVersion => write!(f, "client version not accepted"),
+            LoginError::Banned { until } => write!(f, "banned until {}", until),
+            LoginError::ServerFull => write!(f, "server full"),
+            LoginError::ServerClosed => write!(f, "server closed"),
+            LoginError::AlreadyLoggedWithId => write!(f, "already logged with id"),
+            LoginError::AlreadyOnline => write!(f, "already online"),
+            LoginError::Unknown(code) => write!(f, "refused with unknown code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+/// How the password is sent to the login server.
+#[derive(Clone, Copy, PartialEq, Default, Debug)]
+pub enum LoginMode {
+    /// `REQAUTH`, the password as is.
+    #[default]
+    Plaintext,
+    /// Asks for a key first and sends MD5(key + password), for servers with
+    /// `use_MD5_passwords`.
+    Md5Challenge,
+    /// `SsoLogin`, the password is a token, e.g. from a web launcher.
+    Token,
+}
+
+/// How `start_with` runs a session.
+#[derive(Clone, Default, Debug)]
+pub struct SessionOptions {
+    pub mode: LoginMode,
+    pub server: ServerSelect,
+    /// Start over from login when a stage fails, see `start_with_reconnect`.
+    pub reconnect: Option<ReconnectPolicy>,
+}
+
+/// Length of the packets we send, for tools reading both directions.
+pub fn login_client_packets_len() -> HashMap<u16, u16> {
+    let mut login_client_packets_len = HashMap::new();
+    login_client_packets_len.insert(LoginClient::UDPCLHASH as u16, 16);
+    login_client_packets_len.insert(LoginClient::REQAUTH as u16, 53);
+    login_client_packets_len.insert(LoginClient::ReqHash as u16, 0);
+    login_client_packets_len.insert(LoginClient::ReqAuthHashed as u16, 45);
+    login_client_packets_len.insert(LoginClient::SsoLogin as u16, u16::MAX);
+    login_client_packets_len
+}
+
+pub fn login_packets_len() -> HashMap<u16, u16> {
+    let mut login_packets_len = HashMap::new();
+    login_packets_len.insert(LoginServer::AuthOk as u16, u16::MAX as u16);
+    login_packets_len.insert(LoginServer::AuthResult as u16, 1);
+    login_packets_len.insert(LoginServer::RefuseLogin as u16, 24);
+    login_packets_len.insert(LoginServer::RefuseLoginOld as u16, 21);
+    login_packets_len.insert(LoginServer::AckHash as u16, u16::MAX);
+    login_packets_len
+}
+
+/// Returns the answer to our login once the server sends it, accepted or
+/// refused, which ends the listener.
+pub async fn login_packet_handler(
+    packet_id: u16,
+    data: &mut InputMessage,
+) -> Option<Result<LoginAccepted, LoginError>> {
     let packet_id = LoginServer::try_from(packet_id)
         .expect(format!("missing packet id {:x}", packet_id).as_str());
 
     match packet_id {
         LoginServer::AuthOk => {
-            login_auth_ok(data).await;
-            return false; // break listener loop
+            return Some(Ok(login_auth_ok(data).await)); // break listener loop
         }
         LoginServer::AuthResult => {
-            login_auth_result(data).await;
+            return Some(Err(login_auth_result(data).await));
+        }
+        LoginServer::RefuseLogin => {
+            let code = data.read_u32();
+            return Some(Err(login_refuse(code, data).await));
+        }
+        LoginServer::RefuseLoginOld => {
+            let code = data.read_u8() as u32;
+            return Some(Err(login_refuse(code, data).await));
+        }
+        LoginServer::AckHash => {
+            // read right after ReqHash, see `client_request_hash_key`
+            debug!("hash key out of a md5 login");
+            return None;
         }
     }
-
-    return true;
 }
 
 // parse packets
-async fn login_auth_ok(data: &mut InputMessage) {
+async fn login_auth_ok(data: &mut InputMessage) -> LoginAccepted {
     let login_id: u32 = data.read_u32();
     let acc_id: u32 = data.read_u32();
     let login_id_2: u32 = data.read_u32();
@@ -49,58 +281,61 @@ async fn login_auth_ok(data: &mut InputMessage) {
     let gender = data.read_u8();
     let web_token = data.read_string(None);
 
-    loop {
-        let server_ip = data.read_u32();
-        let server_port = data.read_u16();
-        let server_name = data.read_string(Some(20));
-        let server_users = data.read_u16();
+    let mut servers = Vec::new();
+    while !data.is_eof() {
+        // sent in network order
+        let ip = Ipv4Addr::from(data.read_u32().to_le_bytes());
+        let port = data.read_u16();
+        let name = data.read_string(Some(20));
+        let users = data.read_u16();
         let server_type = data.read_u16();
-        let server_is_new = data.read_u16();
+        let is_new = data.read_u16() != 0;
         data.skip_bytes(128); // unknown bytes
 
-        // print ip, port and name, in a readable format
-        // ip to string
-        let ip_str = format!(
-            "{}.{}.{}.{}",
-            (server_ip & 0xFF),
-            (server_ip >> 8 & 0xFF),
-            (server_ip >> 16 & 0xFF),
-            (server_ip >> 24 & 0xFF)
+        info!(
+            %name,
+            addr = format_args!("{}:{}", ip, port),
+            users,
+            server_type,
+            is_new,
+            "char server"
         );
 
-        println!("Server: {}:{} - {}", ip_str, server_port, server_name);
-
-        if data.is_eof() {
-            tokio::spawn(async move {
-                character_list::initialize(&ip_str, server_port, login_id, login_id_2, acc_id, gender).await;
-            });
-            break;
-        }
+        servers.push(ServerInfo {
+            name,
+            ip,
+            port,
+            users,
+            server_type,
+            is_new,
+        });
     }
 
-    println!("Done reading servers");
+    LoginAccepted {
+        login_id,
+        acc_id,
+        login_id_2,
+        sex: gender,
+        servers,
+    }
 }
 
-async fn login_auth_result(data: &mut InputMessage) {
-    let result = enums::AuthResult::try_from(data.read_u8());
-    match result {
-        Ok(result) => match result {
-            enums::AuthResult::ServerClosed => {
-                println!("Server closed");
-            }
-            enums::AuthResult::AlreadyLoggedWithId => {
-                println!("Already logged with id");
-            }
-            enums::AuthResult::AlreadyOnline => {
-                println!("Already online");
-            }
-        },
-        Err(e) => {
-            println!("Failed to parse auth result: {}", e);
-        }
+async fn login_auth_result(data: &mut InputMessage) -> LoginError {
+    let code = data.read_u8();
+    match enums::AuthResult::try_from(code) {
+        Ok(result) => result.into(),
+        Err(_) => LoginError::Unknown(code as u32),
     }
 }
 
+/// Rest of `RefuseLogin` once the code is read, the ban date is empty
+/// unless banned.
+async fn login_refuse(code: u32, data: &mut InputMessage) -> LoginError {
+    let until = data.read_string(Some(20));
+
+    LoginError::from_refuse_code(code, until)
+}
+
 // 04 02 a2 cc 00 00 04 02 82 d1 2c 91 4f 5a d4 8f d9 6f cf 7e f4 cc 49 2d
 async fn client_send_udpclhash(stream: &mut TcpStream) {
     // send udpclhash packet
@@ -115,15 +350,14 @@ async fn client_send_udpclhash(stream: &mut TcpStream) {
         network_message.add(x);
     }
 
-    let _ = write_message(stream, &network_message).await;
+    let _ = write_message(stream, Stage::Login, &network_message).await;
 }
 
 // 55 bytes total?
 async fn client_send_reqauth(stream: &mut TcpStream, username: String, password: String) {
     let mut network_message = NetworkMessage::new();
     network_message.add(LoginClient::REQAUTH as u16);
-    // 4 unknown bytes?
-    network_message.add(0x80000001 as u32);
+    network_message.add(CLIENT_VERSION);
 
     // username, 24 bytes (length: MAX_USERNAME_LEN)
     network_message.add_string(username.as_str());
@@ -136,12 +370,99 @@ async fn client_send_reqauth(stream: &mut TcpStream, username: String, password:
     network_message.skip_bytes((MAX_CREDENTIAL_LEN - password.len() as u8) as usize);
 
     // client_type
-    network_message.add(0x00000002 as u8);
+    network_message.add(CLIENT_TYPE);
+
+    let _ = write_message(stream, Stage::Login, &network_message).await;
+}
+
+/// Asks for the key of a md5 login, the server answers it right away.
+async fn client_request_hash_key(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut network_message = NetworkMessage::new();
+    network_message.add(LoginClient::ReqHash as u16);
+    let _ = write_message(stream, Stage::Login, &network_message).await;
+
+    let mut header = [0u8; 4];
+    if let Err(e) = stream.read_exact(&mut header).await {
+        warn!(error = %e, "failed to read hash key");
+        return None;
+    }
+    let packet_id = u16::from_le_bytes([header[0], header[1]]);
+    let packet_len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    if packet_id != LoginServer::AckHash as u16 || packet_len < header.len() {
+        warn!(
+            packet_id = format_args!("0x{:04X}", packet_id),
+            "expected the hash key"
+        );
+        return None;
+    }
+
+    let mut packet = header.to_vec();
+    packet.resize(packet_len, 0);
+    if let Err(e) = stream.read_exact(&mut packet[header.len()..]).await {
+        warn!(error = %e, "failed to read hash key");
+        return None;
+    }
+    recorder::record(Stage::Login, Direction::Received, &packet);
+
+    Some(packet.split_off(header.len()))
+}
+
+/// `REQAUTH` with MD5(key + password) in place of the password.
+async fn client_send_reqauth_hashed(
+    stream: &mut TcpStream,
+    username: &str,
+    password: &str,
+    key: &[u8],
+) {
+    let mut hasher = Md5::new();
+    hasher.update(key);
+    hasher.update(password.as_bytes());
+    let password_md5 = hasher.finalize();
+
+    let mut network_message = NetworkMessage::new();
+    network_message.add(LoginClient::ReqAuthHashed as u16);
+    network_message.add(CLIENT_VERSION);
 
-    let _ = write_message(stream, &network_message).await;
+    network_message.add_string(username);
+    network_message.skip_bytes((MAX_CREDENTIAL_LEN - username.len() as u8) as usize);
+
+    for x in password_md5 {
+        network_message.add(x);
+    }
+    network_message.add(CLIENT_TYPE);
+
+    let _ = write_message(stream, Stage::Login, &network_message).await;
 }
 
-async fn login_listener(stream: &mut TcpStream) {
+/// `SsoLogin`: the token goes last, the password field stays empty.
+async fn client_send_sso_login(stream: &mut TcpStream, username: &str, token: &str) {
+    let mut network_message = NetworkMessage::new();
+    network_message.add(LoginClient::SsoLogin as u16);
+    network_message.add(0u16); // packet len, set below
+    network_message.add(CLIENT_VERSION);
+    network_message.add(CLIENT_TYPE);
+
+    network_message.add_string(username);
+    network_message.skip_bytes((MAX_CREDENTIAL_LEN - username.len() as u8) as usize);
+
+    network_message.skip_bytes(27); // password
+    network_message.add_string("111111111111"); // mac address, 17 bytes
+    network_message.skip_bytes(4);
+    network_message.add_string("127.0.0.1"); // ip, 15 bytes
+    network_message.skip_bytes(5);
+
+    for x in token.bytes() {
+        network_message.add(x);
+    }
+
+    let packet_len = network_message.length;
+    network_message.buffer[2..4].copy_from_slice(&packet_len.to_le_bytes());
+
+    let _ = write_message(stream, Stage::Login, &network_message).await;
+}
+
+/// Returns `None` when the server closes without answering.
+async fn login_listener(stream: &mut TcpStream) -> Option<Result<LoginAccepted, LoginError>> {
     let mut data_packet_id: u16 = u16::MAX;
 
     let mut total_read: usize = 0;
@@ -151,12 +472,9 @@ async fn login_listener(stream: &mut TcpStream) {
     let mut parse_len = false;
     let mut has_packet_len = false;
 
-    println!("[login_listener] listening for packets..");
+    debug!("listening for packets");
 
-    let login_packets_len;
-    unsafe {
-        login_packets_len = LOGIN_PACKETS_LEN.as_ref().unwrap();
-    }
+    let login_packets_len = &*LOGIN_PACKETS_LEN;
 
     loop {
         stream.readable().await.expect("stream not readable");
@@ -164,15 +482,12 @@ async fn login_listener(stream: &mut TcpStream) {
         match read_result {
             Ok(n) => {
                 if n == 0 {
-                    println!("Connection closed by server");
+                    info!("connection closed by server");
                     break;
                 }
 
                 total_read += n;
 
-                // print the buffer as hexadecimal bytes, for debugging purposes
-                // println!("{:02X?}", &buffer[0..total_read]);
-
                 // read some data
                 if !parse_len && data_packet_id != u16::MAX && total_read == packet_len {
                     let header_size = if has_packet_len {
@@ -181,11 +496,13 @@ async fn login_listener(stream: &mut TcpStream) {
                         PACKET_HEADER_LEN as usize
                     };
 
+                    recorder::record(Stage::Login, Direction::Received, &buffer[0..packet_len]);
+
                     let mut input_message =
                         InputMessage::new(buffer[header_size..packet_len].to_vec());
                     let result = login_packet_handler(data_packet_id, &mut input_message).await;
                     match result {
-                        true => {
+                        None => {
                             // reset packet_len to read the next packet
                             packet_len = PACKET_HEADER_LEN as usize;
                             data_packet_id = u16::MAX;
@@ -193,8 +510,8 @@ async fn login_listener(stream: &mut TcpStream) {
                             has_packet_len = false;
                             total_read = 0;
                         }
-                        false => {
-                            break;
+                        Some(answer) => {
+                            return Some(answer);
                         }
                     }
                     continue;
@@ -212,8 +529,6 @@ async fn login_listener(stream: &mut TcpStream) {
                     // parse packet id first
                     data_packet_id = u16::from_le_bytes([buffer[0], buffer[1]]);
 
-                    //println!("pckt id: {}", data_packet_id);
-
                     let result = login_packets_len.get(&data_packet_id);
                     match result {
                         Some(&len) => {
@@ -234,37 +549,511 @@ async fn login_listener(stream: &mut TcpStream) {
                 }
             }
             Err(e) => {
-                println!("Failed to read from stream: {}", e);
+                warn!(error = %e, "failed to read from stream");
                 break;
             }
         }
     }
+
+    None
+}
+
+/// Runs a whole session until the map stage takes over, the map stage
+/// keeps running in the background.
+pub async fn initialize(server_addr: &str, username: &str, password: &str) {
+    let mut session = start(server_addr, username, password);
+    while let Some(event) = session.next_event().await {
+        match event {
+            SessionEvent::Connected(Stage::Map) | SessionEvent::Failed { .. } => break,
+            _ => {}
+        }
+    }
+}
+
+/// Starts a session in the background, returns its handle.
+pub fn start(server_addr: &str, username: &str, password: &str) -> Session {
+    start_with(server_addr, username, password, SessionOptions::default())
+}
+
+/// Same as `start`, but the session starts over from login, for the same
+/// character, when any stage fails.
+pub fn start_with_reconnect(
+    server_addr: &str,
+    username: &str,
+    password: &str,
+    policy: ReconnectPolicy,
+) -> Session {
+    let options = SessionOptions {
+        reconnect: Some(policy),
+        ..SessionOptions::default()
+    };
+    start_with(server_addr, username, password, options)
+}
+
+/// Same as `start`, with everything `SessionOptions` sets.
+pub fn start_with(
+    server_addr: &str,
+    username: &str,
+    password: &str,
+    options: SessionOptions,
+) -> Session {
+    let (mut session, link) = Session::new();
+    let server_addr = server_addr.to_string();
+    let username = username.to_string();
+    let password = password.to_string();
+    session.set_task(tokio::spawn(async move {
+        run(&server_addr, &username, &password, link, options).await
+    }));
+    session
 }
 
-pub async fn initialize() {
-    // initialize login packets size
-    unsafe {
-        let mut login_packets_len = HashMap::new();
-        login_packets_len.insert(LoginServer::AuthOk as u16, u16::MAX as u16);
-        login_packets_len.insert(LoginServer::AuthResult as u16, 1);
+/// Logs under a `session` span, with a child span per stage, counts under
+/// the session metrics, see `metrics::registry`, and records packets under
+/// the session id, see `recorder::scope`.
+async fn run(
+    server_addr: &str,
+    username: &str,
+    password: &str,
+    link: SessionLink,
+    options: SessionOptions,
+) {
+    let session_id = recorder::next_session_id();
+    let session = info_span!(
+        "session",
+        id = session_id,
+        account = %username,
+        account_id = tracing::field::Empty
+    );
+    let session_metrics = metrics::registry().register(session_id, username);
+    if let Err(reason) = check_credentials(username, password, options.mode) {
+        warn!(account = %username, %reason, "login not sent");
+        link.fail(Stage::Login, &reason);
+        return;
+    }
+    let stages = async {
+        match &options.reconnect {
+            Some(policy) => {
+                supervise(server_addr, username, password, &options, link, policy).await
+            }
+            None => login(server_addr, username, password, &options, 0, link).await,
+        }
+    };
+    let stages = metrics::scope(Some(session_metrics), stages.instrument(session));
+    recorder::scope(session_id, stages).await;
+}
 
-        LOGIN_PACKETS_LEN = Some(login_packets_len);
+/// Credentials go in 24 byte fields, null-terminator included. A longer
+/// name or password does not fit, and the server refuses longer tokens, so
+/// such a session ends before connecting rather than retrying.
+fn check_credentials(username: &str, password: &str, mode: LoginMode) -> Result<(), String> {
+    let max_len = MAX_CREDENTIAL_LEN as usize;
+    if username.len() > max_len {
+        return Err(format!("username longer than {} bytes", max_len));
+    }
+    let field = match mode {
+        LoginMode::Plaintext => "password",
+        LoginMode::Token => "token",
+        // only the hash is sent
+        LoginMode::Md5Challenge => return Ok(()),
+    };
+    if password.len() > max_len {
+        return Err(format!("{} longer than {} bytes", field, max_len));
     }
+    Ok(())
+}
 
-    let stream = TcpStream::connect(LOGIN_SERVER_ADDR).await;
-    match stream {
-        Ok(mut stream) => {
-            // send first packets
-            client_send_udpclhash(&mut stream).await;
-            client_send_reqauth(&mut stream, "test2".to_string(), "test123".to_string()).await;
-            tokio::spawn(async move {
-                login_listener(&mut stream).await;
-            })
-            .await
-            .expect("Failed to spawn listener");
+/// Runs the stages again each time they fail. Every attempt gets its own
+/// link, events and commands are passed along so the owner keeps the same
+/// `Session` across attempts. Attempts run on this task, so a shutdown
+/// leaves none behind.
+async fn supervise(
+    server_addr: &str,
+    username: &str,
+    password: &str,
+    options: &SessionOptions,
+    mut link: SessionLink,
+    policy: &ReconnectPolicy,
+) {
+    let mut attempt = 0;
+    let mut quitting = false;
+    let mut refused = false;
+    // the character last selected, which may have changed since the login
+    let mut slot = 0;
+    loop {
+        let (mut stages, stages_link) = Session::new();
+        let stages_run = login(server_addr, username, password, options, slot, stages_link);
+        tokio::pin!(stages_run);
+        let mut running = true;
+
+        loop {
+            tokio::select! {
+                _ = &mut stages_run, if running => running = false,
+                event = stages.next_event() => {
+                    // no event left once every stage is gone
+                    let Some(event) = event else { break };
+                    let failed = matches!(event, SessionEvent::Failed { .. });
+                    match event {
+                        SessionEvent::CharSelected { slot: selected, .. } => slot = selected,
+                        SessionEvent::MapReady { .. } => attempt = 0,
+                        SessionEvent::LoginRefused(ref error) => refused = !error.is_retryable(),
+                        _ => {}
+                    }
+                    link.emit(event);
+                    if failed {
+                        break;
+                    }
+                }
+                command = link.next_command() => {
+                    // the owner is gone, nobody to reconnect for
+                    let Some(command) = command else { return };
+                    quitting |= command == SessionCommand::Quit;
+                    stages.send(command);
+                }
+            }
+        }
+
+        if quitting {
+            return;
         }
-        Err(e) => {
-            println!("Failed to connect to server: {}", e);
+        if refused {
+            warn!("login refused for good, not reconnecting");
+            return;
         }
+
+        attempt += 1;
+        let Some(delay) = policy.delay(attempt) else {
+            warn!(attempts = attempt - 1, "giving up reconnecting");
+            return;
+        };
+        info!(attempt, ?delay, "reconnecting");
+        link.emit(SessionEvent::Reconnecting { attempt, delay });
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Logs in and selects the character on `slot`, on the char server picked
+/// by `options`.
+async fn login(
+    server_addr: &str,
+    username: &str,
+    password: &str,
+    options: &SessionOptions,
+    slot: u8,
+    link: SessionLink,
+) {
+    let login_accepted = async {
+        let stream = TcpStream::connect(server_addr).await;
+        match stream {
+            Ok(mut stream) => {
+                info!("connected");
+                link.emit(SessionEvent::Connected(Stage::Login));
+                // send first packets
+                client_send_udpclhash(&mut stream).await;
+                match options.mode {
+                    LoginMode::Plaintext => {
+                        client_send_reqauth(&mut stream, username.to_string(), password.to_string())
+                            .await
+                    }
+                    LoginMode::Md5Challenge => {
+                        let Some(key) = client_request_hash_key(&mut stream).await else {
+                            link.fail(Stage::Login, "no hash key");
+                            return None;
+                        };
+                        client_send_reqauth_hashed(&mut stream, username, password, &key).await;
+                    }
+                    LoginMode::Token => {
+                        client_send_sso_login(&mut stream, username, password).await
+                    }
+                }
+                match login_listener(&mut stream).await {
+                    Some(Ok(login_accepted)) => Some(login_accepted),
+                    Some(Err(error)) => {
+                        warn!(%error, "login refused");
+                        link.emit(SessionEvent::LoginRefused(error.clone()));
+                        link.fail(Stage::Login, &error.to_string());
+                        None
+                    }
+                    None => {
+                        link.fail(Stage::Login, "connection closed before AuthOk");
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "failed to connect");
+                link.fail(Stage::Login, &e.to_string());
+                None
+            }
+        }
+    }
+    .instrument(info_span!("login", server = %server_addr))
+    .await;
+
+    let Some(login) = login_accepted else {
+        return;
+    };
+    tracing::Span::current().record("account_id", login.acc_id);
+    link.emit(SessionEvent::LoginAccepted {
+        account_id: login.acc_id,
+        servers: login.servers.clone(),
+    });
+    match options.server.select(&login.servers) {
+        Some(char_server) => {
+            info!(name = %char_server.name, "char server selected");
+            character_list::initialize(&login, char_server, slot, link).await;
+        }
+        None => link.fail(Stage::Login, "no char server selected"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::mock_server::{
+        collect_events, start_session, wait_for_event, MockScript, MockServer, MockStage,
+        PacketWriter,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn login_reaches_map_server() {
+        let server = MockServer::start(MockScript::default()).await;
+
+        initialize(&server.login_addr.to_string(), "mock", "mock123").await;
+
+        let name_request = server
+            .wait_for(MockStage::Map, 0x0368, Duration::from_secs(5))
+            .await;
+        assert!(
+            name_request.is_some(),
+            "client never finished loading the map"
+        );
+
+        let received: Vec<(MockStage, u16)> = server
+            .received()
+            .iter()
+            .map(|packet| (packet.stage, packet.packet_id))
+            .collect();
+        assert_eq!(
+            received,
+            vec![
+                (MockStage::Login, 0x0204),
+                (MockStage::Login, 0x0064),
+                (MockStage::Char, 0x0065),
+                (MockStage::Char, 0x09A1),
+                (MockStage::Char, 0x0066),
+                (MockStage::Map, 0x0436),
+                (MockStage::Map, 0x007D),
+                (MockStage::Map, 0x0360),
+                (MockStage::Map, 0x021D),
+                (MockStage::Map, 0x0368),
+            ]
+        );
+
+        let req_auth = &server.received()[1].data;
+        assert_eq!(&req_auth[6..11], b"mock\0");
+        let connect = &server.received()[5].data;
+        assert_eq!(&connect[2..6], &2000001u32.to_le_bytes());
+        assert_eq!(connect.len(), 19);
+    }
+
+    #[tokio::test]
+    async fn auth_ok_lists_every_char_server() {
+        let mut auth_ok = PacketWriter::new_var(LoginServer::AuthOk as u16);
+        auth_ok
+            .u32(1)
+            .u32(2000001)
+            .u32(2)
+            .u32(0)
+            .zeros(26)
+            .u8(1)
+            .string("0123456789ABCDEF", 17);
+        for (ip, name, users, is_new) in [
+            ([10, 0, 0, 1], "Main", 42, 0),
+            ([10, 0, 0, 2], "Test", 3, 1),
+        ] {
+            auth_ok
+                .bytes(&ip)
+                .u16(6121)
+                .string(name, 20)
+                .u16(users)
+                .u16(0)
+                .u16(is_new)
+                .zeros(128);
+        }
+        let packet = auth_ok.finish_var();
+        let login = login_auth_ok(&mut InputMessage::new(packet[4..].to_vec())).await;
+
+        assert_eq!(login.servers.len(), 2);
+        assert_eq!(login.servers[1].addr().to_string(), "10.0.0.2:6121");
+        assert_eq!(login.servers[1].users, 3);
+        assert!(login.servers[1].is_new);
+
+        assert_eq!(
+            ServerSelect::default().select(&login.servers),
+            Some(&login.servers[0])
+        );
+        let by_name = ServerSelect::Callback(Arc::new(|servers: &[ServerInfo]| {
+            servers.iter().position(|server| server.name == "Test")
+        }));
+        assert_eq!(by_name.select(&login.servers), Some(&login.servers[1]));
+        assert_eq!(ServerSelect::Index(2).select(&login.servers), None);
+    }
+
+    #[tokio::test]
+    async fn refuse_login_decodes_ban_expiry() {
+        let mut refuse = PacketWriter::new(LoginServer::RefuseLogin as u16);
+        refuse.u32(6).string("2026-12-31 23:59:59", 20);
+        let mut data = InputMessage::new(refuse.finish()[2..].to_vec());
+        let answer = login_packet_handler(LoginServer::RefuseLogin as u16, &mut data).await;
+        assert_eq!(
+            answer.map(|answer| answer.err()),
+            Some(Some(LoginError::Banned {
+                until: "2026-12-31 23:59:59".to_string()
+            }))
+        );
+
+        let mut refuse = PacketWriter::new(LoginServer::RefuseLoginOld as u16);
+        refuse.u8(5).zeros(20);
+        let mut data = InputMessage::new(refuse.finish()[2..].to_vec());
+        let answer = login_packet_handler(LoginServer::RefuseLoginOld as u16, &mut data).await;
+        assert_eq!(
+            answer.map(|answer| answer.err()),
+            Some(Some(LoginError::ClientVersion))
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_password_is_not_retried() {
+        let mut refuse = PacketWriter::new(LoginServer::RefuseLogin as u16);
+        refuse.u32(1).zeros(20);
+        let script = MockScript::default().on(MockStage::Login, 0x0064, vec![refuse.finish()]);
+        let options = SessionOptions {
+            reconnect: Some(ReconnectPolicy {
+                initial_delay: Duration::from_millis(10),
+                jitter: 0.0,
+                ..ReconnectPolicy::default()
+            }),
+            ..SessionOptions::default()
+        };
+        let (_server, mut session) = start_session(script, "wrong", options).await;
+
+        let events = collect_events(&mut session).await;
+        assert_eq!(
+            events,
+            vec![
+                SessionEvent::Connected(Stage::Login),
+                SessionEvent::LoginRefused(LoginError::IncorrectPassword),
+                SessionEvent::Failed {
+                    stage: Stage::Login,
+                    reason: "incorrect password".to_string()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_auth_result_is_not_retried() {
+        let mut auth_result = PacketWriter::new(LoginServer::AuthResult as u16);
+        auth_result.u8(99);
+        let script = MockScript::default().on(MockStage::Login, 0x0064, vec![auth_result.finish()]);
+        let options = SessionOptions {
+            reconnect: Some(ReconnectPolicy::default()),
+            ..SessionOptions::default()
+        };
+        let (server, mut session) = start_session(script, "mock123", options).await;
+
+        let events = collect_events(&mut session).await;
+        assert_eq!(
+            events[1..],
+            [
+                SessionEvent::LoginRefused(LoginError::Unknown(99)),
+                SessionEvent::Failed {
+                    stage: Stage::Login,
+                    reason: "refused with unknown code 99".to_string()
+                },
+            ]
+        );
+        assert_eq!(server.count(MockStage::Login, 0x0064), 1);
+    }
+
+    #[tokio::test]
+    async fn overlong_credentials_are_never_sent() {
+        let options = SessionOptions {
+            mode: LoginMode::Token,
+            reconnect: Some(ReconnectPolicy::default()),
+            ..SessionOptions::default()
+        };
+        let token = "0123456789abcdef01234567";
+        let (server, mut session) = start_session(MockScript::default(), token, options).await;
+        assert_eq!(
+            collect_events(&mut session).await,
+            vec![SessionEvent::Failed {
+                stage: Stage::Login,
+                reason: "token longer than 23 bytes".to_string()
+            }]
+        );
+
+        let username = "a".repeat(256);
+        let login_addr = server.login_addr.to_string();
+        let mut session = start_with(&login_addr, &username, "mock123", SessionOptions::default());
+        assert_eq!(
+            collect_events(&mut session).await,
+            vec![SessionEvent::Failed {
+                stage: Stage::Login,
+                reason: "username longer than 23 bytes".to_string()
+            }]
+        );
+        assert!(server.received().is_empty());
+    }
+
+    /// Runs a session with `mode` until the login server accepted it.
+    async fn login_with(mode: LoginMode, password: &str) -> MockServer {
+        let options = SessionOptions {
+            mode,
+            ..SessionOptions::default()
+        };
+        let (server, mut session) = start_session(MockScript::default(), password, options).await;
+        wait_for_event(&mut session, |event| {
+            matches!(event, SessionEvent::LoginAccepted { .. })
+        })
+        .await;
+        server
+    }
+
+    #[tokio::test]
+    async fn md5_login_hashes_the_key_and_password() {
+        let server = login_with(LoginMode::Md5Challenge, "mock123").await;
+
+        let received = server.received();
+        let ids: Vec<u16> = received
+            .iter()
+            .map(|packet| packet.packet_id)
+            .take(3)
+            .collect();
+        assert_eq!(ids, vec![0x0204, 0x01DB, 0x01DD]);
+
+        let mut hasher = Md5::new();
+        hasher.update(&MockScript::default().hash_key);
+        hasher.update(b"mock123");
+        let req_auth = &received[2].data;
+        assert_eq!(req_auth.len(), 47);
+        assert_eq!(&req_auth[6..11], b"mock\0");
+        assert_eq!(&req_auth[30..46], hasher.finalize().as_slice());
+    }
+
+    #[tokio::test]
+    async fn token_login_sends_the_token_last() {
+        let token = "0123456789abcdef0123456";
+        let server = login_with(LoginMode::Token, token).await;
+
+        let sso_login = &server.received()[1];
+        assert_eq!(sso_login.packet_id, 0x0825);
+        let len = u16::from_le_bytes([sso_login.data[2], sso_login.data[3]]) as usize;
+        assert_eq!(len, sso_login.data.len());
+        assert_eq!(&sso_login.data[9..14], b"mock\0");
+        assert_eq!(&sso_login.data[92..], token.as_bytes());
     }
 }
@@ -1,4 +1,6 @@
-pub mod login;
 pub mod character_list;
+pub mod clock;
 pub mod game;
-pub mod helper;
\ No newline at end of file
+pub mod helper;
+pub mod login;
+pub mod session;
@@ -0,0 +1,333 @@
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{capture::Stage, r#const::SHUTDOWN_TIMEOUT_SECS};
+
+use super::login::{LoginError, ServerInfo};
+
+/// Progress of a session, sent by the stages as they go.
+#[derive(Clone, PartialEq, Debug)]
+pub enum SessionEvent {
+    /// Connected to the server of the stage.
+    Connected(Stage),
+    LoginAccepted {
+        account_id: u32,
+        servers: Vec<ServerInfo>,
+    },
+    /// The login server refused us, `Failed` follows.
+    LoginRefused(LoginError),
+    CharSelected {
+        slot: u8,
+        char_id: u32,
+        map_name: String,
+    },
+    MapAuthOk {
+        x: u16,
+        y: u16,
+    },
+    /// `LoadEndAck` sent, the character is on the map and takes commands.
+    MapReady {
+        map_name: String,
+        x: u16,
+        y: u16,
+    },
+    /// The stage ended without handing over to the next one. Ends the
+    /// session, the map stage included, unless it reconnects.
+    Failed {
+        stage: Stage,
+        reason: String,
+    },
+    /// Left the game on request, see `Session::shutdown`.
+    Disconnected,
+    /// Starting over from login after `delay`, see `ReconnectPolicy`.
+    Reconnecting {
+        attempt: u32,
+        delay: Duration,
+    },
+}
+
+/// How a session started with `login::start_with_reconnect` starts over
+/// when it fails. The attempts start counting again once a session made it
+/// to the map.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Attempts in a row before giving up, `None` retries forever.
+    pub max_attempts: Option<u32>,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Delay growth between two attempts.
+    pub multiplier: f64,
+    /// Part of the delay picked at random, 0.2 is +/- 20%.
+    pub jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(10),
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before `attempt`, the first retry being attempt 1. `None` once
+    /// out of attempts.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if self
+            .max_attempts
+            .is_some_and(|max_attempts| attempt > max_attempts)
+        {
+            return None;
+        }
+
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let jitter = delay * self.jitter.clamp(0.0, 1.0);
+        let delay = if jitter > 0.0 {
+            delay + rand::thread_rng().gen_range(-jitter..=jitter)
+        } else {
+            delay
+        };
+        Some(Duration::from_secs_f64(delay.max(0.0)))
+    }
+}
+
+/// What the owner of a session asks the character to do once on the map.
+#[derive(Clone, PartialEq, Debug)]
+pub enum SessionCommand {
+    WalkTo {
+        x: u16,
+        y: u16,
+    },
+    Chat(String),
+    /// Stand up again on the save point after dying.
+    Respawn,
+    /// Go back to the character list and select the character on `slot`,
+    /// without logging in again.
+    CharSelect {
+        slot: u8,
+    },
+    /// Leave the game, the session ends once the server acked it.
+    Quit,
+}
+
+/// Session side of the channels, handed from one stage to the next.
+#[derive(Default)]
+pub struct SessionLink {
+    events: Option<mpsc::UnboundedSender<SessionEvent>>,
+    commands: Option<mpsc::UnboundedReceiver<SessionCommand>>,
+}
+
+impl SessionLink {
+    pub fn emit(&self, event: SessionEvent) {
+        if let Some(events) = &self.events {
+            // nobody listening anymore is fine
+            let _ = events.send(event);
+        }
+    }
+
+    pub fn fail(&self, stage: Stage, reason: &str) {
+        self.emit(SessionEvent::Failed {
+            stage,
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Next command, `None` once the owner is gone or when there is none.
+    pub async fn next_command(&mut self) -> Option<SessionCommand> {
+        match self.commands.as_mut() {
+            Some(commands) => commands.recv().await,
+            None => None,
+        }
+    }
+}
+
+/// Owner side of a running session, see `login::start`.
+pub struct Session {
+    events: mpsc::UnboundedReceiver<SessionEvent>,
+    commands: mpsc::UnboundedSender<SessionCommand>,
+    /// Task running the stages, joined by `shutdown`.
+    task: Option<JoinHandle<()>>,
+    /// On the map as of the last event, only then is there a game to quit.
+    on_map: bool,
+}
+
+impl Session {
+    pub fn new() -> (Session, SessionLink) {
+        let (events_sender, events) = mpsc::unbounded_channel();
+        let (commands, commands_receiver) = mpsc::unbounded_channel();
+
+        let session = Session {
+            events,
+            commands,
+            task: None,
+            on_map: false,
+        };
+        let link = SessionLink {
+            events: Some(events_sender),
+            commands: Some(commands_receiver),
+        };
+        (session, link)
+    }
+
+    /// `None` once every stage is done.
+    pub async fn next_event(&mut self) -> Option<SessionEvent> {
+        let event = self.events.recv().await;
+        if let Some(event) = &event {
+            self.track(event);
+        }
+        event
+    }
+
+    fn track(&mut self, event: &SessionEvent) {
+        match event {
+            SessionEvent::MapReady { .. } => self.on_map = true,
+            SessionEvent::Connected(_)
+            | SessionEvent::Failed { .. }
+            | SessionEvent::Disconnected
+            | SessionEvent::Reconnecting { .. } => self.on_map = false,
+            _ => {}
+        }
+    }
+
+    /// Returns false when the map stage is gone.
+    pub fn send(&self, command: SessionCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    pub fn set_task(&mut self, task: JoinHandle<()>) {
+        self.task = Some(task);
+    }
+
+    /// Quits the game and waits for every stage to end. A session not on
+    /// the map has no game to quit and is closed right away, a server that
+    /// never acks is cut after `SHUTDOWN_TIMEOUT_SECS`.
+    pub async fn shutdown(mut self) {
+        // events the owner did not read yet
+        while let Ok(event) = self.events.try_recv() {
+            self.track(&event);
+        }
+        let Some(mut task) = self.task.take() else {
+            return;
+        };
+        if !self.on_map {
+            task.abort();
+            let _ = task.await;
+            return;
+        }
+
+        self.send(SessionCommand::Quit);
+        let timeout = Duration::from_secs(SHUTDOWN_TIMEOUT_SECS);
+        if tokio::time::timeout(timeout, &mut task).await.is_err() {
+            tracing::warn!("session did not quit in time, closing it");
+            task.abort();
+            let _ = task.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        mock_server::{collect_events, start_session, wait_for_event, MockScript, MockStage},
+        protocol::login::SessionOptions,
+    };
+
+    use super::*;
+
+    fn with_reconnect(policy: ReconnectPolicy) -> SessionOptions {
+        SessionOptions {
+            reconnect: Some(policy),
+            ..SessionOptions::default()
+        }
+    }
+
+    #[test]
+    fn reconnect_delay_grows_up_to_the_max() {
+        let policy = ReconnectPolicy {
+            max_attempts: Some(5),
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+
+        assert_eq!(policy.delay(1), Some(Duration::from_millis(100)));
+        assert_eq!(policy.delay(3), Some(Duration::from_millis(400)));
+        assert_eq!(policy.delay(5), Some(Duration::from_millis(500)));
+        assert_eq!(policy.delay(6), None);
+
+        let policy = ReconnectPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(2).unwrap();
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(300));
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_quits_and_ends_the_session() {
+        let options = with_reconnect(ReconnectPolicy::default());
+        let (server, mut session) = start_session(MockScript::default(), "mock123", options).await;
+        wait_for_event(&mut session, |event| {
+            matches!(event, SessionEvent::MapReady { .. })
+        })
+        .await;
+
+        // acked well before the shutdown timeout
+        tokio::time::timeout(Duration::from_secs(2), session.shutdown())
+            .await
+            .expect("session did not end on quit");
+
+        assert_eq!(server.count(MockStage::Map, 0x018A), 1);
+        // no reconnect after leaving on purpose
+        assert_eq!(server.count(MockStage::Login, 0x0064), 1);
+    }
+
+    #[tokio::test]
+    async fn session_reconnects_until_out_of_attempts() {
+        // the map server drops every connection
+        let script = MockScript::default().close_on(MockStage::Map, 0x0436);
+        let options = with_reconnect(ReconnectPolicy {
+            max_attempts: Some(2),
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(20),
+            multiplier: 2.0,
+            jitter: 0.0,
+        });
+        let (server, mut session) = start_session(script, "mock123", options).await;
+
+        let events = collect_events(&mut session).await;
+        let failures = events
+            .iter()
+            .filter(|event| {
+                matches!(
+                    event,
+                    SessionEvent::Failed {
+                        stage: Stage::Map,
+                        ..
+                    }
+                )
+            })
+            .count();
+        assert_eq!(failures, 3);
+        assert!(events.contains(&SessionEvent::Reconnecting {
+            attempt: 2,
+            delay: Duration::from_millis(20)
+        }));
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, SessionEvent::Reconnecting { attempt: 3, .. })));
+
+        assert_eq!(server.count(MockStage::Login, 0x0064), 3);
+    }
+}
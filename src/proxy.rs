@@ -0,0 +1,346 @@
+//! Transparent man-in-the-middle proxy between a client and the servers.
+//!
+//! The client connects to the proxy instead of the login server. Server
+//! addresses sent to the client (char servers in `AuthOk`, map servers in
+//! `MapData` and `ChangeMapServer`) are replaced by proxy listeners, so
+//! every connection of the session goes through it. Packets are logged in
+//! both directions and the server ones are run through our decoders.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{
+    capture::{
+        format::{write_text_record, CapturedPacket},
+        recorder::{self, now_millis},
+        replay::{replay_packet, PacketOutcome},
+        splitter::{Chunk, PacketSplitter},
+        Direction, Stage,
+    },
+    protocol::{character_list::CharListServer, game::GameServer, login::LoginServer},
+};
+
+/// Offset of the first char server in `AuthOk`, header included.
+static AUTH_OK_SERVERS_OFFSET: usize = 64;
+static AUTH_OK_SERVER_LEN: usize = 160;
+/// Offset of the map server ip in `MapData`, header included.
+static MAP_DATA_IP_OFFSET: usize = 22;
+/// Offset of the map server ip in `ChangeMapServer`, header included.
+static CHANGE_MAP_SERVER_IP_OFFSET: usize = 22;
+
+pub struct ProxyConfig {
+    /// Where the client connects, instead of the login server.
+    pub listen: SocketAddr,
+    pub login_server: SocketAddr,
+    /// Address sent to the client for the char and map listeners. Defaults
+    /// to the address the client used to reach the proxy.
+    pub public_ip: Option<Ipv4Addr>,
+    /// Run server packets through the decoders and log the outcome.
+    pub decode: bool,
+}
+
+pub struct Proxy {
+    config: ProxyConfig,
+    /// Proxy listener of each server the client was sent to.
+    routes: Mutex<HashMap<SocketAddr, (Stage, SocketAddr)>>,
+}
+
+impl Proxy {
+    pub fn new(config: ProxyConfig) -> Arc<Proxy> {
+        Arc::new(Proxy {
+            config,
+            routes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Binds the login listener, returns its address. Connections are
+    /// served in the background.
+    pub async fn start(self: &Arc<Proxy>) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(self.config.listen).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(serve(
+            self.clone(),
+            listener,
+            Stage::Login,
+            self.config.login_server,
+        ));
+        Ok(addr)
+    }
+
+    /// `(stage, server, proxy listener)` of every server proxied so far.
+    pub fn routes(&self) -> Vec<(Stage, SocketAddr, SocketAddr)> {
+        self.routes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(server, (stage, local))| (*stage, *server, *local))
+            .collect()
+    }
+
+    /// Port of the listener forwarding to `server`, bound on first use.
+    async fn route(self: &Arc<Proxy>, stage: Stage, server: SocketAddr) -> io::Result<u16> {
+        if let Some((_, local)) = self.routes.lock().unwrap().get(&server) {
+            return Ok(local.port());
+        }
+
+        let listener = TcpListener::bind((self.config.listen.ip(), 0)).await?;
+        let local = listener.local_addr()?;
+        self.routes.lock().unwrap().insert(server, (stage, local));
+        tokio::spawn(serve(self.clone(), listener, stage, server));
+
+        Ok(local.port())
+    }
+}
+
+/// Boxed, listeners are started from inside the connections they serve.
+fn serve(
+    proxy: Arc<Proxy>,
+    listener: TcpListener,
+    stage: Stage,
+    server: SocketAddr,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    Box::pin(async move {
+        info!(
+            stage = stage.name(),
+            listen = ?listener.local_addr(),
+            %server,
+            "proxy listener started"
+        );
+
+        while let Ok((client, client_addr)) = listener.accept().await {
+            let proxy = proxy.clone();
+            // every connection records under its own id
+            let session = recorder::next_session_id();
+            let span = info_span!("proxy", stage = stage.name(), client = %client_addr, session);
+            tokio::spawn(recorder::scope(
+                session,
+                async move {
+                    if let Err(e) = handle_connection(proxy, stage, client, server).await {
+                        warn!(error = %e, "connection failed");
+                    }
+                }
+                .instrument(span),
+            ));
+        }
+    })
+}
+
+async fn handle_connection(
+    proxy: Arc<Proxy>,
+    stage: Stage,
+    client: TcpStream,
+    server_addr: SocketAddr,
+) -> io::Result<()> {
+    let server = TcpStream::connect(server_addr).await?;
+    let public_ip = match (proxy.config.public_ip, client.local_addr()?.ip()) {
+        (Some(ip), _) => ip,
+        (None, IpAddr::V4(ip)) => ip,
+        (None, IpAddr::V6(_)) => Ipv4Addr::LOCALHOST,
+    };
+
+    let (client_read, client_write) = client.into_split();
+    let (server_read, server_write) = server.into_split();
+
+    let upstream = tokio::spawn(recorder::scope(
+        recorder::current_session(),
+        pump(
+            proxy.clone(),
+            stage,
+            Direction::Sent,
+            client_read,
+            server_write,
+            public_ip,
+        )
+        .in_current_span(),
+    ));
+    let downstream = pump(
+        proxy,
+        stage,
+        Direction::Received,
+        server_read,
+        client_write,
+        public_ip,
+    )
+    .await;
+
+    upstream.abort();
+    downstream
+}
+
+/// Forwards one direction, `Sent` being client to server.
+async fn pump(
+    proxy: Arc<Proxy>,
+    stage: Stage,
+    direction: Direction,
+    mut from: OwnedReadHalf,
+    mut to: OwnedWriteHalf,
+    public_ip: Ipv4Addr,
+) -> io::Result<()> {
+    let mut splitter = match direction {
+        Direction::Sent => PacketSplitter::new(stage.client_packets_len()),
+        // the char server sends the account id before the first packet
+        Direction::Received if stage == Stage::Char => {
+            PacketSplitter::new(stage.packets_len()).with_raw_prefix(4)
+        }
+        Direction::Received => PacketSplitter::new(stage.packets_len()),
+    };
+
+    let mut buffer = [0u8; 16384];
+    loop {
+        let n = from.read(&mut buffer).await?;
+        if n == 0 {
+            // forward whatever was left of an incomplete packet
+            to.write_all(splitter.remaining()).await?;
+            return Ok(());
+        }
+
+        for chunk in splitter.push(&buffer[..n]) {
+            let data = match chunk {
+                Chunk::Packet(mut data) => {
+                    if direction == Direction::Received {
+                        rewrite_addresses(&proxy, stage, &mut data, public_ip).await?;
+                    }
+                    log_packet(&proxy, stage, direction, &data).await;
+                    data
+                }
+                Chunk::Raw(data) => {
+                    warn!(len = data.len(), "bytes could not be split");
+                    log_packet(&proxy, stage, direction, &data).await;
+                    data
+                }
+            };
+            to.write_all(&data).await?;
+        }
+    }
+}
+
+fn read_addr(data: &[u8], offset: usize) -> SocketAddr {
+    let ip = Ipv4Addr::new(
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    );
+    let port = u16::from_le_bytes([data[offset + 4], data[offset + 5]]);
+    SocketAddr::new(IpAddr::V4(ip), port)
+}
+
+fn write_addr(data: &mut [u8], offset: usize, ip: Ipv4Addr, port: u16) {
+    data[offset..offset + 4].copy_from_slice(&ip.octets());
+    data[offset + 4..offset + 6].copy_from_slice(&port.to_le_bytes());
+}
+
+/// Points the char servers of `AuthOk` and the map servers of `MapData`
+/// and `ChangeMapServer` to proxy listeners.
+async fn rewrite_addresses(
+    proxy: &Arc<Proxy>,
+    stage: Stage,
+    data: &mut [u8],
+    public_ip: Ipv4Addr,
+) -> io::Result<()> {
+    let packet_id = u16::from_le_bytes([data[0], data[1]]);
+
+    if stage == Stage::Login && packet_id == LoginServer::AuthOk as u16 {
+        let mut offset = AUTH_OK_SERVERS_OFFSET;
+        while offset + AUTH_OK_SERVER_LEN <= data.len() {
+            let server = read_addr(data, offset);
+            let port = proxy.route(Stage::Char, server).await?;
+            write_addr(data, offset, public_ip, port);
+            offset += AUTH_OK_SERVER_LEN;
+        }
+    } else if stage == Stage::Char
+        && packet_id == CharListServer::MapData as u16
+        && data.len() >= MAP_DATA_IP_OFFSET + 6
+    {
+        let server = read_addr(data, MAP_DATA_IP_OFFSET);
+        let port = proxy.route(Stage::Map, server).await?;
+        write_addr(data, MAP_DATA_IP_OFFSET, public_ip, port);
+    } else if stage == Stage::Map
+        && packet_id == GameServer::ChangeMapServer as u16
+        && data.len() >= CHANGE_MAP_SERVER_IP_OFFSET + 6
+    {
+        let server = read_addr(data, CHANGE_MAP_SERVER_IP_OFFSET);
+        let port = proxy.route(Stage::Map, server).await?;
+        write_addr(data, CHANGE_MAP_SERVER_IP_OFFSET, public_ip, port);
+    }
+
+    Ok(())
+}
+
+async fn log_packet(proxy: &Arc<Proxy>, stage: Stage, direction: Direction, data: &[u8]) {
+    recorder::record(stage, direction, data);
+
+    let packet = CapturedPacket::new(now_millis(), direction, stage, data)
+        .with_session(recorder::current_session());
+    let mut text = Vec::new();
+    let _ = write_text_record(&mut text, &packet, packet.timestamp);
+
+    if proxy.config.decode && direction == Direction::Received {
+        let outcome = replay_packet(stage, data).await;
+        if outcome != PacketOutcome::Decoded {
+            text.extend_from_slice(format!("    ! {:?}\n", outcome).as_bytes());
+        }
+    }
+
+    // the packet log is the output of the proxy, not a diagnostic
+    print!("{}", String::from_utf8_lossy(&text));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        mock_server::{MockScript, MockServer, MockStage},
+        protocol::login,
+    };
+
+    #[tokio::test]
+    async fn whole_session_goes_through_the_proxy() {
+        let server = MockServer::start(MockScript::default()).await;
+        let proxy = Proxy::new(ProxyConfig {
+            listen: "127.0.0.1:0".parse().unwrap(),
+            login_server: server.login_addr,
+            public_ip: None,
+            decode: true,
+        });
+        let proxy_addr = proxy.start().await.unwrap();
+
+        login::initialize(&proxy_addr.to_string(), "mock", "mock123").await;
+
+        let ack_map = server
+            .wait_for(MockStage::Map, 0x007D, Duration::from_secs(5))
+            .await;
+        assert!(ack_map.is_some(), "client never acked the map");
+
+        let mut routes: Vec<(Stage, SocketAddr)> = proxy
+            .routes()
+            .into_iter()
+            .map(|(stage, server, _)| (stage, server))
+            .collect();
+        routes.sort_by_key(|(stage, _)| *stage as u8);
+        assert_eq!(
+            routes,
+            vec![
+                (Stage::Char, server.char_addr),
+                (Stage::Map, server.map_addr)
+            ]
+        );
+    }
+}
@@ -0,0 +1,473 @@
+//! Load testing: many accounts going through login, char select and map
+//! entry on one runtime, then walking or chatting for a while. Reports the
+//! latency of each step and why sessions failed.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::task::JoinSet;
+use tracing::{info, warn};
+
+use crate::{
+    capture::Stage,
+    protocol::{
+        login,
+        session::{Session, SessionCommand, SessionEvent},
+    },
+};
+
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// One `username:password` per line, blank lines and `#` comments ignored.
+pub fn parse_credentials(text: &str) -> Result<Vec<Credentials>, String> {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(index, line)| match line.split_once(':') {
+            Some((username, password)) if !username.is_empty() => Ok(Credentials {
+                username: username.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err(format!("line {}: expected username:password", index + 1)),
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Behaviour {
+    /// Stays where it spawned.
+    Idle,
+    /// Walks to random cells around its spawn.
+    Walk,
+    Chat,
+    /// Walks or chats, at random.
+    Mixed,
+}
+
+impl FromStr for Behaviour {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Behaviour, String> {
+        match s {
+            "idle" => Ok(Behaviour::Idle),
+            "walk" => Ok(Behaviour::Walk),
+            "chat" => Ok(Behaviour::Chat),
+            "mixed" => Ok(Behaviour::Mixed),
+            _ => Err(format!("unknown behaviour: {}", s)),
+        }
+    }
+}
+
+pub struct SwarmConfig {
+    pub login_server: String,
+    /// Sessions started per second.
+    pub rate: f64,
+    /// Time to reach the map before the session counts as failed.
+    pub timeout: Duration,
+    /// Time spent on the map once there.
+    pub duration: Duration,
+    pub behaviour: Behaviour,
+    /// Time between two actions, jittered by up to half of it.
+    pub action_interval: Duration,
+    /// Cells around the spawn point walkers may go to.
+    pub walk_radius: u16,
+}
+
+impl Default for SwarmConfig {
+    fn default() -> Self {
+        SwarmConfig {
+            login_server: crate::r#const::LOGIN_SERVER_ADDR.to_string(),
+            rate: 5.0,
+            timeout: Duration::from_secs(30),
+            duration: Duration::from_secs(60),
+            behaviour: Behaviour::Walk,
+            action_interval: Duration::from_secs(5),
+            walk_radius: 5,
+        }
+    }
+}
+
+pub struct SessionResult {
+    pub account: String,
+    /// Until connected to the login server.
+    pub connect: Option<Duration>,
+    /// From login connection to `AuthOk`.
+    pub login: Option<Duration>,
+    /// From login `AuthOk` to map ready: char select, map connection and
+    /// `LoadEndAck`.
+    pub map_load: Option<Duration>,
+    pub actions: u32,
+    /// Stage and reason of the failure, when the session failed.
+    pub failure: Option<(Stage, String)>,
+}
+
+impl SessionResult {
+    fn new(account: &str) -> SessionResult {
+        SessionResult {
+            account: account.to_string(),
+            connect: None,
+            login: None,
+            map_load: None,
+            actions: 0,
+            failure: None,
+        }
+    }
+}
+
+/// Drives one account until it spent `duration` on the map, or failed.
+pub async fn run_session(config: Arc<SwarmConfig>, credentials: Credentials) -> SessionResult {
+    let mut result = SessionResult::new(&credentials.username);
+    let mut session = login::start(
+        &config.login_server,
+        &credentials.username,
+        &credentials.password,
+    );
+
+    drive(&config, &mut session, &mut result).await;
+    // leave cleanly whatever happened, the server would keep the character
+    // around otherwise
+    session.shutdown().await;
+    result
+}
+
+async fn drive(config: &SwarmConfig, session: &mut Session, result: &mut SessionResult) {
+    let start = Instant::now();
+    // stage a timeout is blamed on
+    let mut stage = Stage::Login;
+    let mut connected_at = start;
+    let mut accepted_at = start;
+    let spawn = loop {
+        let event =
+            tokio::time::timeout_at((start + config.timeout).into(), session.next_event()).await;
+        match event {
+            Ok(Some(SessionEvent::Connected(connected))) => {
+                if connected == Stage::Login {
+                    connected_at = Instant::now();
+                    result.connect = Some(connected_at - start);
+                }
+                stage = connected;
+            }
+            Ok(Some(SessionEvent::LoginAccepted { .. })) => {
+                accepted_at = Instant::now();
+                result.login = Some(accepted_at - connected_at);
+            }
+            Ok(Some(SessionEvent::MapReady { x, y, .. })) => {
+                result.map_load = Some(accepted_at.elapsed());
+                break (x, y);
+            }
+            Ok(Some(SessionEvent::Failed { stage, reason })) => {
+                result.failure = Some((stage, reason));
+                return;
+            }
+            Ok(Some(_)) => {}
+            Ok(None) => {
+                result.failure = Some((stage, "session ended".to_string()));
+                return;
+            }
+            Err(_) => {
+                result.failure = Some((stage, "timed out".to_string()));
+                return;
+            }
+        }
+    };
+    info!(account = %result.account, x = spawn.0, y = spawn.1, "on the map");
+
+    let on_map_until = Instant::now() + config.duration;
+    while Instant::now() < on_map_until {
+        let wait = jittered(config.action_interval).min(on_map_until - Instant::now());
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            event = session.next_event() => match event {
+                Some(SessionEvent::Failed { stage, reason }) => {
+                    result.failure = Some((stage, reason));
+                    return;
+                }
+                Some(_) => continue,
+                None => {
+                    result.failure = Some((Stage::Map, "session ended".to_string()));
+                    return;
+                }
+            },
+        }
+
+        if Instant::now() < on_map_until && act(config, session, result, spawn) {
+            result.actions += 1;
+        }
+    }
+}
+
+/// Sends the next action of the behaviour, returns false when it sent none.
+fn act(config: &SwarmConfig, session: &Session, result: &SessionResult, spawn: (u16, u16)) -> bool {
+    let mut rng = rand::thread_rng();
+    let walk = match config.behaviour {
+        Behaviour::Idle => return false,
+        Behaviour::Walk => true,
+        Behaviour::Chat => false,
+        Behaviour::Mixed => rng.gen_bool(0.5),
+    };
+
+    let command = if walk {
+        let radius = config.walk_radius as i32;
+        let x = spawn.0 as i32 + rng.gen_range(-radius..=radius);
+        let y = spawn.1 as i32 + rng.gen_range(-radius..=radius);
+        SessionCommand::WalkTo {
+            x: x.max(0) as u16,
+            y: y.max(0) as u16,
+        }
+    } else {
+        SessionCommand::Chat(format!("{} #{}", result.account, result.actions + 1))
+    };
+    session.send(command)
+}
+
+fn jittered(interval: Duration) -> Duration {
+    let jitter = interval.as_millis() as u64 / 2;
+    let offset = match jitter {
+        0 => 0,
+        _ => rand::thread_rng().gen_range(0..=jitter * 2),
+    };
+    Duration::from_millis((interval.as_millis() as u64 - jitter) + offset)
+}
+
+/// Starts every account at `config.rate`, waits for all of them.
+pub async fn run(config: SwarmConfig, accounts: Vec<Credentials>) -> SwarmReport {
+    let config = Arc::new(config);
+    let ramp_interval = Duration::from_secs_f64(1.0 / config.rate.max(0.001));
+    let started = Instant::now();
+
+    let mut sessions = JoinSet::new();
+    let mut ramp = tokio::time::interval(ramp_interval);
+    for credentials in accounts {
+        ramp.tick().await;
+        info!(account = %credentials.username, "starting session");
+        sessions.spawn(run_session(config.clone(), credentials));
+    }
+
+    let mut results = Vec::new();
+    while let Some(result) = sessions.join_next().await {
+        match result {
+            Ok(result) => {
+                if let Some((stage, reason)) = &result.failure {
+                    warn!(account = %result.account, stage = stage.name(), %reason, "session failed");
+                }
+                results.push(result);
+            }
+            Err(e) => warn!(error = %e, "session task failed"),
+        }
+    }
+
+    SwarmReport {
+        elapsed: started.elapsed(),
+        sessions: results,
+    }
+}
+
+pub struct LatencyStats {
+    pub count: usize,
+    pub min: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    /// `None` without samples.
+    pub fn from_samples(mut samples: Vec<Duration>) -> Option<LatencyStats> {
+        if samples.is_empty() {
+            return None;
+        }
+        samples.sort();
+
+        let count = samples.len();
+        let percentile = |p: usize| samples[((count * p).div_ceil(100)).max(1) - 1];
+        Some(LatencyStats {
+            count,
+            min: samples[0],
+            mean: samples.iter().sum::<Duration>() / count as u32,
+            p50: percentile(50),
+            p95: percentile(95),
+            max: samples[count - 1],
+        })
+    }
+}
+
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "n={} min={:?} mean={:?} p50={:?} p95={:?} max={:?}",
+            self.count, self.min, self.mean, self.p50, self.p95, self.max
+        )
+    }
+}
+
+/// Latency of one step of a session.
+type Step = fn(&SessionResult) -> Option<Duration>;
+
+pub struct SwarmReport {
+    pub elapsed: Duration,
+    pub sessions: Vec<SessionResult>,
+}
+
+impl SwarmReport {
+    pub fn failures(&self) -> impl Iterator<Item = &SessionResult> {
+        self.sessions
+            .iter()
+            .filter(|session| session.failure.is_some())
+    }
+
+    fn stats(&self, step: Step) -> Option<LatencyStats> {
+        LatencyStats::from_samples(self.sessions.iter().filter_map(step).collect())
+    }
+}
+
+impl fmt::Display for SwarmReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let failed = self.failures().count();
+        writeln!(
+            f,
+            "{} sessions in {:.1}s: {} reached the map, {} failed",
+            self.sessions.len(),
+            self.elapsed.as_secs_f64(),
+            self.sessions.len() - failed,
+            failed
+        )?;
+
+        let steps: [(&str, Step); 3] = [
+            ("connect", |session| session.connect),
+            ("login", |session| session.login),
+            ("map load", |session| session.map_load),
+        ];
+        for (name, step) in steps {
+            match self.stats(step) {
+                Some(stats) => writeln!(f, "  {:<9} {}", name, stats)?,
+                None => writeln!(f, "  {:<9} no samples", name)?,
+            }
+        }
+
+        let actions: u32 = self.sessions.iter().map(|session| session.actions).sum();
+        writeln!(f, "  {} actions sent", actions)?;
+
+        // failures grouped by stage and reason
+        let mut reasons: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+        for (stage, reason) in self.sessions.iter().filter_map(|s| s.failure.as_ref()) {
+            *reasons.entry((stage.name(), reason.as_str())).or_default() += 1;
+        }
+        for ((stage, reason), count) in reasons {
+            writeln!(f, "  failed at {}: {} x{}", stage, reason, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::{MockScript, MockServer, MockStage};
+
+    #[test]
+    fn parses_credentials_and_latencies() {
+        let accounts = parse_credentials("# bots\nbot1:pass1\n\n  bot2:p:a:ss \n").unwrap();
+        let accounts: Vec<(&str, &str)> = accounts
+            .iter()
+            .map(|c| (c.username.as_str(), c.password.as_str()))
+            .collect();
+        assert_eq!(accounts, vec![("bot1", "pass1"), ("bot2", "p:a:ss")]);
+        assert_eq!(
+            parse_credentials("bot1:a\nbot2").err(),
+            Some("line 2: expected username:password".to_string())
+        );
+
+        let samples = (1..=20).map(Duration::from_millis).collect();
+        let stats = LatencyStats::from_samples(samples).unwrap();
+        assert_eq!(stats.min, Duration::from_millis(1));
+        assert_eq!(stats.p50, Duration::from_millis(10));
+        assert_eq!(stats.p95, Duration::from_millis(19));
+        assert_eq!(stats.max, Duration::from_millis(20));
+        assert!(LatencyStats::from_samples(Vec::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn swarm_reaches_the_map_and_walks() {
+        let server = MockServer::start(MockScript::default()).await;
+        let config = SwarmConfig {
+            login_server: server.login_addr.to_string(),
+            rate: 50.0,
+            timeout: Duration::from_secs(5),
+            duration: Duration::from_millis(300),
+            behaviour: Behaviour::Walk,
+            action_interval: Duration::from_millis(50),
+            walk_radius: 3,
+        };
+        let accounts = parse_credentials("bot1:a\nbot2:b\nbot3:c").unwrap();
+
+        let report = run(config, accounts).await;
+
+        assert_eq!(report.sessions.len(), 3);
+        assert_eq!(report.failures().count(), 0, "{}", report);
+        assert!(report
+            .sessions
+            .iter()
+            .all(|s| s.map_load.is_some() && s.actions > 0));
+        // the last walks may still be on their way to the mock
+        let walks = || {
+            server
+                .received()
+                .iter()
+                .filter(|packet| packet.stage == MockStage::Map && packet.packet_id == 0x035F)
+                .count()
+        };
+        for _ in 0..50 {
+            if walks() >= 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(walks() >= 3);
+
+        // nothing listens there
+        let config = SwarmConfig {
+            login_server: "127.0.0.1:1".to_string(),
+            ..SwarmConfig::default()
+        };
+        let report = run(config, parse_credentials("bot1:a").unwrap()).await;
+        assert_eq!(report.failures().count(), 1);
+        assert!(report.to_string().contains("failed at login"));
+    }
+
+    #[tokio::test]
+    async fn timeout_is_blamed_on_the_last_stage() {
+        // char select is never answered
+        let script = MockScript::default().on(MockStage::Char, 0x0066, Vec::new());
+        let server = MockServer::start(script).await;
+        let config = SwarmConfig {
+            login_server: server.login_addr.to_string(),
+            timeout: Duration::from_millis(300),
+            ..SwarmConfig::default()
+        };
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(2),
+            run_session(
+                Arc::new(config),
+                parse_credentials("bot1:a").unwrap().remove(0),
+            ),
+        )
+        .await
+        .expect("session was not closed");
+
+        assert_eq!(result.failure, Some((Stage::Char, "timed out".to_string())));
+    }
+}